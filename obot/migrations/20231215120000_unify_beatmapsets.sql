@@ -0,0 +1,31 @@
+-- statusごとに分かれていたテーブルを1つにまとめる
+-- 旧beatmapsets(未使用)は作り直す
+DROP TABLE IF EXISTS "beatmapsets";
+CREATE TABLE IF NOT EXISTS "beatmapsets" (
+    id INTEGER PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    artist TEXT NOT NULL,
+    creator TEXT NOT NULL,
+    stars TEXT NOT NULL,
+    keys TEXT NOT NULL,
+    mp3_url TEXT NOT NULL,
+    card_url TEXT NOT NULL,
+    cursor TEXT NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "beatmapsets_status" ON "beatmapsets" (status);
+
+-- 同じidが複数のテーブルにある場合(qualified -> rankedなど)は後に入れた方を残す
+INSERT OR REPLACE INTO "beatmapsets" (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, status)
+    SELECT id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu FROM "graveyard_beatmapsets";
+INSERT OR REPLACE INTO "beatmapsets" (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, status)
+    SELECT id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu FROM "qualified_beatmapsets";
+INSERT OR REPLACE INTO "beatmapsets" (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, status)
+    SELECT id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu FROM "loved_beatmapsets";
+INSERT OR REPLACE INTO "beatmapsets" (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, status)
+    SELECT id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu FROM "ranked_beatmapsets";
+
+DROP TABLE "graveyard_beatmapsets";
+DROP TABLE "qualified_beatmapsets";
+DROP TABLE "loved_beatmapsets";
+DROP TABLE "ranked_beatmapsets";
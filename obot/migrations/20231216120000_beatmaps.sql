@@ -0,0 +1,38 @@
+-- 難易度ごとのテーブル
+-- stars/keysをカンマ区切りで持っていたのをやめる
+-- 旧beatmaps(未使用)は作り直す
+DROP TABLE IF EXISTS "beatmaps";
+CREATE TABLE IF NOT EXISTS "beatmaps" (
+    id INTEGER PRIMARY KEY NOT NULL,
+    beatmapset_id INTEGER NOT NULL REFERENCES "beatmapsets" (id) ON DELETE CASCADE,
+    version TEXT NOT NULL,
+    difficulty_rating REAL NOT NULL,
+    keys INTEGER NOT NULL,
+    od REAL NOT NULL,
+    hp REAL NOT NULL,
+    bpm REAL NOT NULL,
+    total_length INTEGER NOT NULL,
+    count_notes INTEGER NOT NULL,
+    count_lns INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "beatmaps_beatmapset_id" ON "beatmaps" (beatmapset_id);
+CREATE INDEX IF NOT EXISTS "beatmaps_keys" ON "beatmaps" (keys);
+
+-- 既存のstars/keysを分解して移す
+-- 難易度のidは分からないので仮に負のidを振る(次にAPIから取得したときに置き換わる)
+WITH RECURSIVE split(beatmapset_id, n, star_rest, key_rest, star, key) AS (
+    SELECT id, 0, stars || ',', keys || ',', NULL, NULL FROM "beatmapsets" WHERE stars != ''
+    UNION ALL
+    SELECT beatmapset_id, n + 1,
+        substr(star_rest, instr(star_rest, ',') + 1),
+        substr(key_rest, instr(key_rest, ',') + 1),
+        substr(star_rest, 1, instr(star_rest, ',') - 1),
+        substr(key_rest, 1, instr(key_rest, ',') - 1)
+    FROM split WHERE star_rest != ''
+)
+INSERT INTO "beatmaps" (id, beatmapset_id, version, difficulty_rating, keys, od, hp, bpm, total_length, count_notes, count_lns)
+    SELECT -(beatmapset_id * 1000 + n), beatmapset_id, '', CAST(star AS REAL), CAST(ROUND(CAST(key AS REAL)) AS INTEGER), 0, 0, 0, 0, 0, 0
+    FROM split WHERE n > 0;
+
+ALTER TABLE "beatmapsets" DROP COLUMN stars;
+ALTER TABLE "beatmapsets" DROP COLUMN keys;
@@ -0,0 +1,14 @@
+-- beatmapsetのstatusの変化を記録する
+-- old_statusがNULLのものは初めて見つけたとき
+CREATE TABLE IF NOT EXISTS "beatmapset_status_history" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    beatmapset_id INTEGER NOT NULL REFERENCES "beatmapsets" (id) ON DELETE CASCADE,
+    old_status TEXT,
+    new_status TEXT NOT NULL,
+    changed_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "beatmapset_status_history_beatmapset_id" ON "beatmapset_status_history" (beatmapset_id);
+
+-- 既存の譜面はいつからそのstatusなのか分からないので，migrationした時点とする
+INSERT INTO "beatmapset_status_history" (beatmapset_id, old_status, new_status, changed_at)
+    SELECT id, NULL, status, CAST(strftime('%s', 'now') AS INTEGER) FROM "beatmapsets";
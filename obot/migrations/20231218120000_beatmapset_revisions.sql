@@ -0,0 +1,12 @@
+-- 難易度の追加・削除，星の再計算などの変更履歴
+CREATE TABLE IF NOT EXISTS "beatmapset_revisions" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    beatmapset_id INTEGER NOT NULL REFERENCES "beatmapsets" (id) ON DELETE CASCADE,
+    beatmap_id INTEGER NOT NULL,
+    version TEXT NOT NULL,
+    change TEXT NOT NULL, -- added, removed, star_changed
+    old_rating REAL,
+    new_rating REAL,
+    detected_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "beatmapset_revisions_beatmapset_id" ON "beatmapset_revisions" (beatmapset_id);
@@ -0,0 +1,13 @@
+-- 新着譜面を通知するチャンネルとその条件
+-- statuses, keysはカンマ区切り(keysが空なら全てのキー数)
+CREATE TABLE IF NOT EXISTS "subscriptions" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    guild_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    statuses TEXT NOT NULL,
+    keys TEXT NOT NULL,
+    min_stars REAL,
+    max_stars REAL,
+    created_at INTEGER NOT NULL, -- unix time
+    UNIQUE (guild_id, channel_id)
+);
@@ -0,0 +1,31 @@
+-- mania以外のモードにも対応する
+-- 難易度にmode, cs, arを持たせ，keysはmaniaのときだけ入れる(NOT NULLを外すので作り直す)
+CREATE TABLE IF NOT EXISTS "beatmaps_new" (
+    id INTEGER PRIMARY KEY NOT NULL,
+    beatmapset_id INTEGER NOT NULL REFERENCES "beatmapsets" (id) ON DELETE CASCADE,
+    mode TEXT NOT NULL, -- osu, taiko, fruits, mania
+    version TEXT NOT NULL,
+    difficulty_rating REAL NOT NULL,
+    keys INTEGER, -- maniaのみ
+    cs REAL NOT NULL,
+    ar REAL NOT NULL,
+    od REAL NOT NULL,
+    hp REAL NOT NULL,
+    bpm REAL NOT NULL,
+    total_length INTEGER NOT NULL,
+    count_notes INTEGER NOT NULL,
+    count_lns INTEGER NOT NULL
+);
+
+-- 今まではmaniaのみ(csがキー数)
+INSERT INTO "beatmaps_new" (id, beatmapset_id, mode, version, difficulty_rating, keys, cs, ar, od, hp, bpm, total_length, count_notes, count_lns)
+    SELECT id, beatmapset_id, 'mania', version, difficulty_rating, keys, keys, 0, od, hp, bpm, total_length, count_notes, count_lns
+    FROM "beatmaps";
+
+DROP TABLE "beatmaps";
+ALTER TABLE "beatmaps_new" RENAME TO "beatmaps";
+CREATE INDEX IF NOT EXISTS "beatmaps_beatmapset_id" ON "beatmaps" (beatmapset_id);
+CREATE INDEX IF NOT EXISTS "beatmaps_mode_keys" ON "beatmaps" (mode, keys);
+
+-- 通知するモード(カンマ区切り，空なら全て)．既存の設定はmaniaのまま
+ALTER TABLE "subscriptions" ADD COLUMN modes TEXT NOT NULL DEFAULT 'mania';
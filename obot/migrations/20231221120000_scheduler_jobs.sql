@@ -0,0 +1,6 @@
+-- スケジューラのjobごとの最後の実行時刻
+-- 停止中に実行時刻を過ぎたjobを起動後に実行するため
+CREATE TABLE IF NOT EXISTS "scheduler_jobs" (
+    name TEXT PRIMARY KEY NOT NULL,
+    last_run INTEGER NOT NULL -- unix time
+);
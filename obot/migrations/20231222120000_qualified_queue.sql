@@ -0,0 +1,17 @@
+-- APIのranked_date(qualifiedならqualifiedになった日時，unix time)
+ALTER TABLE "beatmapsets" ADD COLUMN ranked_date INTEGER;
+
+-- 既存のqualified譜面はstatusの履歴から埋めておく(次のrefresh_qualifiedでAPIの値になる)
+UPDATE "beatmapsets" SET ranked_date = (
+    SELECT MAX(changed_at) FROM "beatmapset_status_history" h
+    WHERE h.beatmapset_id = "beatmapsets".id AND h.new_status = 'qualified'
+) WHERE status = 'qualified';
+
+-- ranking予定時刻の前に送ったリマインダー
+-- disqualifyされて再びqualifiedになったらranked_dateが変わるので，もう一度送る
+CREATE TABLE IF NOT EXISTS "qualified_reminders" (
+    beatmapset_id INTEGER NOT NULL,
+    ranked_date INTEGER NOT NULL,
+    sent_at INTEGER NOT NULL, -- unix time
+    PRIMARY KEY (beatmapset_id, ranked_date)
+);
@@ -0,0 +1,15 @@
+-- digest: NULLなら見つけた譜面をすぐに通知する．間隔かcron式なら，その間に見つけた譜面をまとめて送る
+-- last_digest: 最後にまとめて送った時刻(unix time)
+ALTER TABLE "subscriptions" ADD COLUMN digest TEXT;
+ALTER TABLE "subscriptions" ADD COLUMN last_digest INTEGER;
+
+-- digestで次に送る予定の譜面
+-- kindは"Ranked", "Disqualified", "Updated"などの見出し
+CREATE TABLE IF NOT EXISTS "digest_entries" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subscription_id INTEGER NOT NULL REFERENCES "subscriptions" (id) ON DELETE CASCADE,
+    beatmapset_id INTEGER NOT NULL,
+    kind TEXT NOT NULL,
+    created_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "digest_entries_subscription_id" ON "digest_entries" (subscription_id);
@@ -0,0 +1,13 @@
+-- 検索用にtagsも持っておく
+ALTER TABLE "beatmapsets" ADD COLUMN tags TEXT NOT NULL DEFAULT '';
+
+-- /search用の全文検索(rowidはbeatmapsetのid)
+-- versionsは難易度名を空白区切りにしたもの．beatmapsetsを入れ直すときに一緒に入れ直す
+CREATE VIRTUAL TABLE IF NOT EXISTS "beatmapsets_fts" USING fts5(
+    title, artist, creator, tags, versions,
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+INSERT INTO "beatmapsets_fts" (rowid, title, artist, creator, tags, versions)
+    SELECT s.id, s.title, s.artist, s.creator, s.tags,
+        COALESCE((SELECT group_concat(version, ' ') FROM "beatmaps" WHERE beatmapset_id = s.id), '')
+    FROM "beatmapsets" s;
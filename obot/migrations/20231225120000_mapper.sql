@@ -0,0 +1,2 @@
+-- mapperのプロフィールへのリンク用(古い行はNULLのまま，次に取り直したときに入る)
+ALTER TABLE "beatmapsets" ADD COLUMN user_id INTEGER;
@@ -0,0 +1,9 @@
+-- ダウンロード済みの譜面(.osz)
+-- statusが変わっても同じbeatmapsetは1回だけダウンロードする
+CREATE TABLE IF NOT EXISTS "downloads" (
+    beatmapset_id INTEGER PRIMARY KEY NOT NULL,
+    path TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    sha256 TEXT NOT NULL,
+    downloaded_at INTEGER NOT NULL
+);
@@ -0,0 +1,14 @@
+-- ダウンロード待ちの譜面
+-- state: queued(待ち), active(ダウンロード中), failed(DOWNLOAD_MAX_ATTEMPTS回失敗した)
+-- 終わったものは消す(downloadsに記録される)
+CREATE TABLE IF NOT EXISTS "download_jobs" (
+    beatmapset_id INTEGER PRIMARY KEY NOT NULL,
+    title TEXT NOT NULL,
+    status TEXT NOT NULL,
+    state TEXT NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_try INTEGER NOT NULL,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "download_jobs_state" ON "download_jobs" (state, next_try);
@@ -0,0 +1,8 @@
+-- ファイルサーバーから配った譜面(ユーザーごとのダウンロード数の制限用)
+CREATE TABLE IF NOT EXISTS "file_downloads" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    beatmapset_id INTEGER NOT NULL,
+    downloaded_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS "file_downloads_user" ON "file_downloads" (user_id, downloaded_at);
@@ -0,0 +1,30 @@
+-- ダウンロードした譜面(maniaの難易度)の配置の分析結果
+-- category: stream, jack, chordjack, ln, mixed
+-- 割合は0.0 ~ 1.0
+CREATE TABLE IF NOT EXISTS "patterns" (
+    beatmap_id INTEGER PRIMARY KEY NOT NULL,
+    beatmapset_id INTEGER NOT NULL,
+    category TEXT NOT NULL,
+    ln_ratio REAL NOT NULL,
+    jack_density REAL NOT NULL,
+    chordjack_ratio REAL NOT NULL,
+    stream_ratio REAL NOT NULL,
+    peak_nps REAL NOT NULL,
+    analyzed_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "patterns_beatmapset_id" ON "patterns" (beatmapset_id);
+CREATE INDEX IF NOT EXISTS "patterns_category" ON "patterns" (category);
+
+-- 配置で絞り込む通知設定(NULLなら絞り込まない)
+ALTER TABLE "subscriptions" ADD COLUMN pattern TEXT;
+
+-- 配置の条件があるsubscriptionに，分析が終わってから送る予定の譜面
+-- old_statusは見つけたときの変更前のstatus(新しい譜面ならNULL)
+CREATE TABLE IF NOT EXISTS "pattern_pending" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    subscription_id INTEGER NOT NULL REFERENCES "subscriptions" (id) ON DELETE CASCADE,
+    beatmapset_id INTEGER NOT NULL,
+    old_status TEXT,
+    created_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "pattern_pending_beatmapset_id" ON "pattern_pending" (beatmapset_id);
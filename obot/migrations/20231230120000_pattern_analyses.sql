@@ -0,0 +1,8 @@
+-- 配置を分析したbeatmapset(maniaの難易度が読めず，patternsに行が無い場合も入れる)
+CREATE TABLE IF NOT EXISTS "pattern_analyses" (
+    beatmapset_id INTEGER PRIMARY KEY NOT NULL,
+    analyzed_at INTEGER NOT NULL -- unix time
+);
+INSERT OR IGNORE INTO "pattern_analyses" (beatmapset_id, analyzed_at)
+    SELECT beatmapset_id, MAX(analyzed_at) FROM "patterns" GROUP BY beatmapset_id;
+CREATE INDEX IF NOT EXISTS "pattern_pending_created_at" ON "pattern_pending" (created_at);
@@ -0,0 +1,15 @@
+-- 難易度ごとのSSのpp(NM)．ダウンロード後の分析で配置と一緒に計算する
+ALTER TABLE "patterns" ADD COLUMN ss_pp REAL;
+-- ss_ppを入れるためにダウンロード済みの譜面を(起動時に)分析し直す
+DELETE FROM "pattern_analyses";
+
+-- ダウンロード前に送った通知(ダウンロードして分析した後にSS ppを付けて編集する)
+CREATE TABLE IF NOT EXISTS "event_messages" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    beatmapset_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    message_id INTEGER NOT NULL,
+    created_at INTEGER NOT NULL -- unix time
+);
+CREATE INDEX IF NOT EXISTS "event_messages_beatmapset_id" ON "event_messages" (beatmapset_id);
+CREATE INDEX IF NOT EXISTS "event_messages_created_at" ON "event_messages" (created_at);
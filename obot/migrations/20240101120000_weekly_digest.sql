@@ -0,0 +1,2 @@
+-- cronクレートでは曜日の1が日曜なので，weeklyの式を月曜(Mon)にする
+UPDATE "subscriptions" SET digest = '0 0 * * Mon' WHERE digest = '0 0 * * 1';
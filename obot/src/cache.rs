@@ -11,6 +11,10 @@ use serenity::{
 
 use tokio::sync::Mutex;
 
+use crate::config::Config;
+use crate::downloader::DownloadQueue;
+use crate::web::api::Api;
+
 // bot操作用の構造体(shutdownとか)
 pub struct SharedManagerContainer;
 impl TypeMapKey for SharedManagerContainer {
@@ -35,8 +39,20 @@ impl TypeMapKey for Database {
     type Value = Arc<Mutex<sqlx::SqlitePool>>;
 }
 
-// 環境変数を記録する
-pub struct Env;
-impl TypeMapKey for Env {
-    type Value = Arc<Mutex<HashMap<String, String>>>; // {name: value}
-}
\ No newline at end of file
+// 起動時に読み込んだ設定
+pub struct Settings;
+impl TypeMapKey for Settings {
+    type Value = Arc<Config>;
+}
+
+// osu! APIのclient(tokenをcacheするので使い回す)
+pub struct OsuApi;
+impl TypeMapKey for OsuApi {
+    type Value = Arc<Api>;
+}
+
+// ダウンロードのworkerとの共有状態
+pub struct Downloads;
+impl TypeMapKey for Downloads {
+    type Value = Arc<DownloadQueue>;
+}
@@ -1,21 +1,86 @@
 use serenity::{
-    framework::standard::{
-        macros::{command},
-        CommandResult, Args,
-    },
+    builder::{CreateApplicationCommands, CreateEmbed},
     model::{
         channel::GuildChannel,
+        application::{command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction},
         prelude::*,
     },
     prelude::*,
 };
 
 use crate::cache::{Database, SharedManagerContainer, CommandCounter};
-use crate::owner;
+use crate::config;
+use super::{CommandResult, check_owner, reply, reply_embed, followup, option_i64, option_str};
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("shutdown")
+                .description("Bot-Processを終了します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+        })
+        .create_application_command(|c| {
+            c.name("delmsg")
+                .description("指定した数だけこのチャンネルのメッセージを削除します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .dm_permission(false)
+                .create_option(|o| {
+                    o.name("count")
+                        .description("削除するメッセージ数")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(100)
+                        .required(true)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("todo")
+                .description("todoを追加、削除、一覧表示します")
+                .create_option(|o| {
+                    o.name("add")
+                        .description("todoを追加します")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|s| {
+                            s.name("todo")
+                                .description("追加するtodo")
+                                .kind(CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+                .create_option(|o| {
+                    o.name("list")
+                        .description("todoを一覧表示します")
+                        .kind(CommandOptionType::SubCommand)
+                })
+                .create_option(|o| {
+                    o.name("remove")
+                        .description("todoを削除します")
+                        .kind(CommandOptionType::SubCommand)
+                        .create_sub_option(|s| {
+                            s.name("todo")
+                                .description("削除するtodo")
+                                .kind(CommandOptionType::String)
+                                .required(true)
+                        })
+                })
+        })
+        .create_application_command(|c| {
+            c.name("infoc")
+                .description("コマンドの実行回数を表示します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+        })
+        .create_application_command(|c| {
+            c.name("reload_config")
+                .description("設定を読み直します(変更点はlog channelに送ります)")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+        })
+}
+
+pub async fn shutdown(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
+        return Ok(());
+    }
 
-#[command]
-#[description("Bot-Processを終了します")]
-async fn shutdown(ctx: &Context, msg: &Message) -> CommandResult {
     let data = ctx.data.read().await;
     let manager = match data.get::<SharedManagerContainer>().cloned() {
         Some(manager) => manager,
@@ -26,93 +91,97 @@ async fn shutdown(ctx: &Context, msg: &Message) -> CommandResult {
     };
     let mut manager = manager.lock().await;
 
-    if owner::is_owner(&ctx, msg.author.id).await {
-        msg.channel_id.say(&ctx.http, "Shutting down...").await?;
-        info!("Shutting down by {}", msg.author.name);
-        manager.shutdown_all().await;
-    } else {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
-    }
+    reply(ctx, command, "Shutting down...", false).await?;
+    info!("Shutting down by {}", command.user.name);
+    manager.shutdown_all().await;
 
     Ok(())
 }
 
-#[command]
-#[description("引数分だけコマンドが実行されたチャンネルのメッセージを削除します")]
-#[min_args(1)]
-#[max_args(1)]
-async fn delmsg(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
-        info!("{} tried to use delmsg", msg.author.name);
+pub async fn delmsg(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
 
-    let nd = match args.rest().parse::<u64>() {
-        Ok(n) => n,
-        Err(_) => {
-            msg.channel_id.say(&ctx.http, "Invalid number").await?;
+    let nd = match option_i64(&command.data.options, "count") {
+        Some(n) if n > 0 => n as u64,
+        _ => {
+            reply(ctx, command, "Invalid number", true).await?;
             return Ok(());
         }
     };
 
-    let channel: GuildChannel = msg.channel_id.to_channel(&ctx.http).await?.guild().unwrap();
-    let messages = match channel.messages(&ctx.http, |r| r.limit(nd)).await {
+    let channel: GuildChannel = match command.channel_id.to_channel(&ctx.http).await?.guild() {
+        Some(c) => c,
+        None => {
+            reply(ctx, command, "This command can only be used in a server", true).await?;
+            return Ok(());
+        }
+    };
+    command.defer(&ctx.http).await?;
+    // deferの応答メッセージは消さない
+    let original = command.get_interaction_response(&ctx.http).await?;
+    let messages = match channel.messages(&ctx.http, |r| r.before(original.id).limit(nd)).await {
         Ok(m) => m,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "Failed to get messages").await?;
+            followup(ctx, command, "Failed to get messages").await?;
             error!("Failed to get messages: {}", e);
             return Ok(());
         }
     };
-    if messages.len() == 0 {
-        msg.channel_id.say(&ctx.http, "No messages").await?;
+    if messages.is_empty() {
+        followup(ctx, command, "No messages").await?;
         return Ok(());
     }
 
-    let mut ids = Vec::new();
-    for m in messages {
-        ids.push(m.id);
-    }
-    match channel.delete_messages(&ctx.http, &ids).await {
-        Ok(_) => (),
-        Err(e) => {
-            msg.channel_id.say(&ctx.http, "Failed to delete messages").await?;
-            error!("Failed to delete messages: {}", e);
-            return Ok(());
-        }
+    let ids = messages.iter().map(|m| m.id).collect::<Vec<MessageId>>();
+    let res = if ids.len() == 1 {
+        channel.id.delete_message(&ctx.http, ids[0]).await
+    } else {
+        channel.delete_messages(&ctx.http, &ids).await
+    };
+    if let Err(e) = res {
+        followup(ctx, command, "Failed to delete messages").await?;
+        error!("Failed to delete messages: {}", e);
+        return Ok(());
     }
 
-    msg.channel_id.say(&ctx.http, format!("Deleted {} messages", nd)).await?;
+    followup(ctx, command, format!("Deleted {} messages", ids.len())).await?;
     Ok(())
 }
 
 
 // test command: todo
-// if arg is "add", add todo, if arg is "list", print todo list, and 
-// if arg is "remove", remove todo from database
-#[command]
-#[description("todoを追加、削除、一覧表示します")]
-#[usage("todo add <todo> | todo remove <todo> | todo list")]
-async fn todo(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
-    let data = ctx.data.read().await;
-    match args.current() {
-        Some("add") => {
-            let todo = msg.content.split("add ").collect::<Vec<&str>>()[1];
-            let db = data.get::<Database>().cloned().unwrap();
-            let db = db.lock().await;
-            let user_id = msg.author.id.0 as i64;
+// subcommandがaddならtodoを追加，listなら一覧表示，removeならdatabaseから削除
+pub async fn todo(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let sub = match command.data.options.first() {
+        Some(s) => s,
+        None => {
+            reply(ctx, command, "Invalid argument", true).await?;
+            return Ok(());
+        }
+    };
+    let db = match ctx.data.read().await.get::<Database>().cloned() {
+        Some(db) => db,
+        None => {
+            error!("Database not found in TypeMap");
+            return Ok(());
+        }
+    };
+    let db = db.lock().await;
+    let user_id = command.user.id.0 as i64;
+    let todo = option_str(&sub.options, "todo").unwrap_or_default();
+
+    match sub.name.as_str() {
+        "add" => {
             let _ = sqlx::query!(
                 "INSERT INTO todo (user_id, todo) VALUES (?, ?)",
                 user_id, todo)
                 .execute(&*db)
                 .await;
-            msg.channel_id.say(&ctx.http, format!("Added todo: {}", todo)).await?;
+            reply(ctx, command, format!("Added todo: {}", todo), false).await?;
         },
-        Some("list") => {
-            let db = data.get::<Database>().cloned().unwrap();
-            let db = db.lock().await;
-            let user_id = msg.author.id.0 as i64;
+        "list" => {
             let rows = sqlx::query!(
                 "SELECT todo FROM todo WHERE user_id = ?",
                 user_id)
@@ -123,20 +192,16 @@ async fn todo(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
                 todos.push_str(&format!("{}. {}\n", i + 1, row.todo));
             }
             if todos.is_empty() {
-                msg.channel_id.say(&ctx.http, "You have no todos").await?;
+                reply(ctx, command, "You have no todos", true).await?;
             } else {
-                msg.channel_id.say(&ctx.http, format!(
+                reply(ctx, command, format!(
                     "Todo list for {}:\n{}",
-                    msg.author.name,
+                    command.user.name,
                     todos
-                )).await?;
+                ), false).await?;
             }
         },
-        Some("remove") => {
-            let todo = msg.content.split("remove ").collect::<Vec<&str>>()[1];
-            let db = data.get::<Database>().cloned().unwrap();
-            let db = db.lock().await;
-            let user_id = msg.author.id.0 as i64;
+        "remove" => {
             let q = sqlx::query!(
                 "SELECT todo FROM todo WHERE user_id = ? AND todo = ?",
                 user_id, todo)
@@ -149,15 +214,15 @@ async fn todo(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
                         user_id, todo)
                         .execute(&*db)
                         .await;
-                    msg.channel_id.say(&ctx.http, format!("Removed todo: {}", todo)).await?;
+                    reply(ctx, command, format!("Removed todo: {}", todo), false).await?;
                 },
                 Err(_) => {
-                    msg.channel_id.say(&ctx.http, "Todo not found").await?;
+                    reply(ctx, command, "Todo not found", true).await?;
                 }
             }
         },
         _ => {
-            msg.channel_id.say(&ctx.http, "Invalid argument").await?;
+            reply(ctx, command, "Invalid argument", true).await?;
         }
     }
 
@@ -165,33 +230,34 @@ async fn todo(ctx: &Context, msg: &Message, args: Args) -> CommandResult {
 }
 
 // dbg command: print CommandCounter
-#[command]
-#[description("コマンドの実行回数を表示します")]
-async fn infoc(ctx: &Context, msg: &Message) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn infoc(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
-    let data = ctx.data.read().await;
-    let counter = data.get::<CommandCounter>().unwrap();
     let mut content = String::new();
-    for (key, value) in counter.iter() {
-        content.push_str(&format!("{}: {}\n", key, value));
-    }
-
-    match msg.channel_id.send_message(&ctx.http, |m| {
-        m.embed(|e| {
-            e.title("Command Counter");
-            e.description(content);
-            e
-        });
-        m
-    }).await {
-        Ok(_) => {},
-        Err(e) => {
-            warn!("Failed to send message in infoc: {}", e);
+    if let Some(counter) = ctx.data.read().await.get::<CommandCounter>() {
+        for (key, value) in counter.iter() {
+            content.push_str(&format!("{}: {}\n", key, value));
         }
     }
 
-    Ok(())
-}
\ No newline at end of file
+    let mut embed = CreateEmbed::default();
+    embed.title("Command Counter");
+    embed.description(content);
+    reply_embed(ctx, command, embed).await
+}
+
+// 設定を読み直す(SIGHUPと同じ)
+pub async fn reload_config(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
+        return Ok(());
+    }
+    match config::reload(&ctx.data, &ctx.http).await {
+        Ok(changes) if changes.is_empty() => reply(ctx, command, "Reloaded config (no changes)", true).await,
+        Ok(changes) => {
+            let lines = changes.iter().map(|c| c.to_string()).collect::<Vec<String>>().join("\n");
+            reply(ctx, command, format!("Reloaded config:\n{}", lines), true).await
+        },
+        Err(e) => reply(ctx, command, format!("Failed to reload config:\n```\n{}```", e), true).await,
+    }
+}
@@ -0,0 +1,232 @@
+use std::{error::Error, time::Duration};
+
+use serenity::{
+    builder::{CreateApplicationCommands, CreateEmbed},
+    model::application::{
+        command::CommandOptionType,
+        interaction::{application_command::ApplicationCommandInteraction, InteractionResponseType},
+    },
+    prelude::*,
+};
+
+use crate::config::Config;
+use crate::db::handler::{DBHandler, DownloadJob};
+use crate::downloader::{self, DownloadQueue};
+use crate::fileserver;
+use crate::utility::unix_now;
+use crate::web::api::Api;
+use super::{CommandResult, check_owner, followup, reply, reply_embed, option_bool, option_i64};
+
+// ダウンロード中は，この間隔でメッセージを更新する
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+// 更新を続ける最大の時間
+const REFRESH_FOR: Duration = Duration::from_secs(5 * 60);
+// 各状態で表示する最大数
+const MAX_LINES: usize = 8;
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("downloads")
+                .description("譜面のダウンロード状況(待ち，ダウンロード中，失敗)を表示します")
+                .create_option(|o| {
+                    o.name("retry_failed")
+                        .description("失敗したダウンロードをやり直します (owner only)")
+                        .kind(CommandOptionType::Boolean)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("getmap")
+                .description("ダウンロード済みの譜面のダウンロードリンクをDMで送ります")
+                .create_option(|o| {
+                    o.name("id")
+                        .description("beatmapset id")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+        })
+}
+
+pub async fn downloads(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let retry = option_bool(&command.data.options, "retry_failed").unwrap_or(false);
+    if retry && !check_owner(ctx, command).await? {
+        return Ok(());
+    }
+    let retried = if retry {
+        match downloader::retry_failed(ctx).await {
+            Ok(n) => Some(n),
+            Err(e) => {
+                reply(ctx, command, "Failed to retry downloads...", true).await?;
+                error!("Failed to retry failed downloads: {}", e);
+                return Ok(());
+            }
+        }
+    } else {
+        None
+    };
+
+    let queue = DownloadQueue::shared(ctx).await?;
+    let db = DBHandler::new(ctx).await;
+    let (mut embed, mut busy) = status_embed(&db, &queue).await?;
+    if let Some(n) = retried {
+        embed.field("Retry", format!("Requeued {} failed downloads", n), false);
+    }
+    reply_embed(ctx, command, embed).await?;
+
+    // 待ちかダウンロード中のものがある間は進み具合を更新する
+    let until = tokio::time::Instant::now() + REFRESH_FOR;
+    while busy && tokio::time::Instant::now() < until {
+        tokio::time::sleep(REFRESH_INTERVAL).await;
+        let (embed, b) = status_embed(&db, &queue).await?;
+        busy = b;
+        command.edit_original_interaction_response(&ctx.http, |r| r.set_embed(embed)).await?;
+    }
+    Ok(())
+}
+
+// 許可されたサーバーのメンバーに，署名付きのダウンロードリンクをDMで送る
+// まだダウンロードしていなければダウンロード待ちに入れる
+pub async fn getmap(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let id = match option_i64(&command.data.options, "id") {
+        Some(id) => id,
+        None => {
+            reply(ctx, command, "Invalid id", true).await?;
+            return Ok(());
+        }
+    };
+    let config = Config::shared(ctx).await?;
+    if config.file_server_addr.is_none() {
+        reply(ctx, command, "File sharing is not enabled on this bot", true).await?;
+        return Ok(());
+    }
+    match command.guild_id {
+        Some(g) if config.getmap_guilds.contains(&g) => {},
+        _ => {
+            reply(ctx, command, "This command is not enabled in this server", true).await?;
+            return Ok(());
+        }
+    }
+
+    let db = DBHandler::new(ctx).await;
+    let downloaded = match db.get_download(id).await? {
+        Some(d) => std::path::Path::new(&d.path).exists(),
+        None => false,
+    };
+    if !downloaded {
+        command.create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+                .interaction_response_data(|d| d.ephemeral(true))
+        }).await?;
+        let api = Api::shared(ctx).await?;
+        let maps = match api.get_beatmaps_by_ids(vec![id.to_string()]).await {
+            Ok(m) => m,
+            Err(e) => {
+                followup(ctx, command, "Failed to get mapset!\n Please inform the owner").await?;
+                error!("Failed to get mapset {}: {}", id, e);
+                return Ok(());
+            }
+        };
+        if maps.is_empty() {
+            followup(ctx, command, format!("Mapset {} not found", id)).await?;
+            return Ok(());
+        }
+        downloader::enqueue(ctx, &maps).await?;
+        followup(ctx, command, format!("Mapset {} is not downloaded yet. It has been queued, try again later (see /downloads)", id)).await?;
+        return Ok(());
+    }
+
+    let (used, counted) = fileserver::quota_used(&db, command.user.id.0, id).await?;
+    if !counted && used >= config.download_quota as usize {
+        reply(ctx, command, format!("You have reached the download quota ({} mapsets per 24 hours)", config.download_quota), true).await?;
+        return Ok(());
+    }
+    let (url, expires) = match fileserver::link(&config, id, command.user.id.0) {
+        Some(l) => l,
+        None => {
+            reply(ctx, command, "File sharing is not enabled on this bot", true).await?;
+            return Ok(());
+        }
+    };
+
+    let content = format!("Download link for mapset {} (expires <t:{}:R>)\n{}", id, expires, url);
+    match command.user.direct_message(&ctx, |m| m.content(&content)).await {
+        Ok(_) => reply(ctx, command, "Sent the download link by DM", true).await?,
+        // DMを受け取らない設定なら実行者にだけ見える返信で送る
+        Err(e) => {
+            warn!("Failed to send DM to {}: {}", command.user.id, e);
+            reply(ctx, command, content, true).await?;
+        }
+    }
+    Ok(())
+}
+
+// 状況のembedと，まだ終わっていないものがあるか
+async fn status_embed(db: &DBHandler, queue: &DownloadQueue) -> Result<(CreateEmbed, bool), Box<dyn Error + Send + Sync>> {
+    let jobs = db.download_jobs().await?;
+    let progress = queue.progress().await;
+    let now = unix_now();
+
+    let by_state = |state: &str| jobs.iter().filter(|j| j.state == state).collect::<Vec<&DownloadJob>>();
+    let active = by_state("active");
+    let queued = by_state("queued");
+    let failed = by_state("failed");
+
+    let mut sections = Vec::new();
+    if !active.is_empty() {
+        let lines = active.iter().map(|j| format!("{} {}", job_line(j), progress_str(progress.get(&j.beatmapset_id))));
+        sections.push(section("Downloading", active.len(), lines));
+    }
+    if !queued.is_empty() {
+        let lines = queued.iter().map(|j| {
+            if j.attempts > 0 && j.next_try > now {
+                format!("{} (retry <t:{}:R>, failed {} times)", job_line(j), j.next_try, j.attempts)
+            } else {
+                job_line(j)
+            }
+        });
+        sections.push(section("Queued", queued.len(), lines));
+    }
+    if !failed.is_empty() {
+        let lines = failed.iter().map(|j| {
+            let error = j.last_error.as_deref().unwrap_or("unknown error").chars().take(80).collect::<String>();
+            format!("{} ({} attempts): {}", job_line(j), j.attempts, error)
+        });
+        sections.push(section("Failed", failed.len(), lines));
+    }
+
+    let mut e = CreateEmbed::default();
+    e.title("Downloads")
+        .color(if failed.is_empty() { 0x00ffff } else { 0xffa500 })
+        .description(if sections.is_empty() { "No downloads".to_string() } else { sections.join("\n\n") });
+    let busy = !active.is_empty() || !queued.is_empty();
+    if busy {
+        e.footer(|f| f.text(format!("Updates every {}s while downloading", REFRESH_INTERVAL.as_secs())));
+    }
+    Ok((e, busy))
+}
+
+// "**Queued (12)**" と最大MAX_LINES行
+fn section(title: &str, total: usize, lines: impl Iterator<Item = String>) -> String {
+    let mut s = format!("**{} ({})**\n", title, total);
+    s.push_str(&lines.take(MAX_LINES).collect::<Vec<String>>().join("\n"));
+    if total > MAX_LINES {
+        s.push_str(&format!("\n...and {} more", total - MAX_LINES));
+    }
+    s
+}
+
+fn job_line(job: &DownloadJob) -> String {
+    let title = job.title.chars().take(40).collect::<String>();
+    format!("`{}` {} ({})", job.beatmapset_id, title, job.status)
+}
+
+// "45% (1.2 / 2.6 MB)"
+fn progress_str(progress: Option<&(u64, u64)>) -> String {
+    let mb = |b: u64| b as f64 / 1024.0 / 1024.0;
+    match progress {
+        Some((done, total)) if *total > 0 => format!("{}% ({:.1} / {:.1} MB)", done * 100 / total, mb(*done), mb(*total)),
+        Some((done, _)) => format!("{:.1} MB", mb(*done)),
+        None => "starting...".to_string(),
+    }
+}
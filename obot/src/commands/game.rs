@@ -1,61 +1,145 @@
 use serenity::{
-    framework::standard::{
-        macros::{command},
-        CommandResult, Args,
-    },
+    builder::{CreateApplicationCommands, CreateComponents, CreateEmbed},
     model::{
+        application::{
+            command::CommandOptionType,
+            interaction::{application_command::ApplicationCommandInteraction, InteractionResponseType},
+        },
         prelude::*,
     },
-    builder::CreateEmbed,
     prelude::*,
 };
 
-use crate::owner;
-use crate::utility;
+use crate::downloader;
 use crate::web::{
-    api::{Api, Beatmap}, handler as web_handler,
+    api::{self as web_api, Api, Beatmap}, handler as web_handler, status::{projected_rank_time, status_label},
 };
 use crate::db::handler::DBHandler;
+use crate::web::mode::GameMode;
+use super::paginator::{Paginator, PER_PAGE, IDLE_TIMEOUT, respond_ephemeral};
+use super::{
+    CommandResult, check_owner, reply, followup, followup_embed,
+    option_i64, option_str, parse_status, parse_mode, key_list, status_option, key_option, mode_option,
+};
+
+// 一度に扱える譜面の最大数
+const MAX_IDS: usize = 10;
+// dbtopで表示する最大数
+const MAX_TOP: usize = 100;
+// 選択メニューの選択肢の上限と1メッセージに付けられるメニューの数(Discordの制限)
+const MAX_MENU_OPTIONS: usize = 25;
+const MAX_MENUS: usize = 5;
+// 2つ目以降のメニューは"mapset_difficulty_1"のように番号を付ける
+const DIFFICULTY_MENU_ID: &str = "mapset_difficulty";
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("init_database")
+                .description("全ての譜面情報により譜面データベースを強制的に更新します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .create_option(|o| status_option(o, "対象のstatus (default: all)"))
+                .create_option(|o| mode_option(o, "対象のmode (default: mania)"))
+        })
+        .create_application_command(|c| {
+            c.name("update_database")
+                .description("最新50件の譜面情報により譜面データベースを強制的に更新します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+        })
+        .create_application_command(|c| {
+            c.name("newmaps")
+                .description("最新のbeatmapsets情報10件を表示します(ranked, loved, qualified)")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .create_option(|o| status_option(o, "対象のstatus (default: ranked)"))
+                .create_option(|o| mode_option(o, "対象のmode (default: mania)"))
+                .create_option(|o| key_option(o, "キー数 (default: 4)"))
+        })
+        .create_application_command(|c| {
+            c.name("dlmaps")
+                .description("指定されたidの譜面をダウンロードします(最大10件)")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .create_option(|o| {
+                    o.name("ids")
+                        .description("beatmapset id (空白区切り)")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("mapset_info")
+                .description("指定されたidの譜面情報を難易度ごとに表示します")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .create_option(|o| {
+                    o.name("id")
+                        .description("beatmapset id")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("dbsize")
+                .description("譜面情報格納DBのサイズを表示します")
+                .create_option(|o| status_option(o, "対象のstatus (default: ranked)"))
+                .create_option(|o| mode_option(o, "対象のmode (default: mania)"))
+                .create_option(|o| key_option(o, "キー数 (default: 4k, 7k)"))
+        })
+        .create_application_command(|c| {
+            c.name("dbtop")
+                .description("譜面情報格納DBの先頭の譜面情報を表示します(最大100件)")
+                .default_member_permissions(Permissions::ADMINISTRATOR)
+                .create_option(|o| status_option(o, "対象のstatus (default: ranked)"))
+                .create_option(|o| mode_option(o, "対象のmode (default: mania)"))
+                .create_option(|o| key_option(o, "キー数 (default: 4k, 7k)"))
+                .create_option(|o| {
+                    o.name("num")
+                        .description("表示する件数 (default: 1)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(MAX_TOP as u64)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("qualified_queue")
+                .description("qualifiedの譜面をranking予定順に表示します")
+                .create_option(|o| mode_option(o, "対象のmode (default: 全て)"))
+                .create_option(|o| key_option(o, "キー数 (default: 全て)"))
+        })
+}
 
 // dbg command: init_database
 // initialize database
-#[command]
-#[description("全ての譜面情報により譜面データベースを強制的に更新します")] 
-#[max_args(1)]
-#[min_args(0)]
-#[usage("init_database [status] (default: all status)")]
-async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn init_database(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
 
-    let keys = vec!["4", "7"];
-
-    let status: Vec<&str> = match arg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "ranked" => ["ranked"].to_vec(),
-                "loved" => ["loved"].to_vec(),
-                "qualified" => ["qualified"].to_vec(),
-                "all" => ["ranked", "loved", "qualified"].to_vec(),
-                _ => ["ranked", "loved", "qualified"].to_vec(),
-            }
+    let options = &command.data.options;
+    let status = match parse_status(option_str(options, "status"), &["ranked", "loved", "qualified"]) {
+        Ok(s) => s,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
         }
-        Err(_e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to parse status! Please inform the owner...").await?;
+    };
+    let mode = match parse_mode(option_str(options, "mode")) {
+        Ok(m) => m,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
             return Ok(());
         }
     };
+    let keys = key_list(mode, None);
 
-    let api = match Api::new(ctx).await {
+    let api = match Api::shared(ctx).await {
         Ok(a) => a,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to initialize api! Please inform the owner...").await?;
+            reply(ctx, command, "[ERROR] Failed to initialize api! Please inform the owner...", true).await?;
             error!("Failed to initialize api: {}", e);
             return Ok(());
         }
     };
+    command.defer(&ctx.http).await?;
 
     let db = DBHandler::new(ctx).await;
 
@@ -63,12 +147,13 @@ async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandRe
     let mut beatmapsets: Vec<Beatmap> = Vec::new();
     let mut over_loop_checker = 0;
     for k in keys {
+        let key = k.map(|k| k.to_string()).unwrap_or_default();
         for s in &status {
             loop {
-                let res = match api.get_beatmapsets_with_cursor("3", s, k, &cursor).await {
+                let res = match api.get_beatmapsets_with_cursor(mode, s, &key, &cursor).await {
                     Ok(r) => r,
                     Err(_e) => {
-                        msg.channel_id.say(&ctx.http, &format!("[ERROR] Failed to get beatmapsets! (status: {}, key: {})", s, k)).await?;
+                        followup(ctx, command, format!("[ERROR] Failed to get beatmapsets! (status: {}, {})", s, mode.group_label(k))).await?;
                         break;
                     }
                 };
@@ -76,16 +161,16 @@ async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandRe
                 beatmapsets.extend(res.0);
                 if cursor.is_empty() {
                     break;
-                }    
+                }
                 over_loop_checker += 1;
                 if over_loop_checker > 1000 {
-                    msg.channel_id.say(&ctx.http, &format!("[ERROR] Too many loops! (status: {}, key: {})", s, k)).await?;
+                    followup(ctx, command, format!("[ERROR] Too many loops! (status: {}, {})", s, mode.group_label(k))).await?;
                     break;
                 }
             }
             // get beatmapsets from db
             for map in &beatmapsets {
-                let res = match db.check_existence(&map.id, &map.statu).await {
+                let res = match db.check_existence(&map.id, &map.status).await {
                     Ok(r) => r,
                     Err(e) => {
                         error!("Failed to check existence: {}", e);
@@ -93,7 +178,7 @@ async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandRe
                     }
                 };
                 if !res {
-                    match db.insert(&map).await {
+                    match db.insert(map).await {
                         Ok(_) => {},
                         Err(e) => {
                             error!("Failed to insert: {}", e);
@@ -103,7 +188,7 @@ async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandRe
                 }
             }
             // send msg to channel
-            msg.channel_id.say(&ctx.http, &format!("Finished to initialize database (status: {}, key: {}) => {} beatmapsets", s, k, beatmapsets.len())).await?;
+            followup(ctx, command, format!("Finished to initialize database (status: {}, {}) => {} beatmapsets", s, mode.group_label(k), beatmapsets.len())).await?;
             beatmapsets.clear();
         }
     }
@@ -111,93 +196,81 @@ async fn init_database(ctx: &Context, msg: &Message, mut arg: Args) -> CommandRe
     Ok(())
 }
 
-#[command]
-#[description("最新50件の譜面情報により譜面データベースを強制的に更新します")]
-async fn update_database(ctx: &Context, msg: &Message) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn update_database(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
+    command.defer(&ctx.http).await?;
     match web_handler::check_maps(ctx).await {
-        Ok(_) => {},
-        Err(_e) => {},
+        Ok(_) => followup(ctx, command, "Updated database").await?,
+        Err(e) => {
+            error!("Failed to update database: {}", e);
+            followup(ctx, command, "[ERROR] Failed to update database... Please inform the owner!").await?;
+        },
     }
 
     Ok(())
 }
 
 // test command: newmaps
-#[command]
-#[description("最新のbeatmapsets情報10件を表示します(ranked, loved, qualified)")]
-#[usage("[status] (default: ranked) [key] (default: 4)")]
-#[max_args(2)]
-#[min_args(0)]
-async fn newmaps(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn newmaps(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
 
-    let api = match Api::new(ctx).await {
-        Ok(a) => a,
+    let options = &command.data.options;
+    let mode = match parse_mode(option_str(options, "mode")) {
+        Ok(m) => m,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to initialize api! Please inform the owner...").await?;
-            error!("Failed to initialize api: {}", e);
+            reply(ctx, command, e, true).await?;
             return Ok(());
         }
     };
-
-    let mut marg = arg.clone();
-    let mode = "3"; // mania
     // default status is ranked
-    let status = match marg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "ranked" => "ranked",
-                "loved" => "loved",
-                "qualified" => "qualified",
-                "all" => "all",
-                _ => "ranked",
-            }
+    let status = match option_str(options, "status").map(|s| s.trim().to_lowercase()) {
+        None => "ranked".to_string(),
+        Some(s) if ["ranked", "loved", "qualified", "all"].contains(&s.as_str()) => s,
+        Some(s) => {
+            reply(ctx, command, format!("Invalid status: {} (ranked, loved, qualified, all)", s), true).await?;
+            return Ok(());
         }
-        Err(_e) => "ranked",
-    };
-    let key = match marg.single::<String>() {
-        Ok(k) => k,
-        Err(_e) => "4".to_string(),
     };
-    let cursor = String::new();
+    // default key is 4 (mania以外では無視される)
+    let key = option_i64(options, "key").unwrap_or(4).to_string();
 
-    let beatmapsets = match api.get_beatmapsets_with_cursor(mode, status, &key, &cursor).await {
-        Ok(b) => b,
+    let api = match Api::shared(ctx).await {
+        Ok(a) => a,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to fetch beatmapsets... Please inform the owner!").await?;
-            error!("Failed to fetch beatmapsets: {}", e);
+            reply(ctx, command, "[ERROR] Failed to initialize api! Please inform the owner...", true).await?;
+            error!("Failed to initialize api: {}", e);
             return Ok(());
         }
     };
+    command.defer(&ctx.http).await?;
 
-    // top 10 beatmapsets
-    let mut beatmapsets = beatmapsets.0;
-    let for_db = beatmapsets.clone();
-    beatmapsets.truncate(10);
-
-    match web_handler::simple_beatmap_send(ctx, &beatmapsets, &msg.channel_id).await {
-        Ok(_) => {},
+    let cursor = String::new();
+    let beatmapsets = match api.get_beatmapsets_with_cursor(mode, &status, &key, &cursor).await {
+        Ok(b) => b,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to send beatmapsets... Please inform the owner!").await?;
-            error!("Failed to send beatmapsets: {}", e);
+            followup(ctx, command, "[ERROR] Failed to fetch beatmapsets... Please inform the owner!").await?;
+            error!("Failed to fetch beatmapsets: {}", e);
             return Ok(());
         }
     };
 
+    let beatmapsets = beatmapsets.0;
+    if beatmapsets.is_empty() {
+        followup(ctx, command, "No beatmapsets found").await?;
+        return Ok(());
+    }
+
     // update database
     let db = DBHandler::new(ctx).await;
-    for map in for_db {
-        match db.check_existence(&map.id, &map.statu).await {
+    for map in &beatmapsets {
+        match db.check_existence(&map.id, &map.status).await {
             Ok(b) => {
-                if b == false {
-                    match db.insert(&map).await {
+                if !b {
+                    match db.insert(map).await {
                         Ok(_) => {},
                         Err(e) => {
                             error!("Failed to insert beatmap: {}", e);
@@ -213,133 +286,176 @@ async fn newmaps(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
         }
     }
 
-    Ok(())
+    // ボタンの操作を待つので，DBを更新してから送る
+    simple_beatmap_pages(ctx, &beatmapsets).await.send(ctx, command, true).await
 }
 
 // test command: download_map
-// fetch api and download map
-#[command]
-#[description("指定されたidの譜面をダウンロードします(最大10件)")]
-#[max_args(10)]
-#[min_args(1)]
-#[usage("dlmaps [id]")]
-async fn dlmaps(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+// fetch api and queue maps for download
+pub async fn dlmaps(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
-    let api = match Api::new(ctx).await {
+    let map_ids = match parse_ids(option_str(&command.data.options, "ids").unwrap_or_default()) {
+        Ok(ids) => ids,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
+    };
+    let api = match Api::shared(ctx).await {
         Ok(a) => a,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to initialize api! Please inform the owner...").await?;
+            reply(ctx, command, "[ERROR] Failed to initialize api! Please inform the owner...", true).await?;
             error!("Failed to initialize api: {}", e);
             return Ok(());
         }
     };
-    let mut map_ids = Vec::new();
-    let mut marg = arg.clone();
-    while let Ok(id) = marg.single::<String>() {
-        map_ids.push(id);
-    }
+    command.defer(&ctx.http).await?;
+
     let maps = match api.get_beatmaps_by_ids(map_ids).await {
         Ok(m) => m,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, format!("Failed to get beatmaps: {}", e)).await?;
+            followup(ctx, command, format!("Failed to get beatmaps: {}", e)).await?;
             return Ok(());
         }
     };
-    
-    let dir = utility::get_env_from_context(ctx, "map_path").await;
-    if let Err(e) = api.download_beatmaps(maps, &dir).await {
-        msg.channel_id.say(&ctx.http, format!("Failed to download beatmaps: {}", e)).await?;
-        return Ok(());
-    }
 
-    msg.channel_id.say(&ctx.http, "Downloaded beatmaps").await?;
+    match downloader::enqueue(ctx, &maps).await {
+        Ok(n) => followup(ctx, command, format!("Queued {} of {} beatmapsets for download (see /downloads)", n, maps.len())).await?,
+        Err(e) => {
+            followup(ctx, command, "[ERROR] Failed to queue beatmapsets... Please inform the owner!").await?;
+            error!("Failed to queue beatmapsets for download: {}", e);
+        }
+    }
     Ok(())
 }
 
 // test command: mapset_info
 // fetch api and print mapset info
-#[command]
-#[description("指定されたidの譜面情報を表示します (最大10件)")]
-#[max_args(10)]
-#[min_args(1)]
-#[usage("mapset_info [mapset id]")]
-async fn mapset_info(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn mapset_info(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
 
-    let api = match Api::new(ctx).await {
+    let id = match option_i64(&command.data.options, "id") {
+        Some(id) => id,
+        None => {
+            reply(ctx, command, "Invalid id", true).await?;
+            return Ok(());
+        }
+    };
+
+    let api = match Api::shared(ctx).await {
         Ok(a) => a,
         Err(e) => {
-            msg.channel_id.say(&ctx.http, "[ERROR] Failed to initialize api! Please inform the owner...").await?;
+            reply(ctx, command, "[ERROR] Failed to initialize api! Please inform the owner...", true).await?;
             error!("Failed to initialize api: {}", e);
             return Ok(());
         }
     };
+    command.defer(&ctx.http).await?;
 
-    let mut mapset_ids = Vec::new();
-    let mut marg = arg.clone();
-    while let Ok(id) = marg.single::<String>() {
-        mapset_ids.push(id);
-    }
-
-    let mapset = match api.get_beatmaps_by_ids(mapset_ids).await {
+    let mapset = match api.get_beatmaps_by_ids(vec![id.to_string()]).await {
         Ok(m) => m,
         Err(_e) => {
-            msg.channel_id.say(&ctx.http, format!("Failed to get mapset!\n Please inform the owner")).await?;
+            followup(ctx, command, "Failed to get mapset!\n Please inform the owner").await?;
             return Ok(());
         }
     };
 
-    // 1件ずつembedで表示
-    for map in mapset {
-        match web_handler::send_beatmap(&ctx, &map, &msg.channel_id).await {
-            Ok(_) => (),
-            Err(e) => {
-                error!("Failed to send beatmap: {}", e);
-                continue;
-            }
+    let map = match mapset.first() {
+        Some(m) => m,
+        None => {
+            followup(ctx, command, format!("Mapset {} not found", id)).await?;
+            return Ok(());
+        }
+    };
+
+    // 難易度を選ぶメニューで表示を切り替える
+    let mut selected = 0;
+    let embed = web_handler::mapset_info_embed(ctx, map, selected).await;
+    let mut message = command.create_followup_message(&ctx.http, |f| {
+        f.add_embed(embed).set_components(difficulty_menu(map, selected))
+    }).await?;
+
+    while let Some(interaction) = message.await_component_interaction(ctx).timeout(IDLE_TIMEOUT).await {
+        if interaction.user.id != command.user.id {
+            respond_ephemeral(ctx, &interaction, "Only the user who ran the command can switch difficulties").await?;
+            continue;
+        }
+        if !interaction.data.custom_id.starts_with(DIFFICULTY_MENU_ID) {
+            continue;
+        }
+        selected = interaction.data.values.first().and_then(|v| v.parse::<usize>().ok()).unwrap_or(0);
+        let embed = web_handler::mapset_info_embed(ctx, map, selected).await;
+        if let Err(e) = interaction.create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| d.set_embed(embed).set_components(difficulty_menu(map, selected)))
+        }).await {
+            warn!("Failed to switch difficulty: {}", e);
         }
     }
 
+    // タイムアウトしたらメニューを消す
+    if let Err(e) = message.edit(&ctx, |m| m.set_components(CreateComponents::default())).await {
+        warn!("Failed to remove difficulty menu: {}", e);
+    }
     Ok(())
 }
 
-#[command]
-#[description("譜面情報格納DBのサイズを表示します")]
-#[usage("[status] (default: ranked), [key] (default: 4)")]
-#[max_args(2)]
-#[min_args(0)]
-async fn dbsize(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
-    let mut marg = arg.clone();
-    let status: Vec<&str> = match marg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "ranked" => vec!["ranked"],
-                "loved" => vec!["loved"],
-                "qualified" => vec!["qualified"],
-                "all" => vec!["ranked", "loved", "qualified"],
-                _ => vec!["ranked"]
-            }
-        },
-        Err(_) => vec!["ranked"]
+// 難易度の選択メニュー
+// 25個を超える分は次のメニューに分ける(最大5つ，125難易度まで)
+fn difficulty_menu(map: &Beatmap, selected: usize) -> CreateComponents {
+    let difficulties = web_handler::sorted_difficulties(map);
+    let chunks = difficulties.chunks(MAX_MENU_OPTIONS).take(MAX_MENUS).collect::<Vec<_>>();
+    let mut c = CreateComponents::default();
+    for (n, chunk) in chunks.iter().enumerate() {
+        let first = n * MAX_MENU_OPTIONS;
+        let (custom_id, placeholder) = if chunks.len() == 1 {
+            (DIFFICULTY_MENU_ID.to_string(), "Select a difficulty".to_string())
+        } else {
+            (format!("{}_{}", DIFFICULTY_MENU_ID, n), format!("Select a difficulty ({}-{})", first + 1, first + chunk.len()))
+        };
+        c.create_action_row(|r| {
+            r.create_select_menu(|m| {
+                m.custom_id(custom_id)
+                    .placeholder(placeholder)
+                    .options(|o| {
+                        for (i, d) in chunk.iter().enumerate().map(|(i, d)| (first + i, d)) {
+                            o.create_option(|opt| {
+                                opt.label(format!("[{}] {}", d.label(), d.version).chars().take(100).collect::<String>())
+                                    .value(i)
+                                    .description(format!("★{:.2}  OD {}  HP {}", d.difficulty_rating, d.od, d.hp))
+                                    .default_selection(i == selected)
+                            });
+                        }
+                        o
+                    })
+            })
+        });
+    }
+    c
+}
+
+pub async fn dbsize(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let options = &command.data.options;
+    let status = match parse_status(option_str(options, "status"), &["ranked"]) {
+        Ok(s) => s,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
     };
-    let key: Vec<&str> = match marg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "4" => vec!["4"],
-                "7" => vec!["7"],
-                "all" => vec!["4", "7"],
-                _ => vec!["4"]
-            }
-        },
-        Err(_) => vec!["4"]
+    let mode = match parse_mode(option_str(options, "mode")) {
+        Ok(m) => m,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
     };
-
+    let key = key_list(mode, option_i64(options, "key"));
+    command.defer(&ctx.http).await?;
 
     let db = DBHandler::new(ctx).await;
     let mut embed = CreateEmbed::default();
@@ -347,107 +463,165 @@ async fn dbsize(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
     embed.color(0x00ffff);
     for k in &key {
         for s in &status {
-            let size = match db.get_db_size(s, k).await {
+            let label = mode.group_label(*k);
+            let size = match db.get_db_size(mode, s, *k).await {
                 Ok(s) => s,
                 Err(e) => {
-                    msg.channel_id.say(&ctx.http, format!("Failed to get {}({}k) db size...", s, k)).await?;
-                    error!("Failed to get {}({}k) db size: {}", s, k, e);
+                    followup(ctx, command, format!("Failed to get {}({}) db size...", s, label)).await?;
+                    error!("Failed to get {}({}) db size: {}", s, label, e);
                     return Ok(());
                 }
             };
-            embed.field(format!("{}({}k)", s, k), format!("{} mapsets", size), true);
+            embed.field(format!("{}({})", s, label), format!("{} mapsets", size), true);
         }
     }
 
-    msg.channel_id.send_message(&ctx.http, |m| {
-        m.embed(|e| {
-            e.0 = embed.0;
-            e
-        });
-        m
-    }).await?;
-
-    Ok(())
+    followup_embed(ctx, command, embed).await
 }
 
-#[command]
-#[description("譜面情報格納DBの先頭の譜面情報を表示します(最大10件)")]
-#[usage("[status] (default: ranked), [key] (default: 4) [num] (default: 1)")]
-#[max_args(4)]
-#[min_args(0)]
-async fn dbtop(ctx: &Context, msg: &Message, arg: Args) -> CommandResult {
-    if owner::is_owner(&ctx, msg.author.id).await == false {
-        msg.channel_id.say(&ctx.http, "You are not the owner").await?;
+pub async fn dbtop(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    if !check_owner(ctx, command).await? {
         return Ok(());
     }
-    
-    let mut marg = arg.clone();
-    let status: Vec<&str> = match marg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "ranked" => vec!["ranked"],
-                "loved" => vec!["loved"],
-                "qualified" => vec!["qualified"],
-                "all" => vec!["ranked", "loved", "qualified"],
-                _ => vec!["ranked"]
-            }
-        },
-        Err(_) => vec!["ranked"]
-    };
-    let key: Vec<&str> = match marg.single::<String>() {
-        Ok(s) => {
-            match s.as_str() {
-                "4" => vec!["4"],
-                "7" => vec!["7"],
-                "all" => vec!["4", "7"],
-                _ => vec!["4"]
-            }
-        },
-        Err(_) => vec!["4"]
+
+    let options = &command.data.options;
+    let status = match parse_status(option_str(options, "status"), &["ranked"]) {
+        Ok(s) => s,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
     };
-    let num: usize = match marg.single::<String>() {
-        Ok(s) => {
-            match s.parse::<usize>() {
-                Ok(n) => n,
-                Err(_) => 1
-            }
-        },
-        Err(_) => 1
+    let mode = match parse_mode(option_str(options, "mode")) {
+        Ok(m) => m,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
     };
-    if num > 10 {
-        msg.channel_id.say(&ctx.http, "num must be less than 10").await?;
-        return Ok(());
-    }
+    let key = key_list(mode, option_i64(options, "key"));
+    let num = option_i64(options, "num").unwrap_or(1).clamp(1, MAX_TOP as i64) as i32;
+    command.defer(&ctx.http).await?;
 
     let db = DBHandler::new(ctx).await;
+    let mut pages = Vec::new();
     for k in &key {
         for s in &status {
-            let db_size = match db.get_db_size(s, k).await {
+            let label = mode.group_label(*k);
+            let db_size = match db.get_db_size(mode, s, *k).await {
                 Ok(s) => s,
                 Err(e) => {
-                    msg.channel_id.say(&ctx.http, format!("Failed to get {}({}k) db size...", s, k)).await?;
-                    error!("Failed to get {}({}k) db size: {}", s, k, e);
+                    followup(ctx, command, format!("Failed to get {}({}) db size...", s, label)).await?;
+                    error!("Failed to get {}({}) db size: {}", s, label, e);
                     return Ok(());
                 }
             };
-            let offset = if db_size > num as i32 { db_size - num as i32 } else { 0 };
-            let topmapsets = match db.select_with_limit("keys", s, k, num as i64, offset as i64).await {
+            let offset = if db_size > num { db_size - num } else { 0 };
+            let res = match k {
+                Some(k) => db.select_with_limit("keys", mode, s, &k.to_string(), num as i64, offset as i64).await,
+                None => db.select_with_limit("*", mode, s, "", num as i64, offset as i64).await,
+            };
+            let topmapsets = match res {
                 Ok(t) => t,
                 Err(e) => {
-                    msg.channel_id.say(&ctx.http, format!("Failed to get {}({}k) db top...", s, k)).await?;
-                    error!("Failed to get {}({}k) db top: {}", s, k, e);
+                    followup(ctx, command, format!("Failed to get {}({}) db top...", s, label)).await?;
+                    error!("Failed to get {}({}) db top: {}", s, label, e);
                     return Ok(());
                 }
             };
-            match web_handler::simple_beatmap_send(ctx, &topmapsets, &msg.channel_id).await {
-                Ok(_) => (),
-                Err(e) => {
-                    error!("Failed to send beatmap: {}", e);
-                    continue;
-                }
+            if topmapsets.is_empty() {
+                followup(ctx, command, format!("No beatmapsets in {}({})", s, label)).await?;
+                continue;
             }
+            pages.extend(simple_beatmap_pages(ctx, &topmapsets).await.into_pages());
         }
     }
+    // status，キー数ごとのページをまとめて1つのメッセージで送る
+    Paginator::new(pages).send(ctx, command, true).await
+}
 
-    Ok(())
-}
\ No newline at end of file
+// "123 456,789" => ["123", "456", "789"]
+fn parse_ids(s: &str) -> Result<Vec<String>, String> {
+    let ids = s.split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<u64>().map(|id| id.to_string()).map_err(|_| format!("Invalid id: {}", v)))
+        .collect::<Result<Vec<String>, String>>()?;
+    if ids.is_empty() || ids.len() > MAX_IDS {
+        return Err(format!("Specify 1 to {} ids", MAX_IDS));
+    }
+    Ok(ids)
+}
+
+// 複数件のBeatmapset情報をまとめる場合(PER_PAGE件ずつのページ)
+async fn simple_beatmap_pages(ctx: &Context, beatmapsets: &[Beatmap]) -> Paginator {
+    let (color, title_str) = match beatmapsets.first() {
+        Some(b) => status_label(&b.status),
+        None => status_label(""),
+    };
+
+    let lines = web_handler::simple_beatmap_lines(ctx, beatmapsets).await;
+
+    let mut e = CreateEmbed::default();
+    e.color(color);
+    Paginator::from_lines(&e, &format!("{} Beatmapsets ({})", title_str, beatmapsets.len()), &lines, PER_PAGE)
+}
+
+// ranking予定時刻(qualifiedになってから7日後)の順に表示する
+pub async fn qualified_queue(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let options = &command.data.options;
+    // 省略時は全てのmode
+    let mode = match option_str(options, "mode") {
+        None => None,
+        Some(m) => match parse_mode(Some(m)) {
+            Ok(m) => Some(m),
+            Err(e) => {
+                reply(ctx, command, e, true).await?;
+                return Ok(());
+            }
+        },
+    };
+    let key = match mode {
+        Some(m) if !m.has_keys() => None,
+        _ => option_i64(options, "key"),
+    };
+    // キー数だけ指定されたらmania
+    let mode = if key.is_some() { Some(mode.unwrap_or(GameMode::Mania)) } else { mode };
+
+    // qualifiedの譜面が多いと時間がかかる
+    command.defer(&ctx.http).await?;
+
+    let db = DBHandler::new(ctx).await;
+    let maps = match db.qualified_queue(mode, key).await {
+        Ok(m) => m,
+        Err(e) => {
+            followup(ctx, command, "Failed to get qualified maps...").await?;
+            error!("Failed to get qualified queue: {}", e);
+            return Ok(());
+        }
+    };
+
+    let mut lines = Vec::new();
+    for (i, map) in maps.iter().enumerate() {
+        let url = web_api::get_url(ctx, map).await;
+        let when = match map.ranked_date {
+            Some(t) => format!("<t:{}:R>", projected_rank_time(t)),
+            None => "unknown".to_string(),
+        };
+        lines.push(format!("`{:>2}.` [{}]({}) {} {}", i + 1, map.title, url, web_handler::simple_starstr(&map.difficulties), when));
+    }
+    let target = match (mode, key) {
+        (Some(m), k) => m.group_label(k),
+        (None, _) => "all modes".to_string(),
+    };
+
+    if lines.is_empty() {
+        followup(ctx, command, format!("No qualified mapsets ({})", target)).await?;
+        return Ok(());
+    }
+
+    let mut embed = CreateEmbed::default();
+    embed.color(0xffff00)
+        .footer(|f| f.text("Projected rank time = qualified + 7 days (it can be later when the queue is busy)"));
+    let title = format!("Qualified queue ({}, {} mapsets)", target, maps.len());
+    Paginator::from_lines(&embed, &title, &lines, PER_PAGE).send(ctx, command, true).await
+}
@@ -0,0 +1,98 @@
+use std::borrow::Cow;
+
+use serenity::{
+    builder::{CreateApplicationCommands, CreateEmbed},
+    model::{
+        channel::AttachmentType,
+        application::{command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction},
+    },
+    prelude::*,
+};
+
+use crate::db::handler::DBHandler;
+use crate::downloader;
+use crate::osu::graph::{self, Density};
+use crate::web::{api, handler::{format_length, GRAPH_FILENAME}};
+use super::{CommandResult, followup, reply, option_i64};
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("graph")
+                .description("ダウンロード済みの譜面の1秒ごとのnote数(NPS)のグラフを表示します")
+                .create_option(|o| {
+                    o.name("id")
+                        .description("beatmap id (難易度のid)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+        })
+}
+
+pub async fn graph(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let id = match option_i64(&command.data.options, "id") {
+        Some(id) => id,
+        None => {
+            reply(ctx, command, "Invalid id", true).await?;
+            return Ok(());
+        }
+    };
+    command.defer(&ctx.http).await?;
+
+    // 難易度のidからbeatmapsetを探す(DBにある譜面のみ)
+    let db = DBHandler::new(ctx).await;
+    let beatmapset = match db.beatmapset_id_of(id).await? {
+        Some(set) => db.get_beatmapset(set).await?,
+        None => None,
+    };
+    let (beatmapset, difficulty) = match beatmapset.and_then(|s| s.difficulties.iter().find(|d| d.id == id).cloned().map(|d| (s, d))) {
+        Some(m) => m,
+        None => {
+            followup(ctx, command, format!("Beatmap {} is not in the database", id)).await?;
+            return Ok(());
+        }
+    };
+
+    let (map, png) = match graph::load(&db, &difficulty).await {
+        Ok(Some(g)) => g,
+        // 描くには.oszが必要
+        Ok(None) => {
+            downloader::enqueue(ctx, std::slice::from_ref(&beatmapset)).await?;
+            followup(ctx, command, format!("Mapset {} is not downloaded yet. It has been queued, try again later (see /downloads)", beatmapset.id)).await?;
+            return Ok(());
+        },
+        Err(e) => {
+            followup(ctx, command, "Failed to render the graph...").await?;
+            error!("Failed to render graph of {}: {}", id, e);
+            return Ok(());
+        }
+    };
+    let peak = Density::new(&map).map(|d| d.peak()).unwrap_or(0);
+    let (notes, long) = map.count_notes();
+    let bpm = match map.bpm_range() {
+        Some((min, max, main)) if (max - min).abs() >= 0.5 => format!("{:.0}-{:.0} ({:.0})", min, max, main),
+        Some((_, _, main)) => format!("{:.0}", main),
+        None => "-".to_string(),
+    };
+    let long_label = if difficulty.mode.has_keys() { "LNs" } else { "Sliders" };
+
+    let url = api::get_url(ctx, &beatmapset).await;
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} [{}]", beatmapset.id, beatmapset.title, difficulty.version))
+        .color(0xff69b4)
+        .thumbnail(&beatmapset.card_url)
+        .url(format!("{}#{}/{}", url, difficulty.mode, difficulty.id))
+        .field("Notes", notes.to_string(), true)
+        .field(long_label, long.to_string(), true)
+        .field("Peak", format!("{} NPS", peak), true)
+        .field("BPM", bpm, true)
+        .field("Drain", format_length((map.drain_time() / 1000.0).round() as i64), true)
+        .attachment(GRAPH_FILENAME)
+        .footer(|f| f.text(format!("blue: notes, orange: {}, dark: breaks, grid: 5 NPS / 30s", long_label.to_lowercase())));
+    command.create_followup_message(&ctx.http, |f| {
+        f.add_embed(e)
+            .add_file(AttachmentType::Bytes { data: Cow::from(png), filename: GRAPH_FILENAME.to_string() })
+    }).await?;
+    Ok(())
+}
@@ -1,3 +1,255 @@
 pub mod dbg;
-pub mod help;
-pub mod game;
\ No newline at end of file
+pub mod downloads;
+pub mod game;
+pub mod graph;
+pub mod paginator;
+pub mod pp;
+pub mod search;
+pub mod subscription;
+
+use std::error::Error;
+
+use serde_json::Value;
+use serenity::{
+    builder::{CreateApplicationCommands, CreateApplicationCommandOption, CreateEmbed},
+    model::{
+        application::{
+            command::CommandOptionType,
+            interaction::{
+                application_command::{ApplicationCommandInteraction, CommandDataOption},
+                autocomplete::AutocompleteInteraction,
+                InteractionResponseType,
+            },
+        },
+        prelude::*,
+    },
+    prelude::*,
+};
+
+use crate::cache::CommandCounter;
+use crate::db::subscription::STATUSES;
+use crate::owner;
+use crate::web::mode::GameMode;
+
+pub type CommandResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+// statusとkeyの候補(autocomplete用)
+pub const STATUS_CHOICES: [&str; 4] = ["ranked", "loved", "qualified", "all"];
+pub const KEY_CHOICES: [i64; 9] = [4, 7, 1, 2, 3, 5, 6, 8, 9];
+// key省略時に対象とするキー数
+pub const DEFAULT_KEYS: [i64; 2] = [4, 7];
+
+// 全てのslash commandの定義
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    dbg::register(commands);
+    downloads::register(commands);
+    game::register(commands);
+    graph::register(commands);
+    pp::register(commands);
+    search::register(commands);
+    subscription::register(commands);
+    commands
+}
+
+// コマンド名から各handlerに振り分ける
+pub async fn dispatch(ctx: &Context, command: &ApplicationCommandInteraction) {
+    let name = command.data.name.as_str();
+    count(ctx, name).await;
+    info!("{} used /{}", command.user.name, name);
+
+    let res = match name {
+        "shutdown" => dbg::shutdown(ctx, command).await,
+        "delmsg" => dbg::delmsg(ctx, command).await,
+        "todo" => dbg::todo(ctx, command).await,
+        "infoc" => dbg::infoc(ctx, command).await,
+        "reload_config" => dbg::reload_config(ctx, command).await,
+        "init_database" => game::init_database(ctx, command).await,
+        "update_database" => game::update_database(ctx, command).await,
+        "newmaps" => game::newmaps(ctx, command).await,
+        "dlmaps" => game::dlmaps(ctx, command).await,
+        "mapset_info" => game::mapset_info(ctx, command).await,
+        "dbsize" => game::dbsize(ctx, command).await,
+        "dbtop" => game::dbtop(ctx, command).await,
+        "qualified_queue" => game::qualified_queue(ctx, command).await,
+        "downloads" => downloads::downloads(ctx, command).await,
+        "getmap" => downloads::getmap(ctx, command).await,
+        "graph" => graph::graph(ctx, command).await,
+        "pp" => pp::pp(ctx, command).await,
+        "search" => search::search(ctx, command).await,
+        "subscribe" => subscription::subscribe(ctx, command).await,
+        "unsubscribe" => subscription::unsubscribe(ctx, command).await,
+        "subscriptions" => subscription::subscriptions(ctx, command).await,
+        _ => reply(ctx, command, format!("Unknown command: '{}'", name), true).await,
+    };
+
+    if let Err(e) = res {
+        error!("Failed to run /{}: {}", name, e);
+        // まだ返信していなければreply，defer済みならfollowupで知らせる
+        let content = "[ERROR] Something went wrong... Please inform the owner!";
+        if reply(ctx, command, content, true).await.is_err() {
+            let _ = followup(ctx, command, content).await;
+        }
+    }
+}
+
+// 入力中のstatus/keyの候補を返す
+pub async fn autocomplete(ctx: &Context, interaction: &AutocompleteInteraction) {
+    let focused = match interaction.data.options.iter().find(|o| o.focused) {
+        Some(o) => o,
+        None => return,
+    };
+    let input = match &focused.value {
+        Some(Value::String(s)) => s.to_lowercase(),
+        Some(v) => v.to_string(),
+        None => String::new(),
+    };
+
+    let res = interaction.create_autocomplete_response(&ctx.http, |r| {
+        match focused.name.as_str() {
+            "status" => {
+                for s in STATUS_CHOICES.iter().filter(|s| s.starts_with(&input)) {
+                    r.add_string_choice(s, s);
+                }
+            },
+            "key" => {
+                for k in KEY_CHOICES.iter().filter(|k| k.to_string().starts_with(&input)) {
+                    r.add_int_choice(format!("{}k", k), *k);
+                }
+            },
+            _ => {},
+        }
+        r
+    }).await;
+    if let Err(e) = res {
+        warn!("Failed to send autocomplete response: {}", e);
+    }
+}
+
+// Command Counter
+async fn count(ctx: &Context, name: &str) {
+    let mut data = ctx.data.write().await;
+    match data.get_mut::<CommandCounter>() {
+        Some(counter) => *counter.entry(name.to_string()).or_insert(0) += 1,
+        None => error!("Expected CommandCounter in TypeMap."),
+    }
+}
+
+// すぐに返信する(ephemeralなら実行者にだけ見える)
+pub async fn reply(ctx: &Context, command: &ApplicationCommandInteraction, content: impl ToString, ephemeral: bool) -> CommandResult {
+    command.create_interaction_response(&ctx.http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(ephemeral))
+    }).await?;
+    Ok(())
+}
+
+pub async fn reply_embed(ctx: &Context, command: &ApplicationCommandInteraction, embed: CreateEmbed) -> CommandResult {
+    command.create_interaction_response(&ctx.http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.add_embed(embed))
+    }).await?;
+    Ok(())
+}
+
+// 3秒以内に返信できないコマンドは先にdeferして，followupで結果を返す
+pub async fn followup(ctx: &Context, command: &ApplicationCommandInteraction, content: impl ToString) -> CommandResult {
+    command.create_followup_message(&ctx.http, |f| f.content(content)).await?;
+    Ok(())
+}
+
+pub async fn followup_embed(ctx: &Context, command: &ApplicationCommandInteraction, embed: CreateEmbed) -> CommandResult {
+    command.create_followup_message(&ctx.http, |f| f.add_embed(embed)).await?;
+    Ok(())
+}
+
+// オーナー以外なら断ってfalseを返す
+pub async fn check_owner(ctx: &Context, command: &ApplicationCommandInteraction) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    if owner::is_owner(ctx, command.user.id).await {
+        return Ok(true);
+    }
+    info!("{} tried to use /{}", command.user.name, command.data.name);
+    reply(ctx, command, "You are not the owner", true).await?;
+    Ok(false)
+}
+
+// optionの値を取り出す
+pub fn option<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a Value> {
+    options.iter().find(|o| o.name == name).and_then(|o| o.value.as_ref())
+}
+
+pub fn option_str<'a>(options: &'a [CommandDataOption], name: &str) -> Option<&'a str> {
+    option(options, name).and_then(|v| v.as_str())
+}
+
+pub fn option_i64(options: &[CommandDataOption], name: &str) -> Option<i64> {
+    option(options, name).and_then(|v| v.as_i64())
+}
+
+pub fn option_f64(options: &[CommandDataOption], name: &str) -> Option<f64> {
+    option(options, name).and_then(|v| v.as_f64())
+}
+
+pub fn option_bool(options: &[CommandDataOption], name: &str) -> Option<bool> {
+    option(options, name).and_then(|v| v.as_bool())
+}
+
+// channel optionの値はidの文字列
+pub fn option_channel(options: &[CommandDataOption], name: &str) -> Option<ChannelId> {
+    option_str(options, name).and_then(|v| v.parse::<u64>().ok()).map(ChannelId)
+}
+
+// "all"なら全てのstatus，省略時はdefault
+pub fn parse_status(value: Option<&str>, default: &[&'static str]) -> Result<Vec<&'static str>, String> {
+    match value.map(|s| s.trim().to_lowercase()) {
+        None => Ok(default.to_vec()),
+        Some(s) if s == "all" => Ok(STATUSES.to_vec()),
+        Some(s) => match STATUSES.iter().find(|c| **c == s) {
+            Some(c) => Ok(vec![*c]),
+            None => Err(format!("Invalid status: {} (ranked, loved, qualified, all)", s)),
+        },
+    }
+}
+
+// キー数はmaniaのみ(それ以外はNone = 絞り込まない)
+pub fn key_list(mode: GameMode, value: Option<i64>) -> Vec<Option<i64>> {
+    match (mode.has_keys(), value) {
+        (false, _) => vec![None],
+        (true, Some(k)) => vec![Some(k)],
+        (true, None) => DEFAULT_KEYS.iter().map(|k| Some(*k)).collect(),
+    }
+}
+
+// 省略時はmania
+pub fn parse_mode(value: Option<&str>) -> Result<GameMode, String> {
+    match value {
+        None => Ok(GameMode::Mania),
+        Some(v) => GameMode::from_name(v).ok_or_else(|| format!("Invalid mode: {} (osu, taiko, catch, mania)", v)),
+    }
+}
+
+// 共通のoption定義
+pub fn mode_option<'a>(o: &'a mut CreateApplicationCommandOption, description: &str) -> &'a mut CreateApplicationCommandOption {
+    o.name("mode")
+        .description(description)
+        .kind(CommandOptionType::String);
+    for mode in GameMode::ALL {
+        o.add_string_choice(mode.label(), mode.as_str());
+    }
+    o
+}
+
+pub fn status_option<'a>(o: &'a mut CreateApplicationCommandOption, description: &str) -> &'a mut CreateApplicationCommandOption {
+    o.name("status")
+        .description(description)
+        .kind(CommandOptionType::String)
+        .set_autocomplete(true)
+}
+
+pub fn key_option<'a>(o: &'a mut CreateApplicationCommandOption, description: &str) -> &'a mut CreateApplicationCommandOption {
+    o.name("key")
+        .description(format!("{} (maniaのみ)", description))
+        .kind(CommandOptionType::Integer)
+        .min_int_value(1)
+        .max_int_value(18)
+        .set_autocomplete(true)
+}
@@ -0,0 +1,272 @@
+use std::{error::Error, time::Duration};
+
+use futures::future::BoxFuture;
+use serenity::{
+    builder::{CreateComponents, CreateEmbed},
+    model::application::{
+        component::{ActionRowComponent, ButtonStyle, InputTextStyle},
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            message_component::MessageComponentInteraction,
+            modal::ModalSubmitInteraction,
+            InteractionResponseType,
+        },
+    },
+    prelude::*,
+};
+
+use super::CommandResult;
+
+// 1ページの件数
+pub const PER_PAGE: usize = 10;
+// 最後の操作からボタンを消すまでの時間
+pub const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+// embedのdescriptionの上限(4096)に余裕を持たせる
+const MAX_DESCRIPTION: usize = 4000;
+
+const PREV_ID: &str = "paginator_prev";
+const NEXT_ID: &str = "paginator_next";
+const JUMP_ID: &str = "paginator_jump";
+const JUMP_INPUT_ID: &str = "paginator_page";
+
+// 0始まりのページ番号からそのページを作る(DBから1ページずつ読むときなど)
+pub type PageLoader = Box<dyn Fn(usize) -> BoxFuture<'static, Result<CreateEmbed, Box<dyn Error + Send + Sync>>> + Send + Sync>;
+
+// 複数ページのembedをPrevious/Next/Jumpボタンで切り替える
+// ボタンを押せるのはコマンドの実行者だけ
+pub struct Paginator {
+    pages: Vec<Option<CreateEmbed>>, // Noneはまだ作っていないページ(loaderで作る)
+    loader: Option<PageLoader>,
+    current: usize,
+}
+
+impl Paginator {
+    pub fn new(pages: Vec<CreateEmbed>) -> Self {
+        Paginator { pages: pages.into_iter().map(Some).collect(), loader: None, current: 0 }
+    }
+
+    // total件のページを開いたときに作る(作ったページは取っておく)
+    pub fn lazy(total: usize, loader: PageLoader) -> Self {
+        Paginator { pages: vec![None; total], loader: Some(loader), current: 0 }
+    }
+
+    // 1行1件の一覧をper_page行ずつ(descriptionの上限を超えるならそれより少なく)ページに分ける
+    // タイトルには"[1/3]"のようにページ番号を付ける
+    pub fn from_lines(template: &CreateEmbed, title: &str, lines: &[String], per_page: usize) -> Self {
+        let mut chunks: Vec<String> = Vec::new();
+        let mut chunk = String::new();
+        let mut count = 0;
+        for line in lines {
+            if count > 0 && (count >= per_page || chunk.len() + line.len() + 1 > MAX_DESCRIPTION) {
+                chunks.push(std::mem::take(&mut chunk));
+                count = 0;
+            }
+            chunk.push_str(line);
+            chunk.push('\n');
+            count += 1;
+        }
+        if !chunk.is_empty() || chunks.is_empty() {
+            chunks.push(chunk);
+        }
+
+        let total = chunks.len();
+        let pages = chunks.into_iter().enumerate().map(|(i, description)| {
+            let mut e = template.clone();
+            e.title(format!("{} [{}/{}]", title, i + 1, total))
+                .description(description);
+            e
+        }).collect();
+        Paginator::new(pages)
+    }
+
+    // 他のPaginatorのページとまとめるとき(作っていないページは含まない)
+    pub fn into_pages(self) -> Vec<CreateEmbed> {
+        self.pages.into_iter().flatten().collect()
+    }
+
+    // 最初に表示するページ(0始まり，範囲外なら最後のページ)
+    pub fn start_at(mut self, page: usize) -> Self {
+        self.current = page.min(self.pages.len().saturating_sub(1));
+        self
+    }
+
+    // i番目のページ(まだ作っていなければloaderで作る)
+    async fn page(&mut self, i: usize) -> Result<CreateEmbed, Box<dyn Error + Send + Sync>> {
+        if let Some(Some(e)) = self.pages.get(i) {
+            return Ok(e.clone());
+        }
+        let e = match &self.loader {
+            Some(load) if i < self.pages.len() => load(i).await?,
+            _ => return Ok(CreateEmbed::default()),
+        };
+        self.pages[i] = Some(e.clone());
+        Ok(e)
+    }
+
+    fn components(&self) -> CreateComponents {
+        let mut c = CreateComponents::default();
+        c.create_action_row(|r| {
+            r.create_button(|b| {
+                b.style(ButtonStyle::Secondary)
+                    .label("Previous")
+                    .custom_id(PREV_ID)
+                    .disabled(self.current == 0)
+            })
+            .create_button(|b| {
+                b.style(ButtonStyle::Secondary)
+                    .label("Next")
+                    .custom_id(NEXT_ID)
+                    .disabled(self.current + 1 >= self.pages.len())
+            })
+            .create_button(|b| {
+                b.style(ButtonStyle::Primary)
+                    .label(format!("Jump ({}/{})", self.current + 1, self.pages.len()))
+                    .custom_id(JUMP_ID)
+            })
+        });
+        c
+    }
+
+    // コマンドへの返信として送り，タイムアウトするまでボタンの操作を受け付ける
+    // deferred: defer済みならfollowupで送る
+    pub async fn send(mut self, ctx: &Context, command: &ApplicationCommandInteraction, deferred: bool) -> CommandResult {
+        if self.pages.is_empty() {
+            return Ok(());
+        }
+        // 1ページならボタンは付けない
+        let paged = self.pages.len() > 1;
+        let embed = self.page(self.current).await?;
+
+        let mut message = if deferred {
+            command.create_followup_message(&ctx.http, |f| {
+                f.add_embed(embed);
+                if paged {
+                    f.set_components(self.components());
+                }
+                f
+            }).await?
+        } else {
+            command.create_interaction_response(&ctx.http, |r| {
+                r.kind(InteractionResponseType::ChannelMessageWithSource)
+                    .interaction_response_data(|d| {
+                        d.add_embed(embed);
+                        if paged {
+                            d.set_components(self.components());
+                        }
+                        d
+                    })
+            }).await?;
+            command.get_interaction_response(&ctx.http).await?
+        };
+        if !paged {
+            return Ok(());
+        }
+
+        // Jumpのmodalの入力はボタンと一緒に待つ(modalを開いたままでもボタンを押せる)
+        loop {
+            let component = message.await_component_interaction(ctx).timeout(IDLE_TIMEOUT);
+            let modal = message.await_modal_interaction(ctx).author_id(command.user.id).timeout(IDLE_TIMEOUT);
+            let res = tokio::select! {
+                Some(interaction) = component => self.handle(ctx, command, &interaction).await,
+                Some(submit) = modal => self.submit_jump(ctx, &submit).await,
+                else => break,
+            };
+            // 1回の操作の失敗でページ送りを止めない
+            if let Err(e) = res {
+                warn!("Failed to turn page: {}", e);
+            }
+        }
+
+        // タイムアウトしたらボタンを消す
+        if let Err(e) = message.edit(&ctx, |m| m.set_components(CreateComponents::default())).await {
+            warn!("Failed to remove paginator buttons: {}", e);
+        }
+        Ok(())
+    }
+
+    async fn handle(&mut self, ctx: &Context, command: &ApplicationCommandInteraction, interaction: &MessageComponentInteraction) -> CommandResult {
+        if interaction.user.id != command.user.id {
+            return respond_ephemeral(ctx, interaction, "Only the user who ran the command can turn pages").await;
+        }
+        let next = match interaction.data.custom_id.as_str() {
+            PREV_ID => self.current.saturating_sub(1),
+            NEXT_ID => (self.current + 1).min(self.pages.len() - 1),
+            JUMP_ID => return self.jump(ctx, interaction).await,
+            _ => return Ok(()),
+        };
+        // 作れなかったらページを変えない
+        let embed = self.page(next).await?;
+        self.current = next;
+        interaction.create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::UpdateMessage)
+                .interaction_response_data(|d| d.set_embed(embed).set_components(self.components()))
+        }).await?;
+        Ok(())
+    }
+
+    // ページ番号を入力するmodalを出す(入力はsendのループでsubmit_jumpに渡す)
+    async fn jump(&self, ctx: &Context, interaction: &MessageComponentInteraction) -> CommandResult {
+        let total = self.pages.len();
+        interaction.create_interaction_response(&ctx.http, |r| {
+            r.kind(InteractionResponseType::Modal)
+                .interaction_response_data(|d| {
+                    d.custom_id(JUMP_ID)
+                        .title("Jump to page")
+                        .components(|c| {
+                            c.create_action_row(|r| {
+                                r.create_input_text(|t| {
+                                    t.custom_id(JUMP_INPUT_ID)
+                                        .label(format!("Page (1-{})", total))
+                                        .style(InputTextStyle::Short)
+                                        .min_length(1)
+                                        .max_length(6)
+                                        .required(true)
+                                })
+                            })
+                        })
+                })
+        }).await?;
+        Ok(())
+    }
+
+    // modalで送られたページに移動する
+    async fn submit_jump(&mut self, ctx: &Context, submit: &ModalSubmitInteraction) -> CommandResult {
+        let total = self.pages.len();
+        let input = submit.data.components.iter()
+            .flat_map(|row| row.components.iter())
+            .find_map(|c| match c {
+                ActionRowComponent::InputText(t) if t.custom_id == JUMP_INPUT_ID => Some(t.value.trim().to_string()),
+                _ => None,
+            })
+            .unwrap_or_default();
+
+        match input.parse::<usize>() {
+            Ok(p) if p >= 1 && p <= total => {
+                let embed = self.page(p - 1).await?;
+                self.current = p - 1;
+                submit.create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::UpdateMessage)
+                        .interaction_response_data(|d| d.set_embed(embed).set_components(self.components()))
+                }).await?;
+            },
+            _ => {
+                submit.create_interaction_response(&ctx.http, |r| {
+                    r.kind(InteractionResponseType::ChannelMessageWithSource)
+                        .interaction_response_data(|d| {
+                            d.content(format!("Invalid page: {} (1-{})", input, total)).ephemeral(true)
+                        })
+                }).await?;
+            },
+        }
+        Ok(())
+    }
+}
+
+// ボタンなどを押した人にだけ見える返信
+pub async fn respond_ephemeral(ctx: &Context, interaction: &MessageComponentInteraction, content: &str) -> CommandResult {
+    interaction.create_interaction_response(&ctx.http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true))
+    }).await?;
+    Ok(())
+}
@@ -0,0 +1,154 @@
+use serenity::{
+    builder::{CreateApplicationCommands, CreateEmbed},
+    model::application::{command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction},
+    prelude::*,
+};
+
+use crate::db::handler::DBHandler;
+use crate::downloader;
+use crate::pp::{self, Attributes, Mods, ACCURACIES};
+use crate::web::{api::{self as web_api, Difficulty}, mode::GameMode};
+use super::{CommandResult, followup, followup_embed, reply, option_f64, option_i64, option_str};
+
+// 表の難易度名の最大文字数
+const NAME_LEN: usize = 16;
+// descriptionの上限(4096)に余裕を持たせる
+const MAX_TABLE_LEN: usize = 4000;
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("pp")
+                .description("ダウンロード済みのmania譜面のSRとpp(SS, 99%, 97%, 95%)を同じbeatmapsetの難易度ごとに計算します")
+                .create_option(|o| {
+                    o.name("id")
+                        .description("beatmap id (難易度のid)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .required(true)
+                })
+                .create_option(|o| {
+                    o.name("acc")
+                        .description("この精度(%)のppも表示します e.g. 98.5")
+                        .kind(CommandOptionType::Number)
+                        .min_number_value(0.0)
+                        .max_number_value(100.0)
+                })
+                .create_option(|o| {
+                    o.name("mods")
+                        .description("e.g. DT, HTNF, EZ")
+                        .kind(CommandOptionType::String)
+                })
+        })
+}
+
+pub async fn pp(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let options = &command.data.options;
+    let id = match option_i64(options, "id") {
+        Some(id) => id,
+        None => {
+            reply(ctx, command, "Invalid id", true).await?;
+            return Ok(());
+        }
+    };
+    let acc = option_f64(options, "acc");
+    let mods = match Mods::parse(option_str(options, "mods").unwrap_or_default()) {
+        Ok(m) => m,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
+    };
+
+    command.defer(&ctx.http).await?;
+
+    // 難易度のidからbeatmapsetを探す(DBにある譜面のみ)
+    let db = DBHandler::new(ctx).await;
+    let beatmapset = match db.beatmapset_id_of(id).await? {
+        Some(set) => db.get_beatmapset(set).await?,
+        None => None,
+    };
+    let (map, difficulty) = match beatmapset.and_then(|s| s.difficulties.iter().find(|d| d.id == id).cloned().map(|d| (s, d))) {
+        Some(m) => m,
+        None => {
+            followup(ctx, command, format!("Beatmap {} is not in the database", id)).await?;
+            return Ok(());
+        }
+    };
+    if difficulty.mode != GameMode::Mania {
+        followup(ctx, command, format!("Beatmap {} is not a mania difficulty", id)).await?;
+        return Ok(());
+    }
+
+    let attributes = match pp::mapset_attributes(&db, map.id, mods).await {
+        Ok(Some(a)) => a,
+        // 計算には.oszが必要
+        Ok(None) => {
+            downloader::enqueue(ctx, std::slice::from_ref(&map)).await?;
+            followup(ctx, command, format!("Mapset {} is not downloaded yet. It has been queued, try again later (see /downloads)", map.id)).await?;
+            return Ok(());
+        },
+        Err(e) => {
+            followup(ctx, command, "Failed to calculate pp...").await?;
+            error!("Failed to calculate pp of {}: {}", map.id, e);
+            return Ok(());
+        }
+    };
+    if attributes.is_empty() {
+        followup(ctx, command, format!("Mapset {} has no mania difficulties", map.id)).await?;
+        return Ok(());
+    }
+
+    let url = web_api::get_url(ctx, &map).await;
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} [{}] ({})", map.id, map.title, difficulty.version, mods))
+        .color(0xff69b4)
+        .thumbnail(&map.card_url)
+        .url(format!("{}#{}/{}", url, difficulty.mode, difficulty.id))
+        .description(pp_table(&attributes, &difficulty, acc.map(|a| a / 100.0), &mods))
+        .footer(|f| f.text("Calculated locally from the .osz, may differ slightly from osu!"));
+    followup_embed(ctx, command, e).await?;
+    Ok(())
+}
+
+// "> Insane  4k 4.52  312  290  251  215"
+// 指定された難易度の行には">"を付ける
+fn pp_table(attributes: &[Attributes], selected: &Difficulty, acc: Option<f64>, mods: &Mods) -> String {
+    let mut accs = vec![1.0];
+    accs.extend(ACCURACIES.iter().rev());
+    if let Some(a) = acc {
+        accs.push(a);
+    }
+    let label = |a: f64| if a >= 1.0 { "SS".to_string() } else { format!("{}%", (a * 1000.0).round() / 10.0) };
+
+    let mut table = String::from("```\n");
+    table.push_str(&format!("  {:<16} {:>3} {:>5}", "Name", "Key", "SR"));
+    for a in &accs {
+        table.push_str(&format!(" {:>5}", label(*a)));
+    }
+    table.push('\n');
+    for (i, d) in attributes.iter().enumerate() {
+        let name = if d.version.chars().count() > NAME_LEN {
+            format!("{}~", d.version.chars().take(NAME_LEN - 1).collect::<String>())
+        } else {
+            d.version.clone()
+        };
+        // 古い.osuにはidが無いので難易度名で比べる
+        let marker = match d.beatmap_id {
+            Some(b) => b == selected.id,
+            None => d.version == selected.version,
+        };
+        let mut line = format!("{} {:<16} {:>3} {:>5.2}", if marker { ">" } else { " " }, name, format!("{}k", d.keys), d.stars);
+        for a in &accs {
+            line.push_str(&format!(" {:>5.0}", d.pp(*a, mods)));
+        }
+        line.push('\n');
+        if table.len() + line.len() > MAX_TABLE_LEN {
+            table.push_str(&format!("...and {} more\n", attributes.len() - i));
+            break;
+        }
+        table.push_str(&line);
+    }
+    table.push_str("```");
+    table
+}
@@ -0,0 +1,85 @@
+use serenity::{
+    builder::{CreateApplicationCommands, CreateEmbed},
+    model::application::{command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction},
+    prelude::*,
+};
+
+use crate::db::{handler::DBHandler, search::SearchQuery};
+use crate::web::handler as web_handler;
+use super::paginator::{PageLoader, Paginator, PER_PAGE};
+use super::{CommandResult, followup, reply, option_i64, option_str};
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("search")
+                .description("DBの譜面を検索します (title, artist, creator, tags, 難易度名)")
+                .create_option(|o| {
+                    o.name("query")
+                        .description("e.g. camellia status:ranked keys:7 stars>5.2 creator:xxx mode:taiko")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_option(|o| {
+                    o.name("page")
+                        .description("最初に表示するページ (default: 1)")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                })
+        })
+}
+
+pub async fn search(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let options = &command.data.options;
+    let input = option_str(options, "query").unwrap_or_default();
+    let page = option_i64(options, "page").unwrap_or(1).max(1) as usize;
+    let query = match SearchQuery::parse(input) {
+        Ok(q) => q,
+        Err(e) => {
+            reply(ctx, command, e, true).await?;
+            return Ok(());
+        }
+    };
+
+    // 検索に時間がかかることがある
+    command.defer(&ctx.http).await?;
+
+    let db = DBHandler::new(ctx).await;
+    let total = match db.search_count(&query).await {
+        Ok(t) => t,
+        Err(e) => {
+            followup(ctx, command, "Failed to search...").await?;
+            error!("Failed to search '{}': {}", input, e);
+            return Ok(());
+        }
+    };
+    if total == 0 {
+        followup(ctx, command, format!("No mapsets found for `{}`", input)).await?;
+        return Ok(());
+    }
+    let pages = total.div_ceil(PER_PAGE);
+    if page > pages {
+        followup(ctx, command, format!("Page {} is out of range (1-{})", page, pages)).await?;
+        return Ok(());
+    }
+
+    // 開いたページの譜面だけをDBから読む
+    let title = format!("Search: {} ({} mapsets)", input, total);
+    let ctx_ = ctx.clone();
+    let loader: PageLoader = Box::new(move |i| {
+        let (ctx, query, title) = (ctx_.clone(), query.clone(), title.clone());
+        Box::pin(async move {
+            let db = DBHandler::new(&ctx).await;
+            let maps = db.search(&query, PER_PAGE, i * PER_PAGE).await?;
+            let mut embed = CreateEmbed::default();
+            embed.color(0x00ffff)
+                .title(format!("{} [{}/{}]", title, i + 1, pages))
+                .description(web_handler::simple_beatmap_lines(&ctx, &maps).await.join("\n"));
+            Ok(embed)
+        })
+    });
+    Paginator::lazy(pages, loader)
+        .start_at(page - 1)
+        .send(ctx, command, true)
+        .await
+}
@@ -0,0 +1,266 @@
+use serenity::{
+    builder::{CreateApplicationCommands, CreateEmbed},
+    model::{
+        application::{command::CommandOptionType, interaction::application_command::ApplicationCommandInteraction},
+        prelude::*,
+    },
+    prelude::*,
+};
+
+use crate::db::{
+    handler::DBHandler,
+    subscription::{Subscription, STATUSES, parse_digest},
+};
+use crate::osu::pattern::CATEGORIES;
+use crate::web::mode::GameMode;
+use super::{CommandResult, reply, reply_embed, option_channel, option_str};
+use super::paginator::{Paginator, PER_PAGE};
+
+pub fn register(commands: &mut CreateApplicationCommands) -> &mut CreateApplicationCommands {
+    commands
+        .create_application_command(|c| {
+            c.name("subscribe")
+                .description("このチャンネル(または指定したチャンネル)に新着譜面を通知します．既に設定がある場合は上書きします")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .dm_permission(false)
+                .create_option(|o| {
+                    o.name("channel")
+                        .description("通知先のチャンネル (default: このチャンネル)")
+                        .kind(CommandOptionType::Channel)
+                        .channel_types(&[ChannelType::Text, ChannelType::News])
+                })
+                .create_option(|o| {
+                    o.name("status")
+                        .description("通知するstatus (e.g. ranked,loved) (default: 全て)")
+                        .kind(CommandOptionType::String)
+                })
+                .create_option(|o| {
+                    o.name("modes")
+                        .description("通知するmode (e.g. mania,taiko, all) (default: mania)")
+                        .kind(CommandOptionType::String)
+                })
+                .create_option(|o| {
+                    o.name("keys")
+                        .description("maniaのキー数 (e.g. 4,7) (default: 全て)")
+                        .kind(CommandOptionType::String)
+                })
+                .create_option(|o| {
+                    o.name("stars")
+                        .description("星の範囲 (e.g. 3-6, 3-, -6) (default: 全て)")
+                        .kind(CommandOptionType::String)
+                })
+                .create_option(|o| {
+                    o.name("pattern")
+                        .description("maniaの配置で絞り込みます(ダウンロードして分析した後に通知します) (default: 絞り込まない)")
+                        .kind(CommandOptionType::String);
+                    for c in CATEGORIES {
+                        o.add_string_choice(c, c);
+                    }
+                    o
+                })
+                .create_option(|o| {
+                    o.name("digest")
+                        .description("まとめて送る間隔 (daily: 毎日0時, weekly: 毎週月曜0時, 12h, cron式, off) (UTC, default: off = すぐに通知)")
+                        .kind(CommandOptionType::String)
+                })
+        })
+        .create_application_command(|c| {
+            c.name("unsubscribe")
+                .description("このチャンネル(または指定したチャンネル)への通知をやめます")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .dm_permission(false)
+                .create_option(|o| {
+                    o.name("channel")
+                        .description("通知をやめるチャンネル (default: このチャンネル)")
+                        .kind(CommandOptionType::Channel)
+                        .channel_types(&[ChannelType::Text, ChannelType::News])
+                })
+        })
+        .create_application_command(|c| {
+            c.name("subscriptions")
+                .description("このサーバの通知設定を表示します")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .dm_permission(false)
+        })
+}
+
+pub async fn subscribe(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let guild_id = match command.guild_id {
+        Some(g) => g,
+        None => return Ok(()),
+    };
+
+    let options = &command.data.options;
+    let channel_id = option_channel(options, "channel").unwrap_or(command.channel_id);
+    let mut statuses: Vec<String> = STATUSES.iter().map(|s| s.to_string()).collect();
+    let mut modes = vec![GameMode::Mania];
+    let mut keys: Vec<i64> = Vec::new();
+    let (mut min_stars, mut max_stars) = (None, None);
+    if let Some(v) = option_str(options, "status") {
+        statuses = v.split(',').map(|s| s.trim().to_lowercase()).filter(|s| !s.is_empty()).collect();
+        if let Some(s) = statuses.iter().find(|s| !STATUSES.contains(&s.as_str())) {
+            reply(ctx, command, format!("Invalid status: {} (ranked, loved, qualified)", s), true).await?;
+            return Ok(());
+        }
+    }
+    if let Some(v) = option_str(options, "modes") {
+        modes = if v.trim() == "all" {
+            Vec::new()
+        } else {
+            match v.split(',').map(|m| GameMode::from_name(m).ok_or(m)).collect() {
+                Ok(m) => m,
+                Err(m) => {
+                    reply(ctx, command, format!("Invalid mode: {} (osu, taiko, catch, mania, all)", m), true).await?;
+                    return Ok(());
+                }
+            }
+        };
+    }
+    if let Some(v) = option_str(options, "keys") {
+        keys = match v.split(',').map(|k| k.trim().trim_end_matches('k').parse::<i64>()).collect() {
+            Ok(k) => k,
+            Err(_) => {
+                reply(ctx, command, format!("Invalid keys: {}", v), true).await?;
+                return Ok(());
+            }
+        };
+    }
+    if let Some(v) = option_str(options, "stars") {
+        (min_stars, max_stars) = match parse_star_range(v) {
+            Some(r) => r,
+            None => {
+                reply(ctx, command, format!("Invalid stars: {} (e.g. 3-6, 3-, -6)", v), true).await?;
+                return Ok(());
+            }
+        };
+    }
+    let pattern = option_str(options, "pattern").map(|p| p.to_lowercase());
+    if let Some(p) = pattern.as_deref().filter(|p| !CATEGORIES.contains(p)) {
+        reply(ctx, command, format!("Invalid pattern: {} ({})", p, CATEGORIES.join(", ")), true).await?;
+        return Ok(());
+    }
+    // 配置はmaniaの難易度にしかない
+    if pattern.is_some() && !modes.is_empty() && !modes.contains(&GameMode::Mania) {
+        reply(ctx, command, "pattern can only be used with mania", true).await?;
+        return Ok(());
+    }
+    let digest = match parse_digest(option_str(options, "digest").unwrap_or("off")) {
+        Ok(d) => d,
+        Err(e) => {
+            reply(ctx, command, format!("Invalid digest: {}", e), true).await?;
+            return Ok(());
+        }
+    };
+    if statuses.is_empty() {
+        reply(ctx, command, "At least one status is required", true).await?;
+        return Ok(());
+    }
+
+    // 他のサーバのチャンネルは指定できない
+    match channel_id.to_channel(&ctx.http).await {
+        Ok(Channel::Guild(c)) if c.guild_id == guild_id => {},
+        _ => {
+            reply(ctx, command, "The channel must be in this server", true).await?;
+            return Ok(());
+        }
+    }
+
+    let sub = Subscription {
+        id: 0,
+        guild_id: guild_id.0 as i64,
+        channel_id: channel_id.0 as i64,
+        statuses: statuses.join(","),
+        keys: keys.iter().map(|k| k.to_string()).collect::<Vec<String>>().join(","),
+        min_stars,
+        max_stars,
+        created_at: 0,
+        modes: modes.iter().map(|m| m.as_str()).collect::<Vec<&str>>().join(","),
+        digest,
+        last_digest: None,
+        pattern,
+    };
+    let db = DBHandler::new(ctx).await;
+    if let Err(e) = db.upsert_subscription(&sub).await {
+        reply(ctx, command, "[ERROR] Failed to save subscription... Please inform the owner!", true).await?;
+        error!("Failed to save subscription: {}", e);
+        return Ok(());
+    }
+
+    let description = sub.describe();
+    let mut embed = CreateEmbed::default();
+    embed.title("Subscribed")
+        .color(0x00ffff)
+        .description(format!("<#{}>: {}", channel_id.0, description));
+    reply_embed(ctx, command, embed).await
+}
+
+pub async fn unsubscribe(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let guild_id = match command.guild_id {
+        Some(g) => g,
+        None => return Ok(()),
+    };
+    let channel_id = option_channel(&command.data.options, "channel").unwrap_or(command.channel_id);
+
+    let db = DBHandler::new(ctx).await;
+    match db.delete_subscription(guild_id.0 as i64, channel_id.0 as i64).await {
+        Ok(true) => reply(ctx, command, format!("Unsubscribed <#{}>", channel_id.0), false).await?,
+        Ok(false) => reply(ctx, command, format!("<#{}> is not subscribed", channel_id.0), true).await?,
+        Err(e) => {
+            error!("Failed to delete subscription: {}", e);
+            reply(ctx, command, "[ERROR] Failed to delete subscription... Please inform the owner!", true).await?
+        }
+    };
+
+    Ok(())
+}
+
+pub async fn subscriptions(ctx: &Context, command: &ApplicationCommandInteraction) -> CommandResult {
+    let guild_id = match command.guild_id {
+        Some(g) => g,
+        None => return Ok(()),
+    };
+
+    let db = DBHandler::new(ctx).await;
+    let subs = match db.guild_subscriptions(guild_id.0 as i64).await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to get subscriptions: {}", e);
+            reply(ctx, command, "[ERROR] Failed to get subscriptions... Please inform the owner!", true).await?;
+            return Ok(());
+        }
+    };
+
+    if subs.is_empty() {
+        reply(ctx, command, "No subscriptions. Use `/subscribe` to add one.", true).await?;
+        return Ok(());
+    }
+    // 多いとdescriptionの上限を超えるのでページに分ける
+    let lines = subs.iter()
+        .map(|s| format!("<#{}>: {}", s.channel_id, s.describe()))
+        .collect::<Vec<String>>();
+    let mut embed = CreateEmbed::default();
+    embed.color(0x00ffff);
+    Paginator::from_lines(&embed, &format!("Subscriptions ({})", subs.len()), &lines, PER_PAGE)
+        .send(ctx, command, false).await
+}
+
+// "3-6" => (3, 6), "3-" => (3, None), "-6" => (None, 6), "3" => (3, None)
+fn parse_star_range(s: &str) -> Option<(Option<f64>, Option<f64>)> {
+    let parse = |v: &str| -> Option<Option<f64>> {
+        let v = v.trim();
+        if v.is_empty() {
+            Some(None)
+        } else {
+            // nanやinfは範囲にならない
+            v.parse::<f64>().ok().filter(|v| v.is_finite()).map(Some)
+        }
+    };
+    let (min, max) = match s.split_once('-') {
+        Some((min, max)) => (parse(min)?, parse(max)?),
+        None => (parse(s)?, None),
+    };
+    match (min, max) {
+        (Some(a), Some(b)) if a > b => None,
+        r => Some(r),
+    }
+}
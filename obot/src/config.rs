@@ -0,0 +1,556 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use serenity::{
+    builder::CreateEmbed,
+    http::Http,
+    model::id::{ChannelId, GuildId},
+    prelude::*,
+};
+use url::Url;
+
+use crate::cache::{OsuApi, Settings};
+use crate::scheduler::Schedule;
+use crate::web::api::Api;
+
+// 設定ファイルの場所(OBOT_CONFIGがなければconfig.toml，なくてもよい)
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+pub const DEFAULT_API_BASE: &str = "https://osu.ppy.sh";
+const DEFAULT_DOWNLOAD_BASE: &str = "https://api.chimu.moe/v1/download";
+// 毎日0:00と4:00(UTC)
+const DEFAULT_DAILY_DIGEST_SCHEDULE: &str = "0 0 * * *";
+const DEFAULT_DB_BACKUP_SCHEDULE: &str = "0 4 * * *";
+
+// botの設定
+// 環境変数とTOMLファイルから読み込む(同じ項目は環境変数が優先)
+// TOMLのkeyは環境変数名を小文字にしたもの(DISCORD_LOG_CHANNEL_ID => discord_log_channel_id)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub discord_token: String,
+    pub guild_id: Option<GuildId>, // slash commandをこのサーバにだけ登録する
+    pub log_channel: ChannelId,
+    pub api_base: Url,
+    pub download_base: Url,
+    pub user_id: u64, // osu! api client id
+    pub api_secret: String,
+    pub api_requests_per_minute: u32,
+    pub map_path: PathBuf,
+    pub download_workers: u32, // 同時にダウンロードする数
+    pub download_max_attempts: u32, // この回数失敗したら諦める(/downloadsから再開できる)
+    pub database_path: PathBuf,
+    // 定期実行するjobの間隔(間隔またはcron式)
+    pub poll_schedule: Schedule,
+    pub refresh_qualified_schedule: Schedule,
+    pub daily_digest_schedule: Schedule,
+    pub db_backup_schedule: Schedule,
+    pub qualified_reminder_schedule: Schedule,
+    pub qualified_reminder_before: Duration, // ranking予定時刻のどれだけ前に知らせるか
+    pub subscription_digest_schedule: Schedule, // チャンネルごとのdigestを送る時刻か確認する間隔
+    pub backup_dir: PathBuf,
+    pub backup_keep: u32, // 残すバックアップの数
+    // ダウンロードした譜面を配るHTTPサーバー(file_server_addrがなければ起動しない)
+    pub file_server_addr: Option<SocketAddr>,
+    pub file_server_url: Option<Url>, // リンクに使う外から見えるURL
+    pub file_link_secret: Option<String>, // リンクの署名用
+    pub file_link_ttl: Duration, // リンクの有効期限
+    pub download_quota: u32, // 1人が24時間にダウンロードできる譜面の数
+    pub getmap_guilds: Vec<GuildId>, // /getmapを使えるサーバー(空なら使えない)
+    pub show_pp: bool, // 譜面のembedにSSのpp(maniaのダウンロード済みの譜面のみ)を載せる
+    pub show_graph: bool, // 譜面のembedにNPSのグラフ(ダウンロード済みの譜面のみ)を載せる
+}
+
+// 設定の問題1つ分
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigProblem {
+    Missing { key: &'static str },
+    Invalid { key: &'static str, value: String, reason: String },
+    File { path: PathBuf, reason: String },
+}
+
+// 見つかった問題を全てまとめて返す
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<ConfigProblem>,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigProblem::Missing { key } => write!(f, "{} is not set", key),
+            ConfigProblem::Invalid { key, value, reason } => write!(f, "{} is invalid ({:?}): {}", key, value, reason),
+            ConfigProblem::File { path, reason } => write!(f, "failed to read {}: {}", path.display(), reason),
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration ({} problems):", self.problems.len())?;
+        for p in &self.problems {
+            writeln!(f, "  - {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for ConfigError {}
+
+// reloadで変わった項目1つ分(秘密の値はold/newを伏せる)
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    pub key: &'static str,
+    pub old: String,
+    pub new: String,
+    pub restart: bool, // 反映には再起動が必要
+}
+
+impl fmt::Display for ConfigChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "`{}`: {} → {}", self.key, self.old, self.new)?;
+        if self.restart {
+            write!(f, " (要再起動)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // 環境変数，.envの値(read_dotenv)とOBOT_CONFIG(またはconfig.toml)から読み込む
+    pub fn load(dotenv: &HashMap<String, String>) -> Result<Self, ConfigError> {
+        let (path, explicit) = match env::var("OBOT_CONFIG").ok().or_else(|| dotenv.get("OBOT_CONFIG").cloned()) {
+            Some(p) => (PathBuf::from(p), true),
+            None => (PathBuf::from(DEFAULT_CONFIG_PATH), false),
+        };
+        let mut source = Source::default();
+        if explicit || path.exists() {
+            source.read_file(&path);
+        }
+        source.read_values(dotenv);
+        source.read_env();
+        Self::from_source(source)
+    }
+
+    // TypeMapに入っている設定を取り出す
+    pub async fn shared(ctx: &Context) -> Result<Arc<Self>, Box<dyn Error + Send + Sync>> {
+        match ctx.data.read().await.get::<Settings>() {
+            Some(config) => Ok(config.clone()),
+            None => Err("Config is not found in TypeMap".into()),
+        }
+    }
+
+    fn from_source(mut s: Source) -> Result<Self, ConfigError> {
+        let discord_token = s.required("DISCORD_TOKEN", parse_string);
+        let guild_id = s.optional("DISCORD_GUILD_ID", parse_id).map(GuildId);
+        let log_channel = s.required("DISCORD_LOG_CHANNEL_ID", parse_id).map(ChannelId);
+        let api_base = s.optional("API_BASE", parse_url);
+        let download_base = s.optional("DOWNLOAD_BASE", parse_url);
+        let user_id = s.required("USER_ID", parse_id);
+        let api_secret = s.required("API_SECRET", parse_string);
+        let api_requests_per_minute = s.optional("API_REQUESTS_PER_MINUTE", parse_positive);
+        let map_path = s.required("MAP_PATH", parse_path);
+        let download_workers = s.optional("DOWNLOAD_WORKERS", parse_positive);
+        let download_max_attempts = s.optional("DOWNLOAD_MAX_ATTEMPTS", parse_positive);
+        let database_path = s.optional("DATABASE_PATH", parse_path);
+        // POLL_INTERVALは以前の名前
+        let poll_schedule = match s.optional("POLL_SCHEDULE", Schedule::parse) {
+            Some(p) => Some(p),
+            None => s.optional("POLL_INTERVAL", Schedule::parse),
+        };
+        let refresh_qualified_schedule = s.optional("REFRESH_QUALIFIED_SCHEDULE", Schedule::parse);
+        let daily_digest_schedule = s.optional("DAILY_DIGEST_SCHEDULE", Schedule::parse);
+        let db_backup_schedule = s.optional("DB_BACKUP_SCHEDULE", Schedule::parse);
+        let qualified_reminder_schedule = s.optional("QUALIFIED_REMINDER_SCHEDULE", Schedule::parse);
+        let qualified_reminder_before = s.optional("QUALIFIED_REMINDER_BEFORE", parse_duration);
+        let subscription_digest_schedule = s.optional("SUBSCRIPTION_DIGEST_SCHEDULE", Schedule::parse);
+        let backup_dir = s.optional("BACKUP_DIR", parse_path);
+        let backup_keep = s.optional("BACKUP_KEEP", parse_positive);
+        let file_server_addr = s.optional("FILE_SERVER_ADDR", parse_addr);
+        // サーバーを起動するならURLと署名の鍵も必要
+        let (file_server_url, file_link_secret) = if file_server_addr.is_some() {
+            (s.required("FILE_SERVER_URL", parse_url), s.required("FILE_LINK_SECRET", parse_string))
+        } else {
+            (s.optional("FILE_SERVER_URL", parse_url), s.optional("FILE_LINK_SECRET", parse_string))
+        };
+        let file_link_ttl = s.optional("FILE_LINK_TTL", parse_duration);
+        let download_quota = s.optional("DOWNLOAD_QUOTA", parse_positive);
+        let getmap_guilds = s.optional("GETMAP_GUILD_IDS", parse_ids);
+        let show_pp = s.optional("SHOW_PP", parse_bool);
+        let show_graph = s.optional("SHOW_GRAPH", parse_bool);
+
+        match (discord_token, log_channel, user_id, api_secret, map_path) {
+            (Some(discord_token), Some(log_channel), Some(user_id), Some(api_secret), Some(map_path))
+                if s.problems.is_empty() => Ok(Config {
+                discord_token,
+                guild_id,
+                log_channel,
+                api_base: api_base.unwrap_or_else(|| Url::parse(DEFAULT_API_BASE).unwrap()),
+                download_base: download_base.unwrap_or_else(|| Url::parse(DEFAULT_DOWNLOAD_BASE).unwrap()),
+                user_id,
+                api_secret,
+                api_requests_per_minute: api_requests_per_minute.unwrap_or(60),
+                map_path,
+                download_workers: download_workers.unwrap_or(3),
+                download_max_attempts: download_max_attempts.unwrap_or(5),
+                database_path: database_path.unwrap_or_else(|| PathBuf::from("database.sqlite")),
+                poll_schedule: poll_schedule.unwrap_or(Schedule::Every(Duration::from_secs(30 * 60))),
+                refresh_qualified_schedule: refresh_qualified_schedule.unwrap_or(Schedule::Every(Duration::from_secs(60 * 60))),
+                daily_digest_schedule: daily_digest_schedule.unwrap_or_else(|| Schedule::parse(DEFAULT_DAILY_DIGEST_SCHEDULE).unwrap()),
+                db_backup_schedule: db_backup_schedule.unwrap_or_else(|| Schedule::parse(DEFAULT_DB_BACKUP_SCHEDULE).unwrap()),
+                qualified_reminder_schedule: qualified_reminder_schedule.unwrap_or(Schedule::Every(Duration::from_secs(10 * 60))),
+                qualified_reminder_before: qualified_reminder_before.unwrap_or(Duration::from_secs(24 * 60 * 60)),
+                subscription_digest_schedule: subscription_digest_schedule.unwrap_or(Schedule::Every(Duration::from_secs(60))),
+                backup_dir: backup_dir.unwrap_or_else(|| PathBuf::from("backups")),
+                backup_keep: backup_keep.unwrap_or(7),
+                file_server_addr,
+                file_server_url,
+                file_link_secret,
+                file_link_ttl: file_link_ttl.unwrap_or(Duration::from_secs(60 * 60)),
+                download_quota: download_quota.unwrap_or(20),
+                getmap_guilds: getmap_guilds.unwrap_or_default().into_iter().map(GuildId).collect(),
+                show_pp: show_pp.unwrap_or(false),
+                show_graph: show_graph.unwrap_or(false),
+            }),
+            _ => Err(ConfigError { problems: s.problems }),
+        }
+    }
+
+    // URLの末尾の/を除いたもの("{}/api/v2/..."のように繋げる用)
+    pub fn api_base_str(&self) -> &str {
+        self.api_base.as_str().trim_end_matches('/')
+    }
+
+    pub fn download_base_str(&self) -> &str {
+        self.download_base.as_str().trim_end_matches('/')
+    }
+
+    // 譜面の保存先(末尾に/を付けたもの)
+    pub fn map_dir(&self) -> String {
+        let dir = self.map_path.to_string_lossy();
+        if dir.ends_with('/') { dir.to_string() } else { format!("{}/", dir) }
+    }
+
+    // osu! APIのclientを作り直す必要がある項目
+    fn api_settings(&self) -> (&Url, &Url, u64, &str, u32) {
+        (&self.api_base, &self.download_base, self.user_id, &self.api_secret, self.api_requests_per_minute)
+    }
+
+    pub fn api(&self) -> Api {
+        Api::new(
+            self.user_id,
+            self.api_secret.clone(),
+            self.api_base_str().to_string(),
+            self.download_base_str().to_string(),
+            self.api_requests_per_minute,
+        )
+    }
+
+    // selfからnewへの変更点
+    pub fn diff(&self, new: &Config) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        let mut push = |key: &'static str, old: String, new: String, secret: bool, restart: bool| {
+            if old == new {
+                return;
+            }
+            let (old, new) = if secret { ("***".to_string(), "***".to_string()) } else { (old, new) };
+            changes.push(ConfigChange { key, old, new, restart });
+        };
+        let id = |v: Option<GuildId>| v.map(|g| g.0.to_string()).unwrap_or_else(|| "-".to_string());
+
+        push("DISCORD_TOKEN", self.discord_token.clone(), new.discord_token.clone(), true, true);
+        // slash commandの登録先は起動時(ready)に決まる
+        push("DISCORD_GUILD_ID", id(self.guild_id), id(new.guild_id), false, true);
+        push("DISCORD_LOG_CHANNEL_ID", self.log_channel.0.to_string(), new.log_channel.0.to_string(), false, false);
+        push("API_BASE", self.api_base.to_string(), new.api_base.to_string(), false, false);
+        push("DOWNLOAD_BASE", self.download_base.to_string(), new.download_base.to_string(), false, false);
+        push("USER_ID", self.user_id.to_string(), new.user_id.to_string(), false, false);
+        push("API_SECRET", self.api_secret.clone(), new.api_secret.clone(), true, false);
+        push("API_REQUESTS_PER_MINUTE", self.api_requests_per_minute.to_string(), new.api_requests_per_minute.to_string(), false, false);
+        push("MAP_PATH", self.map_path.display().to_string(), new.map_path.display().to_string(), false, false);
+        // workerは起動時に作る
+        push("DOWNLOAD_WORKERS", self.download_workers.to_string(), new.download_workers.to_string(), false, true);
+        push("DOWNLOAD_MAX_ATTEMPTS", self.download_max_attempts.to_string(), new.download_max_attempts.to_string(), false, false);
+        push("DATABASE_PATH", self.database_path.display().to_string(), new.database_path.display().to_string(), false, true);
+        push("POLL_SCHEDULE", self.poll_schedule.to_string(), new.poll_schedule.to_string(), false, false);
+        push("REFRESH_QUALIFIED_SCHEDULE", self.refresh_qualified_schedule.to_string(), new.refresh_qualified_schedule.to_string(), false, false);
+        push("DAILY_DIGEST_SCHEDULE", self.daily_digest_schedule.to_string(), new.daily_digest_schedule.to_string(), false, false);
+        push("DB_BACKUP_SCHEDULE", self.db_backup_schedule.to_string(), new.db_backup_schedule.to_string(), false, false);
+        push("QUALIFIED_REMINDER_SCHEDULE", self.qualified_reminder_schedule.to_string(), new.qualified_reminder_schedule.to_string(), false, false);
+        push("QUALIFIED_REMINDER_BEFORE", format!("{}s", self.qualified_reminder_before.as_secs()), format!("{}s", new.qualified_reminder_before.as_secs()), false, false);
+        push("SUBSCRIPTION_DIGEST_SCHEDULE", self.subscription_digest_schedule.to_string(), new.subscription_digest_schedule.to_string(), false, false);
+        push("BACKUP_DIR", self.backup_dir.display().to_string(), new.backup_dir.display().to_string(), false, false);
+        push("BACKUP_KEEP", self.backup_keep.to_string(), new.backup_keep.to_string(), false, false);
+        let addr = |v: Option<SocketAddr>| v.map(|a| a.to_string()).unwrap_or_else(|| "-".to_string());
+        let opt_url = |v: &Option<Url>| v.as_ref().map(|u| u.to_string()).unwrap_or_else(|| "-".to_string());
+        let guilds = |v: &[GuildId]| v.iter().map(|g| g.0.to_string()).collect::<Vec<String>>().join(",");
+        // サーバーは起動時にbindする
+        push("FILE_SERVER_ADDR", addr(self.file_server_addr), addr(new.file_server_addr), false, true);
+        push("FILE_SERVER_URL", opt_url(&self.file_server_url), opt_url(&new.file_server_url), false, false);
+        push("FILE_LINK_SECRET", self.file_link_secret.clone().unwrap_or_default(), new.file_link_secret.clone().unwrap_or_default(), true, false);
+        push("FILE_LINK_TTL", format!("{}s", self.file_link_ttl.as_secs()), format!("{}s", new.file_link_ttl.as_secs()), false, false);
+        push("DOWNLOAD_QUOTA", self.download_quota.to_string(), new.download_quota.to_string(), false, false);
+        push("GETMAP_GUILD_IDS", guilds(&self.getmap_guilds), guilds(&new.getmap_guilds), false, false);
+        push("SHOW_PP", self.show_pp.to_string(), new.show_pp.to_string(), false, false);
+        push("SHOW_GRAPH", self.show_graph.to_string(), new.show_graph.to_string(), false, false);
+        changes
+    }
+}
+
+// .env(カレントディレクトリか親ディレクトリにあるもの)を読む
+// プロセスの環境変数には入れない(実行中に書き換えると他のスレッドの読み込みと競合する)
+pub fn read_dotenv() -> Result<HashMap<String, String>, dotenvy::Error> {
+    dotenvy::dotenv_iter()?.collect()
+}
+
+// 設定を読み直してTypeMapの設定(とAPI client)を差し替え，結果をlog channelに送る
+// 読み込みに失敗したときは今の設定のまま
+pub async fn reload(data: &RwLock<TypeMap>, http: &Http) -> Result<Vec<ConfigChange>, ConfigError> {
+    let dotenv = match read_dotenv() {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to reload .env file: {}", e);
+            HashMap::new()
+        }
+    };
+    let loaded = Config::load(&dotenv);
+
+    // 読み込みから差し替えまで書き込みロックを持ったままにする
+    let mut map = data.write().await;
+    let old = match map.get::<Settings>() {
+        Some(c) => c.clone(),
+        None => {
+            error!("Config is not found in TypeMap");
+            return loaded.map(|_| Vec::new());
+        }
+    };
+    let (res, log_channel) = match loaded {
+        Ok(new) => {
+            let changes = old.diff(&new);
+            if old.api_settings() != new.api_settings() {
+                map.insert::<OsuApi>(Arc::new(new.api()));
+            }
+            let log_channel = new.log_channel;
+            map.insert::<Settings>(Arc::new(new));
+            (Ok(changes), log_channel)
+        },
+        Err(e) => (Err(e), old.log_channel),
+    };
+    drop(map);
+
+    let mut embed = CreateEmbed::default();
+    match &res {
+        Ok(changes) => {
+            info!("Reloaded config ({} changes)", changes.len());
+            let description = if changes.is_empty() {
+                "No changes".to_string()
+            } else {
+                changes.iter().map(|c| c.to_string()).collect::<Vec<String>>().join("\n")
+            };
+            embed.title("Config reloaded").description(description).color(0x00ffff);
+        },
+        Err(e) => {
+            error!("Failed to reload config: {}", e);
+            embed.title("Config reload failed")
+                .description(format!("```\n{}```\n今の設定のまま動作しています", e))
+                .color(0xff0000);
+        },
+    }
+    if let Err(e) = log_channel.send_message(http, |m| m.set_embed(embed)).await {
+        error!("Failed to log config reload: {}", e);
+    }
+    res
+}
+
+// 読み込んだ生の値(key => 値)と見つかった問題
+#[derive(Default)]
+struct Source {
+    values: HashMap<String, String>,
+    problems: Vec<ConfigProblem>,
+}
+
+impl Source {
+    fn read_file(&mut self, path: &Path) {
+        let text = match std::fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                self.problems.push(ConfigProblem::File { path: path.to_path_buf(), reason: e.to_string() });
+                return;
+            }
+        };
+        let table = match text.parse::<toml::Value>() {
+            Ok(toml::Value::Table(t)) => t,
+            Ok(_) => {
+                self.problems.push(ConfigProblem::File { path: path.to_path_buf(), reason: "not a table".to_string() });
+                return;
+            }
+            Err(e) => {
+                self.problems.push(ConfigProblem::File { path: path.to_path_buf(), reason: e.to_string() });
+                return;
+            }
+        };
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                v => {
+                    self.problems.push(ConfigProblem::File {
+                        path: path.to_path_buf(),
+                        reason: format!("{} must be a string or a number, got {}", key, v.type_str()),
+                    });
+                    continue;
+                }
+            };
+            self.values.insert(key.to_uppercase(), value);
+        }
+    }
+
+    // .envの値(環境変数と同じく大文字のkey)
+    fn read_values(&mut self, values: &HashMap<String, String>) {
+        for (key, value) in values {
+            self.values.insert(key.clone(), value.clone());
+        }
+    }
+
+    fn read_env(&mut self) {
+        for (key, value) in env::vars() {
+            self.values.insert(key, value);
+        }
+    }
+
+    // 空文字列は未設定扱い
+    fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|v| v.trim()).filter(|v| !v.is_empty())
+    }
+
+    fn required<T>(&mut self, key: &'static str, parse: fn(&str) -> Result<T, String>) -> Option<T> {
+        if self.get(key).is_none() {
+            self.problems.push(ConfigProblem::Missing { key });
+            return None;
+        }
+        self.optional(key, parse)
+    }
+
+    fn optional<T>(&mut self, key: &'static str, parse: fn(&str) -> Result<T, String>) -> Option<T> {
+        let value = self.get(key)?.to_string();
+        match parse(&value) {
+            Ok(v) => Some(v),
+            Err(reason) => {
+                self.problems.push(ConfigProblem::Invalid { key, value, reason });
+                None
+            }
+        }
+    }
+}
+
+fn parse_string(s: &str) -> Result<String, String> {
+    Ok(s.to_string())
+}
+
+fn parse_id(s: &str) -> Result<u64, String> {
+    s.parse::<u64>().map_err(|e| e.to_string())
+}
+
+// "123,456"
+fn parse_ids(s: &str) -> Result<Vec<u64>, String> {
+    s.split(',').map(|v| v.trim()).filter(|v| !v.is_empty()).map(parse_id).collect()
+}
+
+fn parse_bool(s: &str) -> Result<bool, String> {
+    match s.to_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err("must be true or false".to_string()),
+    }
+}
+
+fn parse_addr(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>().map_err(|e| e.to_string())
+}
+
+fn parse_positive(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(0) => Err("must be greater than 0".to_string()),
+        Ok(v) => Ok(v),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_url(s: &str) -> Result<Url, String> {
+    match Url::parse(s) {
+        Ok(u) if u.scheme() == "http" || u.scheme() == "https" => Ok(u),
+        Ok(u) => Err(format!("unsupported scheme: {}", u.scheme())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn parse_path(s: &str) -> Result<PathBuf, String> {
+    Ok(PathBuf::from(s))
+}
+
+// parse_durationの上限(100年)．unix時刻に足してもi64で溢れないようにする
+const MAX_DURATION_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+// "90", "90s", "30m", "1h", "1d"
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let n = num.parse::<u64>().map_err(|_| "expected a number followed by s, m, h or d".to_string())?;
+    let unit_secs = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 60 * 60 * 24,
+        u => return Err(format!("unknown unit: {} (s, m, h, d)", u)),
+    };
+    let secs = match n.checked_mul(unit_secs) {
+        Some(secs) if secs <= MAX_DURATION_SECS => secs,
+        _ => return Err("too long (at most 100 years)".to_string()),
+    };
+    if secs == 0 {
+        return Err("must be greater than 0".to_string());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration(" 30m "), Ok(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("1h"), Ok(Duration::from_secs(60 * 60)));
+        assert_eq!(parse_duration("2 d"), Ok(Duration::from_secs(2 * 24 * 60 * 60)));
+    }
+
+    #[test]
+    fn rejects_invalid_durations() {
+        assert!(parse_duration("").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("0m").is_err());
+        assert!(parse_duration("-1s").is_err());
+        assert!(parse_duration("10w").is_err());
+        assert!(parse_duration("1.5h").is_err());
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!(parse_duration("18446744073709551615d").is_err());
+        assert!(parse_duration("36500d").is_ok());
+        assert!(parse_duration("36501d").is_err());
+    }
+}
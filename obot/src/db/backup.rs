@@ -0,0 +1,52 @@
+use std::{
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use chrono::Utc;
+use serenity::prelude::*;
+
+use crate::config::Config;
+use super::handler::DBHandler;
+
+// バックアップのファイル名は obot-YYYYmmdd-HHMMSS.sqlite (名前順 = 日時順)
+const PREFIX: &str = "obot-";
+const SUFFIX: &str = ".sqlite";
+
+// スケジューラから呼び出される関数
+// BACKUP_DIRにDBのコピーを作り，BACKUP_KEEPより古いものを消す
+pub async fn backup_database(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::shared(ctx).await?;
+    fs::create_dir_all(&config.backup_dir)?;
+
+    let name = format!("{}{}{}", PREFIX, Utc::now().format("%Y%m%d-%H%M%S"), SUFFIX);
+    let path = config.backup_dir.join(name);
+    let db = DBHandler::new(ctx).await;
+    db.vacuum_into(&path.to_string_lossy()).await?;
+    info!("Backed up database to {}", path.display());
+
+    for old in old_backups(&config.backup_dir, config.backup_keep as usize)? {
+        match fs::remove_file(&old) {
+            Ok(_) => info!("Removed old backup {}", old.display()),
+            Err(e) => warn!("Failed to remove old backup {}: {}", old.display(), e),
+        }
+    }
+    Ok(())
+}
+
+// 新しいものからkeep個を除いたバックアップ
+fn old_backups(dir: &Path, keep: usize) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        if name.starts_with(PREFIX) && name.ends_with(SUFFIX) {
+            backups.push(path);
+        }
+    }
+    backups.sort();
+    let n = backups.len().saturating_sub(keep);
+    backups.truncate(n);
+    Ok(backups)
+}
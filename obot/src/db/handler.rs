@@ -4,18 +4,57 @@ use serenity::{
 };
 
 use std::sync::{Arc};
-use std::io::{Error as StdError, ErrorKind};
+use std::io::Error as StdError;
 use std::error::Error;
+use sqlx::{QueryBuilder, Sqlite};
 
 use crate::cache::Database;
-use crate::web::api;
-use api::Beatmap;
+use crate::db::{search::{Range, SearchQuery}, subscription::Subscription};
+use crate::osu::pattern::Pattern;
+use crate::utility;
+use crate::web::{api, mode::GameMode, revision::DifficultyChange};
+use api::{Beatmap, Difficulty};
 
 pub struct DBHandler {
     db: Arc<Mutex<sqlx::SqlitePool>>,
 }
 
-// TODO: statusごとにSQL文作ってる問題を解決する
+// beatmapsetsテーブルの1行
+// 難易度はbeatmapsテーブルから別に取ってきてBeatmapにまとめる
+#[derive(sqlx::FromRow)]
+struct BeatmapsetRow {
+    id: i64,
+    title: String,
+    artist: String,
+    creator: String,
+    mp3_url: String,
+    card_url: String,
+    cursor: String,
+    status: String,
+    ranked_date: Option<i64>,
+    tags: String,
+    user_id: Option<i64>,
+}
+
+impl BeatmapsetRow {
+    fn into_beatmap(self, difficulties: Vec<Difficulty>) -> Beatmap {
+        Beatmap {
+            id: self.id,
+            title: self.title,
+            artist: self.artist,
+            creator: self.creator,
+            mp3_url: self.mp3_url,
+            card_url: self.card_url,
+            cursor: self.cursor,
+            status: self.status,
+            ranked_date: self.ranked_date,
+            tags: self.tags,
+            user_id: self.user_id,
+            difficulties,
+        }
+    }
+}
+
 // TODO: cursor_stirng の更新処理
 impl DBHandler {
     pub async fn new(ctx: &Context) -> Self {
@@ -24,87 +63,135 @@ impl DBHandler {
         Self { db }
     }
 
-    pub async fn insert(&self, beatmapset: &Beatmap) -> Result<(), Box<dyn Error + Sync + Send>> {
-        let db = self.db.lock().await;
-
-        match beatmapset.statu.as_str() {
-            "ranked" => {
-                match sqlx::query!(r#"
-                INSERT INTO ranked_beatmapsets
-                (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-                beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.stars, beatmapset.keys, beatmapset.mp3_url, beatmapset.card_url, beatmapset.cursor, beatmapset.statu
-                ).execute(&*db).await {
-                    Ok(_) => {},
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "loved" => {
-                match sqlx::query!(r#"
-                INSERT INTO loved_beatmapsets
-                (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-                beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.stars, beatmapset.keys, beatmapset.mp3_url, beatmapset.card_url, beatmapset.cursor, beatmapset.statu
-                ).execute(&*db).await {
-                    Ok(_) => {},
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "qualified" => {
-                match sqlx::query!(r#"
-                INSERT INTO qualified_beatmapsets
-                (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-                beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.stars, beatmapset.keys, beatmapset.mp3_url, beatmapset.card_url, beatmapset.cursor, beatmapset.statu
-                ).execute(&*db).await {
-                    Ok(_) => {},
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            _ => {
-                match sqlx::query!(r#"
-                INSERT INTO graveyard_beatmapsets
-                (id, title, artist, creator, stars, keys, mp3_url, card_url, cursor, statu)
-                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
-                beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.stars, beatmapset.keys, beatmapset.mp3_url, beatmapset.card_url, beatmapset.cursor, beatmapset.statu
-                ).execute(&*db).await {
-                    Ok(_) => {},
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
+    // 既に同じidがある場合は上書きする(statusが変わった場合など)
+    // 難易度は全て入れ直す
+    // 新規またはstatusが変わった場合は履歴に残す．戻り値は変更前のstatus(新規ならNone)
+    pub async fn insert(&self, beatmapset: &Beatmap) -> Result<Option<String>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+
+        let old_status = sqlx::query!("SELECT status FROM beatmapsets WHERE id = ?", beatmapset.id)
+            .fetch_optional(&mut tx).await?
+            .map(|r| r.status);
+
+        sqlx::query!(r#"
+        INSERT INTO beatmapsets
+        (id, title, artist, creator, mp3_url, card_url, cursor, status, ranked_date, tags, user_id)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET
+            title = excluded.title, artist = excluded.artist, creator = excluded.creator,
+            mp3_url = excluded.mp3_url, card_url = excluded.card_url,
+            cursor = excluded.cursor, status = excluded.status, ranked_date = excluded.ranked_date,
+            tags = excluded.tags, user_id = COALESCE(excluded.user_id, user_id)"#,
+        beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.mp3_url, beatmapset.card_url, beatmapset.cursor, beatmapset.status, beatmapset.ranked_date,
+        beatmapset.tags, beatmapset.user_id
+        ).execute(&mut tx).await?;
+
+        // 全文検索用
+        let versions = beatmapset.difficulties.iter().map(|d| d.version.as_str()).collect::<Vec<&str>>().join(" ");
+        sqlx::query!("DELETE FROM beatmapsets_fts WHERE rowid = ?", beatmapset.id)
+            .execute(&mut tx).await?;
+        sqlx::query!(r#"
+        INSERT INTO beatmapsets_fts (rowid, title, artist, creator, tags, versions)
+        VALUES (?, ?, ?, ?, ?, ?)"#,
+        beatmapset.id, beatmapset.title, beatmapset.artist, beatmapset.creator, beatmapset.tags, versions
+        ).execute(&mut tx).await?;
+
+        sqlx::query!("DELETE FROM beatmaps WHERE beatmapset_id = ?", beatmapset.id)
+            .execute(&mut tx).await?;
+        for d in &beatmapset.difficulties {
+            sqlx::query!(r#"
+            INSERT INTO beatmaps
+            (id, beatmapset_id, mode, version, difficulty_rating, keys, cs, ar, od, hp, bpm, total_length, count_notes, count_lns)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+            d.id, beatmapset.id, d.mode, d.version, d.difficulty_rating, d.keys, d.cs, d.ar, d.od, d.hp, d.bpm, d.total_length, d.count_notes, d.count_lns
+            ).execute(&mut tx).await?;
         }
 
+        if old_status.as_deref() != Some(beatmapset.status.as_str()) {
+            let now = utility::unix_now();
+            sqlx::query!(r#"
+            INSERT INTO beatmapset_status_history (beatmapset_id, old_status, new_status, changed_at)
+            VALUES (?, ?, ?, ?)"#,
+            beatmapset.id, old_status, beatmapset.status, now
+            ).execute(&mut tx).await?;
+        }
+
+        tx.commit().await?;
+        Ok(old_status)
+    }
+
+    // 現在DBに入っているstatus(なければNone)
+    pub async fn get_status(&self, id: i64) -> Result<Option<String>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!("SELECT status FROM beatmapsets WHERE id = ?", id)
+            .fetch_optional(&*db).await?;
+        Ok(row.map(|r| r.status))
+    }
+
+    // statusに関係なくidで取り出す
+    pub async fn get_beatmapset(&self, id: i64) -> Result<Option<Beatmap>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let rows = sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE id = ?", id)
+            .fetch_all(&*db).await?;
+        Ok(with_difficulties(&db, rows).await?.pop())
+    }
+
+    // 現在DBに入っている難易度
+    pub async fn get_difficulties(&self, id: i64) -> Result<Vec<Difficulty>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let difficulties = sqlx::query_as!(Difficulty, r#"
+            SELECT id, beatmapset_id, mode as "mode: GameMode", version, difficulty_rating, keys,
+                cs, ar, od, hp, bpm, total_length, count_notes, count_lns
+            FROM beatmaps WHERE beatmapset_id = ? ORDER BY mode, keys, difficulty_rating"#,
+            id
+        ).fetch_all(&*db).await?;
+        Ok(difficulties)
+    }
+
+    // 難易度の変更履歴を残す
+    pub async fn insert_revisions(&self, id: i64, changes: &[DifficultyChange]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        let now = utility::unix_now();
+        for c in changes {
+            let (beatmap_id, version, kind) = (c.beatmap_id(), c.version(), c.kind());
+            let (old_rating, new_rating) = c.ratings();
+            sqlx::query!(r#"
+            INSERT INTO beatmapset_revisions
+            (beatmapset_id, beatmap_id, version, change, old_rating, new_rating, detected_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?)"#,
+            id, beatmap_id, version, kind, old_rating, new_rating, now
+            ).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
         Ok(())
     }
 
-    pub async fn get_db_size(&self, status: &str, key: &str) -> Result<i32, Box<dyn Error + Sync + Send>> {
-        let db = self.db.lock().await;
-        let key_str = format!("%{}%", key);
-        let size: i32 = match status {
-            "ranked" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM ranked_beatmapsets WHERE keys LIKE ?", key_str).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "loved" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM loved_beatmapsets WHERE keys LIKE ?", key_str).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "qualified" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM qualified_beatmapsets WHERE keys LIKE ?", key_str).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            _ => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM graveyard_beatmapsets WHERE keys LIKE ?", key_str).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
+    // 最後にstatusになった時刻(unix time)
+    pub async fn status_since(&self, id: i64, status: &str) -> Result<Option<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!(r#"
+            SELECT changed_at as "changed_at!" FROM beatmapset_status_history
+            WHERE beatmapset_id = ? AND new_status = ?
+            ORDER BY changed_at DESC, id DESC LIMIT 1"#,
+            id, status
+        ).fetch_optional(&*db).await?;
+        Ok(row.map(|r| r.changed_at))
+    }
+
+    // modeの難易度を含むbeatmapsetの数(keyはmaniaのみ，Noneなら全てのキー数)
+    pub async fn get_db_size(&self, mode: GameMode, status: &str, key: Option<i64>) -> Result<i32, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let size = match sqlx::query!(r#"
+            SELECT COUNT(*) as count FROM beatmapsets
+            WHERE status = ? AND id IN (
+                SELECT beatmapset_id FROM beatmaps WHERE mode = ? AND (? IS NULL OR keys = ?)
+            )"#,
+            status, mode, key, key
+        ).fetch_one(&*db).await {
+            Ok(r) => r.count,
+            Err(e) => return Err(Box::new(e)),
         };
 
         Ok(size)
@@ -112,150 +199,673 @@ impl DBHandler {
 
     pub async fn check_existence(&self, id: &i64, status: &str) -> Result<bool, Box<dyn Error + Sync + Send>> {
         let db = self.db.lock().await;
-        let res = match status {
-            "ranked" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM ranked_beatmapsets WHERE id = ?", id).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "loved" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM loved_beatmapsets WHERE id = ?", id).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            "qualified" => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM qualified_beatmapsets WHERE id = ?", id).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
-            _ => {
-                match sqlx::query!("SELECT COUNT(*) as count FROM graveyard_beatmapsets WHERE id = ?", id).fetch_one(&*db).await {
-                    Ok(r) => r.count,
-                    Err(e) => return Err(Box::new(e)),
-                }
-            },
+        let res = match sqlx::query!(
+            "SELECT COUNT(*) as count FROM beatmapsets WHERE id = ? AND status = ?",
+            id, status
+        ).fetch_one(&*db).await {
+            Ok(r) => r.count,
+            Err(e) => return Err(Box::new(e)),
         };
 
-        if res == 0 {
-            Ok(false)
-        } else {
-            Ok(true)
-        }
+        Ok(res != 0)
     }
 
-    // select_by: select beatmapset by id, title, artist, creator, or cursor (stars is other method)
-    // keysは"4, 7"のようにカンマ区切りで格納されているので，"4"とか"7"が含まれているかどうかで検索する必要がある
+    // select_by: select beatmapset by id, title, artist, creator, cursor, keys (mania) or mode (stars is other method)
     pub async fn select(&self, select_by: &str, status: &str, value: &str) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
         let db = self.db.lock().await;
-        let mut beatmapsets = Vec::new();
-        let key_str = format!("%{}%", value);
-        let res = match status {
-            "ranked" => {
-                match select_by {
-                    "id" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE id = ?", value).fetch_all(&*db).await,
-                    "title" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE title = ?", value).fetch_all(&*db).await,
-                    "artist" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE artist = ?", value).fetch_all(&*db).await,
-                    "creator" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE creator = ?", value).fetch_all(&*db).await,
-                    "cursor" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE cursor = ?", value).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE keys LIKE ?", key_str).fetch_all(&*db).await,
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets").fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            "loved" => {
-                match select_by {
-                    "id" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE id = ?", value).fetch_all(&*db).await,
-                    "title" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE title = ?", value).fetch_all(&*db).await,
-                    "artist" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE artist = ?", value).fetch_all(&*db).await,
-                    "creator" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE creator = ?", value).fetch_all(&*db).await,
-                    "cursor" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE cursor = ?", value).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE keys LIKE ?", key_str).fetch_all(&*db).await,
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets").fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            "qualified" => {
-                match select_by {
-                    "id" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE id = ?", value).fetch_all(&*db).await,
-                    "title" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE title = ?", value).fetch_all(&*db).await,
-                    "artist" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE artist = ?", value).fetch_all(&*db).await,
-                    "creator" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE creator = ?", value).fetch_all(&*db).await,
-                    "cursor" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE cursor = ?", value).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE keys LIKE ?", key_str).fetch_all(&*db).await,
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets").fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            "graveyard" => {
-                match select_by {
-                    "id" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE id = ?", value).fetch_all(&*db).await,
-                    "title" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE title = ?", value).fetch_all(&*db).await,
-                    "artist" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE artist = ?", value).fetch_all(&*db).await,
-                    "creator" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE creator = ?", value).fetch_all(&*db).await,
-                    "cursor" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE cursor = ?", value).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE keys LIKE ?", key_str).fetch_all(&*db).await,
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets").fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid status"))),
+        let res = match select_by {
+            "id" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ? AND id = ?", status, value).fetch_all(&*db).await,
+            "title" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ? AND title = ?", status, value).fetch_all(&*db).await,
+            "artist" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ? AND artist = ?", status, value).fetch_all(&*db).await,
+            "creator" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ? AND creator = ?", status, value).fetch_all(&*db).await,
+            "cursor" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ? AND cursor = ?", status, value).fetch_all(&*db).await,
+            "keys" => sqlx::query_as!(BeatmapsetRow, r#"
+                SELECT * FROM beatmapsets
+                WHERE status = ? AND id IN (SELECT beatmapset_id FROM beatmaps WHERE mode = 'mania' AND keys = ?)"#,
+                status, value).fetch_all(&*db).await,
+            "mode" => sqlx::query_as!(BeatmapsetRow, r#"
+                SELECT * FROM beatmapsets
+                WHERE status = ? AND id IN (SELECT beatmapset_id FROM beatmaps WHERE mode = ?)"#,
+                status, value).fetch_all(&*db).await,
+            "*" => sqlx::query_as!(BeatmapsetRow, "SELECT * FROM beatmapsets WHERE status = ?", status).fetch_all(&*db).await,
+            _ => return Err(Box::new(StdError::other("Invalid select_by"))),
         };
 
         match res {
-            Ok(res) => {
-                for beatmapset in res {
-                    beatmapsets.push(beatmapset);
-                }
-            },
-            Err(e) => return Err(Box::new(e)),
+            Ok(rows) => with_difficulties(&db, rows).await,
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+
+    // 指定したmode(とキー数)で，星の範囲[min, max]に入る難易度を含むbeatmapset
+    pub async fn select_by_stars(&self, mode: GameMode, status: &str, key: Option<i64>, min: f64, max: f64) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let res = sqlx::query_as!(BeatmapsetRow, r#"
+            SELECT * FROM beatmapsets
+            WHERE status = ? AND id IN (
+                SELECT beatmapset_id FROM beatmaps
+                WHERE mode = ? AND (? IS NULL OR keys = ?) AND difficulty_rating BETWEEN ? AND ?
+            )
+            ORDER BY id"#,
+            status, mode, key, key, min, max).fetch_all(&*db).await;
+
+        match res {
+            Ok(rows) => with_difficulties(&db, rows).await,
+            Err(e) => Err(Box::new(e)),
         }
+    }
+
+    // select_by: *, keys
+    // *はmodeの難易度を含む全て，keysはさらにキー数で絞り込む(maniaのみ)
+    // idの昇順(=新しい譜面ほど後ろ)
+    pub async fn select_with_limit(&self, select_by: &str, mode: GameMode, status: &str, value: &str, limit: i64, offset: i64) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
 
-        Ok(beatmapsets)
-    }
-
-
-    // select_by: keys, stars
-    pub async fn select_with_limit(&self, select_by: &str, status: &str, value: &str, limit: i64, offset: i64) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
-        let db = self.db.lock().await;
-        let key_str = format!("%{}%", value);
-
-        let res = match status {
-            "ranked" => {
-                match select_by {
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets LIMIT ? OFFSET ?", limit, offset).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM ranked_beatmapsets WHERE keys LIKE ? LIMIT ? OFFSET ?", key_str, limit, offset).fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            "loved" => {
-                match select_by {
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets LIMIT ? OFFSET ?", limit, offset).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM loved_beatmapsets WHERE keys LIKE ? LIMIT ? OFFSET ?", key_str, limit, offset).fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            "qualified" => {
-                match select_by {
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets LIMIT ? OFFSET ?", limit, offset).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM qualified_beatmapsets WHERE keys LIKE ? LIMIT ? OFFSET ?", key_str, limit, offset).fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
-            _ => {
-                match select_by {
-                    "*" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets LIMIT ? OFFSET ?", limit, offset).fetch_all(&*db).await,
-                    "keys" => sqlx::query_as!(Beatmap, "SELECT * FROM graveyard_beatmapsets WHERE keys LIKE ? LIMIT ? OFFSET ?", key_str, limit, offset).fetch_all(&*db).await,
-                    _ => return Err(Box::new(StdError::new(ErrorKind::Other, "Invalid select_by"))),
-                }
-            },
+        let res = match select_by {
+            "*" => sqlx::query_as!(BeatmapsetRow, r#"
+                SELECT * FROM beatmapsets
+                WHERE status = ? AND id IN (SELECT beatmapset_id FROM beatmaps WHERE mode = ?)
+                ORDER BY id LIMIT ? OFFSET ?"#,
+                status, mode, limit, offset).fetch_all(&*db).await,
+            "keys" => sqlx::query_as!(BeatmapsetRow, r#"
+                SELECT * FROM beatmapsets
+                WHERE status = ? AND id IN (SELECT beatmapset_id FROM beatmaps WHERE mode = ? AND keys = ?)
+                ORDER BY id LIMIT ? OFFSET ?"#,
+                status, mode, value, limit, offset).fetch_all(&*db).await,
+            _ => return Err(Box::new(StdError::other("Invalid select_by"))),
         };
 
         match res {
-            Ok(res) => return Ok(res),
-            Err(e) => return Err(Box::new(e)),
+            Ok(rows) => with_difficulties(&db, rows).await,
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+}
+
+// 検索
+impl DBHandler {
+    // 条件に合う譜面の数
+    pub async fn search_count(&self, q: &SearchQuery) -> Result<usize, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let (count,) = search_query(q, "SELECT COUNT(*)")
+            .build_query_as::<(i64,)>()
+            .fetch_one(&*db).await?;
+        Ok(count as usize)
+    }
+
+    // 条件に合う譜面のoffsetからlimit件(難易度はその分だけ読む)
+    // 語があれば全文検索の関連度順，なければ新しい順
+    pub async fn search(&self, q: &SearchQuery, limit: usize, offset: usize) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut query = search_query(q, "SELECT s.*");
+        query.push(if q.fts_query().is_some() { " ORDER BY f.rank, s.id DESC" } else { " ORDER BY s.id DESC" })
+            .push(" LIMIT ").push_bind(limit as i64)
+            .push(" OFFSET ").push_bind(offset as i64);
+        let rows = query.build_query_as::<BeatmapsetRow>().fetch_all(&*db).await?;
+        with_difficulties(&db, rows).await
+    }
+}
+
+// selectの後に検索の条件を付ける(全文検索ではbeatmapsets_ftsをfとしてJOINする)
+// 条件の有無で形が変わるのでquery!ではなくQueryBuilderで組み立てる
+fn search_query<'a>(q: &'a SearchQuery, select: &str) -> QueryBuilder<'a, Sqlite> {
+    let mut query = QueryBuilder::new(select);
+    match q.fts_query() {
+        Some(text) => {
+            query.push(" FROM beatmapsets_fts f JOIN beatmapsets s ON s.id = f.rowid WHERE beatmapsets_fts MATCH ")
+                .push_bind(text);
+        },
+        None => {
+            query.push(" FROM beatmapsets s WHERE 1");
+        }
+    }
+    if let Some(status) = &q.status {
+        query.push(" AND s.status = ").push_bind(status);
+    }
+    if let Some(creator) = &q.creator {
+        query.push(" AND s.creator = ").push_bind(creator).push(" COLLATE NOCASE");
+    }
+    if !q.has_difficulty_filter() {
+        return query;
+    }
+    // 条件に合う難易度が1つでもあるbeatmapset
+    query.push(" AND s.id IN (SELECT b.beatmapset_id FROM beatmaps b LEFT JOIN patterns p ON p.beatmap_id = b.id WHERE 1");
+    if let Some(mode) = q.mode {
+        query.push(" AND b.mode = ").push_bind(mode);
+    }
+    if let Some(keys) = q.keys {
+        query.push(" AND b.keys = ").push_bind(keys);
+    }
+    push_range(&mut query, "b.difficulty_rating", q.stars);
+    if let Some(pattern) = &q.pattern {
+        query.push(" AND p.category = ").push_bind(pattern);
+    }
+    push_range(&mut query, "p.ln_ratio", q.ln);
+    push_range(&mut query, "p.peak_nps", q.nps);
+    query.push(")");
+    query
+}
+
+fn push_range(query: &mut QueryBuilder<Sqlite>, column: &str, range: Range) {
+    if let Some((min, inclusive)) = range.min {
+        query.push(format!(" AND {} {} ", column, if inclusive { ">=" } else { ">" })).push_bind(min);
+    }
+    if let Some((max, inclusive)) = range.max {
+        query.push(format!(" AND {} {} ", column, if inclusive { "<=" } else { "<" })).push_bind(max);
+    }
+}
+
+// qualifiedの譜面のranking
+impl DBHandler {
+    // ranking予定順のqualified譜面(modeとキー数で絞り込む．ranked_dateが分からないものは最後)
+    pub async fn qualified_queue(&self, mode: Option<GameMode>, key: Option<i64>) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let rows = sqlx::query_as!(BeatmapsetRow, r#"
+            SELECT * FROM beatmapsets
+            WHERE status = 'qualified' AND (? IS NULL OR id IN (
+                SELECT beatmapset_id FROM beatmaps WHERE mode = ? AND (? IS NULL OR keys = ?)
+            ))
+            ORDER BY ranked_date IS NULL, ranked_date, id"#,
+            mode, mode, key, key
+        ).fetch_all(&*db).await?;
+        with_difficulties(&db, rows).await
+    }
+
+    pub async fn reminder_sent(&self, id: i64, ranked_date: i64) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!(
+            "SELECT sent_at FROM qualified_reminders WHERE beatmapset_id = ? AND ranked_date = ?",
+            id, ranked_date
+        ).fetch_optional(&*db).await?;
+        Ok(row.is_some())
+    }
+
+    pub async fn mark_reminder_sent(&self, id: i64, ranked_date: i64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let now = utility::unix_now();
+        sqlx::query!(
+            "INSERT OR IGNORE INTO qualified_reminders (beatmapset_id, ranked_date, sent_at) VALUES (?, ?, ?)",
+            id, ranked_date, now
+        ).execute(&*db).await?;
+        Ok(())
+    }
+}
+
+// 通知設定(subscriptions)
+impl DBHandler {
+    // 同じチャンネルに既に設定がある場合は上書きする(id, created_at, last_digestは無視)
+    // digestは設定した時点から数える
+    pub async fn upsert_subscription(&self, sub: &Subscription) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let now = utility::unix_now();
+        sqlx::query!(r#"
+        INSERT INTO subscriptions (guild_id, channel_id, statuses, modes, keys, min_stars, max_stars, pattern, digest, last_digest, created_at)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(guild_id, channel_id) DO UPDATE SET
+            statuses = excluded.statuses, modes = excluded.modes, keys = excluded.keys,
+            min_stars = excluded.min_stars, max_stars = excluded.max_stars, pattern = excluded.pattern,
+            digest = excluded.digest, last_digest = excluded.last_digest"#,
+        sub.guild_id, sub.channel_id, sub.statuses, sub.modes, sub.keys, sub.min_stars, sub.max_stars, sub.pattern, sub.digest, now, now
+        ).execute(&*db).await?;
+        // digestをやめたら溜まっていた譜面は捨てる
+        if sub.digest.is_none() {
+            sqlx::query!(r#"
+            DELETE FROM digest_entries WHERE subscription_id IN (
+                SELECT id FROM subscriptions WHERE guild_id = ? AND channel_id = ?
+            )"#,
+            sub.guild_id, sub.channel_id
+            ).execute(&*db).await?;
+        }
+        Ok(())
+    }
+
+    // digestで送る譜面を溜めておく
+    pub async fn add_digest_entry(&self, subscription_id: i64, beatmapset_id: i64, kind: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let now = utility::unix_now();
+        sqlx::query!(
+            "INSERT INTO digest_entries (subscription_id, beatmapset_id, kind, created_at) VALUES (?, ?, ?, ?)",
+            subscription_id, beatmapset_id, kind, now
+        ).execute(&*db).await?;
+        Ok(())
+    }
+
+    // 溜まっている譜面(古い順)
+    pub async fn digest_entries(&self, subscription_id: i64) -> Result<Vec<DigestEntry>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let entries = sqlx::query_as!(DigestEntry,
+            "SELECT id, beatmapset_id, kind FROM digest_entries WHERE subscription_id = ? ORDER BY id",
+            subscription_id
+        ).fetch_all(&*db).await?;
+        Ok(entries)
+    }
+
+    // 送ったもの(id <= last_entry)を消して，送った時刻を記録する
+    pub async fn finish_digest(&self, subscription_id: i64, last_entry: i64, sent_at: i64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        sqlx::query!(
+            "DELETE FROM digest_entries WHERE subscription_id = ? AND id <= ?",
+            subscription_id, last_entry
+        ).execute(&mut tx).await?;
+        sqlx::query!(
+            "UPDATE subscriptions SET last_digest = ? WHERE id = ?",
+            sent_at, subscription_id
+        ).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // 削除できたらtrue
+    pub async fn delete_subscription(&self, guild_id: i64, channel_id: i64) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let res = sqlx::query!(
+            "DELETE FROM subscriptions WHERE guild_id = ? AND channel_id = ?",
+            guild_id, channel_id
+        ).execute(&*db).await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    pub async fn guild_subscriptions(&self, guild_id: i64) -> Result<Vec<Subscription>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let subs = sqlx::query_as!(Subscription,
+            "SELECT * FROM subscriptions WHERE guild_id = ? ORDER BY id",
+            guild_id
+        ).fetch_all(&*db).await?;
+        Ok(subs)
+    }
+
+    pub async fn all_subscriptions(&self) -> Result<Vec<Subscription>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let subs = sqlx::query_as!(Subscription, "SELECT * FROM subscriptions ORDER BY id")
+            .fetch_all(&*db).await?;
+        Ok(subs)
+    }
+}
+
+// digestで送る予定の譜面1件分
+#[derive(Debug, Clone)]
+pub struct DigestEntry {
+    pub id: i64,
+    pub beatmapset_id: i64,
+    pub kind: String,
+}
+
+// ダウンロード済みの.osz 1件分(sha256は16進数)
+#[derive(Debug, Clone)]
+pub struct Download {
+    pub beatmapset_id: i64,
+    pub path: String,
+    pub size: i64,
+    pub sha256: String,
+    pub downloaded_at: i64,
+}
+
+// ダウンロード待ちの譜面1件分
+#[derive(Debug, Clone)]
+pub struct DownloadJob {
+    pub beatmapset_id: i64,
+    pub title: String,
+    pub status: String,
+    pub state: String, // queued, active, failed
+    pub attempts: i64,
+    pub last_error: Option<String>,
+    pub next_try: i64,
+    pub created_at: i64,
+}
+
+// 配置の条件があるsubscriptionに，分析後に送る予定の譜面1件分
+#[derive(Debug, Clone)]
+pub struct PatternPending {
+    pub id: i64,
+    pub subscription_id: i64,
+    pub beatmapset_id: i64,
+    pub old_status: Option<String>,
+}
+
+// statusの変化(履歴)1件分．digest用にbeatmapsetの情報も付ける
+#[derive(Debug, Clone)]
+pub struct StatusChange {
+    pub beatmapset_id: i64,
+    pub title: String,
+    pub artist: String,
+    pub old_status: Option<String>,
+    pub new_status: String,
+    pub changed_at: i64,
+}
+
+// digest
+impl DBHandler {
+    // since(unix time)以降のstatusの変化(古い順)
+    pub async fn status_changes_since(&self, since: i64) -> Result<Vec<StatusChange>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let changes = sqlx::query_as!(StatusChange, r#"
+            SELECT h.beatmapset_id, s.title, s.artist, h.old_status, h.new_status, h.changed_at
+            FROM beatmapset_status_history h JOIN beatmapsets s ON s.id = h.beatmapset_id
+            WHERE h.changed_at >= ?
+            ORDER BY h.changed_at, h.id"#,
+            since
+        ).fetch_all(&*db).await?;
+        Ok(changes)
+    }
+}
+
+// スケジューラ
+impl DBHandler {
+    pub async fn job_last_run(&self, name: &str) -> Result<Option<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!("SELECT last_run FROM scheduler_jobs WHERE name = ?", name)
+            .fetch_optional(&*db).await?;
+        Ok(row.map(|r| r.last_run))
+    }
+
+    pub async fn set_job_last_run(&self, name: &str, last_run: i64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        sqlx::query!(r#"
+            INSERT INTO scheduler_jobs (name, last_run) VALUES (?, ?)
+            ON CONFLICT(name) DO UPDATE SET last_run = excluded.last_run"#,
+            name, last_run
+        ).execute(&*db).await?;
+        Ok(())
+    }
+
+    pub async fn get_download(&self, beatmapset_id: i64) -> Result<Option<Download>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let download = sqlx::query_as!(Download, "SELECT * FROM downloads WHERE beatmapset_id = ?", beatmapset_id)
+            .fetch_optional(&*db).await?;
+        Ok(download)
+    }
+
+    pub async fn insert_download(&self, d: &Download) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        sqlx::query!(r#"
+            INSERT INTO downloads (beatmapset_id, path, size, sha256, downloaded_at) VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(beatmapset_id) DO UPDATE SET
+                path = excluded.path, size = excluded.size, sha256 = excluded.sha256, downloaded_at = excluded.downloaded_at"#,
+            d.beatmapset_id, d.path, d.size, d.sha256, d.downloaded_at
+        ).execute(&*db).await?;
+        Ok(())
+    }
+
+    // ダウンロードを待ちに入れる(既に待ちかダウンロード中なら何もしない，失敗していたらやり直す)
+    pub async fn enqueue_download(&self, id: i64, title: &str, status: &str, now: i64) -> Result<bool, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let res = sqlx::query!(r#"
+            INSERT INTO download_jobs (beatmapset_id, title, status, state, attempts, next_try, created_at)
+            VALUES (?, ?, ?, 'queued', 0, ?, ?)
+            ON CONFLICT(beatmapset_id) DO UPDATE SET
+                title = excluded.title, status = excluded.status, state = 'queued', attempts = 0,
+                last_error = NULL, next_try = excluded.next_try
+            WHERE state = 'failed'"#,
+            id, title, status, now, now
+        ).execute(&*db).await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    // 次にダウンロードするもの(next_tryを過ぎたqueuedの中で古い順)をactiveにして返す
+    pub async fn claim_download_job(&self, now: i64) -> Result<Option<DownloadJob>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        let job = sqlx::query_as!(DownloadJob, r#"
+            SELECT beatmapset_id as "beatmapset_id!", title as "title!", status as "status!", state as "state!",
+                attempts as "attempts!", last_error, next_try as "next_try!", created_at as "created_at!"
+            FROM download_jobs WHERE state = 'queued' AND next_try <= ?
+            ORDER BY created_at, beatmapset_id LIMIT 1"#,
+            now
+        ).fetch_optional(&mut tx).await?;
+        if let Some(j) = &job {
+            sqlx::query!("UPDATE download_jobs SET state = 'active' WHERE beatmapset_id = ?", j.beatmapset_id)
+                .execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(job)
+    }
+
+    // 終わったjobを消す
+    pub async fn finish_download_job(&self, id: i64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        sqlx::query!("DELETE FROM download_jobs WHERE beatmapset_id = ?", id)
+            .execute(&*db).await?;
+        Ok(())
+    }
+
+    // 失敗したjobをnext_tryに再試行する(give_upならfailedにする)
+    pub async fn fail_download_job(&self, id: i64, error: &str, next_try: i64, give_up: bool) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let state = if give_up { "failed" } else { "queued" };
+        sqlx::query!(r#"
+            UPDATE download_jobs SET state = ?, attempts = attempts + 1, last_error = ?, next_try = ?
+            WHERE beatmapset_id = ?"#,
+            state, error, next_try, id
+        ).execute(&*db).await?;
+        Ok(())
+    }
+
+    // 起動時: 前回ダウンロード中だったものを待ちに戻す
+    pub async fn requeue_active_downloads(&self) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let res = sqlx::query!("UPDATE download_jobs SET state = 'queued' WHERE state = 'active'")
+            .execute(&*db).await?;
+        Ok(res.rows_affected())
+    }
+
+    // failedを全て待ちに戻す
+    pub async fn retry_failed_downloads(&self, now: i64) -> Result<u64, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let res = sqlx::query!(
+            "UPDATE download_jobs SET state = 'queued', attempts = 0, next_try = ? WHERE state = 'failed'",
+            now
+        ).execute(&*db).await?;
+        Ok(res.rows_affected())
+    }
+
+    pub async fn download_jobs(&self) -> Result<Vec<DownloadJob>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let jobs = sqlx::query_as!(DownloadJob, "SELECT * FROM download_jobs ORDER BY created_at, beatmapset_id")
+            .fetch_all(&*db).await?;
+        Ok(jobs)
+    }
+
+    // since以降にuser_idがファイルサーバーからダウンロードした譜面(重複なし)
+    pub async fn recent_file_downloads(&self, user_id: i64, since: i64) -> Result<Vec<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let rows = sqlx::query!(
+            "SELECT DISTINCT beatmapset_id FROM file_downloads WHERE user_id = ? AND downloaded_at >= ?",
+            user_id, since
+        ).fetch_all(&*db).await?;
+        Ok(rows.into_iter().map(|r| r.beatmapset_id).collect())
+    }
+
+    // since以降のダウンロード数がquota未満なら記録する(数えるのと記録するのを1つのtransactionで行う)
+    // since以降に同じ譜面をダウンロードしていれば記録せずに数えない
+    // (since以降のダウンロード数, 記録したか)，quotaを超えていればNone
+    pub async fn add_file_download(&self, user_id: i64, beatmapset_id: i64, since: i64, quota: i64, now: i64) -> Result<Option<(i64, bool)>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        let counted = sqlx::query!(
+            "SELECT COUNT(*) AS count FROM file_downloads WHERE user_id = ? AND beatmapset_id = ? AND downloaded_at >= ?",
+            user_id, beatmapset_id, since
+        ).fetch_one(&mut tx).await?.count > 0;
+        let used = sqlx::query!(
+            "SELECT COUNT(DISTINCT beatmapset_id) AS count FROM file_downloads WHERE user_id = ? AND downloaded_at >= ?",
+            user_id, since
+        ).fetch_one(&mut tx).await?.count as i64;
+        if counted {
+            return Ok(Some((used, false)));
+        }
+        if used >= quota {
+            return Ok(None);
         }
+        sqlx::query!(
+            "INSERT INTO file_downloads (user_id, beatmapset_id, downloaded_at) VALUES (?, ?, ?)",
+            user_id, beatmapset_id, now
+        ).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(Some((used + 1, true)))
     }
-}
\ No newline at end of file
+
+    // .osuにBeatmapIDが無いときは難易度名で探す
+    pub async fn beatmap_id_by_version(&self, beatmapset_id: i64, version: &str) -> Result<Option<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!(
+            "SELECT id FROM beatmaps WHERE beatmapset_id = ? AND version = ?",
+            beatmapset_id, version
+        ).fetch_optional(&*db).await?;
+        Ok(row.map(|r| r.id))
+    }
+
+    // 難易度のidからbeatmapsetのidを探す
+    pub async fn beatmapset_id_of(&self, beatmap_id: i64) -> Result<Option<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let row = sqlx::query!(
+            "SELECT beatmapset_id FROM beatmaps WHERE id = ?",
+            beatmap_id
+        ).fetch_optional(&*db).await?;
+        Ok(row.map(|r| r.beatmapset_id))
+    }
+
+    // DB全体をpathにコピーする(書き込み中でも一貫したコピーになる)
+    pub async fn vacuum_into(&self, path: &str) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        sqlx::query("VACUUM INTO ?").bind(path).execute(&*db).await?;
+        Ok(())
+    }
+}
+
+// 配置の分析
+impl DBHandler {
+    // beatmapsetの分析結果を全て入れ替え，分析済みにする(patternsが空でも)
+    pub async fn replace_patterns(&self, beatmapset_id: i64, patterns: &[Pattern]) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        let now = utility::unix_now();
+        sqlx::query!("DELETE FROM patterns WHERE beatmapset_id = ?", beatmapset_id)
+            .execute(&mut tx).await?;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO pattern_analyses (beatmapset_id, analyzed_at) VALUES (?, ?)",
+            beatmapset_id, now
+        ).execute(&mut tx).await?;
+        for p in patterns {
+            sqlx::query!(r#"
+            INSERT OR REPLACE INTO patterns
+            (beatmap_id, beatmapset_id, category, ln_ratio, jack_density, chordjack_ratio, stream_ratio, peak_nps, ss_pp, analyzed_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"#,
+            p.beatmap_id, p.beatmapset_id, p.category, p.ln_ratio, p.jack_density, p.chordjack_ratio, p.stream_ratio, p.peak_nps, p.ss_pp, now
+            ).execute(&mut tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // 分析していなければNone
+    pub async fn get_patterns(&self, beatmapset_id: i64) -> Result<Option<Vec<Pattern>>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut conn = db.acquire().await?;
+        fetch_patterns(&mut conn, beatmapset_id).await
+    }
+
+    // ダウンロード済みで，maniaの難易度があるのに分析していないbeatmapset
+    pub async fn unanalyzed_downloads(&self) -> Result<Vec<i64>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let rows = sqlx::query!(r#"
+            SELECT d.beatmapset_id FROM downloads d
+            WHERE EXISTS (SELECT 1 FROM beatmaps b WHERE b.beatmapset_id = d.beatmapset_id AND b.mode = 'mania')
+                AND NOT EXISTS (SELECT 1 FROM pattern_analyses a WHERE a.beatmapset_id = d.beatmapset_id)
+            ORDER BY d.downloaded_at"#
+        ).fetch_all(&*db).await?;
+        Ok(rows.into_iter().map(|r| r.beatmapset_id).collect())
+    }
+
+    // まだ分析していなければ分析後に送るように入れてNone，分析済みなら入れずに分析結果を返す
+    // 確認と追加を同じtransactionで行い，その間に分析が終わって取り出されることがないようにする
+    pub async fn add_pattern_pending(&self, subscription_id: i64, beatmapset_id: i64, old_status: Option<&str>) -> Result<Option<Vec<Pattern>>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        if let Some(patterns) = fetch_patterns(&mut tx, beatmapset_id).await? {
+            return Ok(Some(patterns));
+        }
+        let now = utility::unix_now();
+        sqlx::query!(
+            "INSERT INTO pattern_pending (subscription_id, beatmapset_id, old_status, created_at) VALUES (?, ?, ?, ?)",
+            subscription_id, beatmapset_id, old_status, now
+        ).execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(None)
+    }
+
+    // beatmapsetの分析を待っていたものを取り出して消す
+    // before(unix time)より古いものは(ダウンロードに失敗し続けているなど)送らずに消し，その数も返す
+    pub async fn take_pattern_pending(&self, beatmapset_id: i64, before: i64) -> Result<(Vec<PatternPending>, u64), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        let expired = sqlx::query!("DELETE FROM pattern_pending WHERE created_at < ?", before)
+            .execute(&mut tx).await?
+            .rows_affected();
+        let pending = sqlx::query_as!(PatternPending,
+            "SELECT id, subscription_id, beatmapset_id, old_status FROM pattern_pending WHERE beatmapset_id = ? ORDER BY id",
+            beatmapset_id
+        ).fetch_all(&mut tx).await?;
+        sqlx::query!("DELETE FROM pattern_pending WHERE beatmapset_id = ?", beatmapset_id)
+            .execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok((pending, expired))
+    }
+
+    // ダウンロード前に送った通知を覚えておく
+    pub async fn add_event_message(&self, beatmapset_id: i64, channel_id: i64, message_id: i64) -> Result<(), Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let now = utility::unix_now();
+        sqlx::query!(
+            "INSERT INTO event_messages (beatmapset_id, channel_id, message_id, created_at) VALUES (?, ?, ?, ?)",
+            beatmapset_id, channel_id, message_id, now
+        ).execute(&*db).await?;
+        Ok(())
+    }
+
+    // beatmapsetの通知を(channel_id, message_id)で取り出して消す
+    // before(unix time)より古いものは編集せずに消す
+    pub async fn take_event_messages(&self, beatmapset_id: i64, before: i64) -> Result<Vec<(i64, i64)>, Box<dyn Error + Sync + Send>> {
+        let db = self.db.lock().await;
+        let mut tx = db.begin().await?;
+        sqlx::query!("DELETE FROM event_messages WHERE created_at < ?", before)
+            .execute(&mut tx).await?;
+        let rows = sqlx::query!(
+            "SELECT channel_id, message_id FROM event_messages WHERE beatmapset_id = ? ORDER BY id",
+            beatmapset_id
+        ).fetch_all(&mut tx).await?;
+        sqlx::query!("DELETE FROM event_messages WHERE beatmapset_id = ?", beatmapset_id)
+            .execute(&mut tx).await?;
+        tx.commit().await?;
+        Ok(rows.into_iter().map(|r| (r.channel_id, r.message_id)).collect())
+    }
+}
+
+// 分析していなければNone(transactionの中でも使う)
+async fn fetch_patterns(conn: &mut sqlx::SqliteConnection, beatmapset_id: i64) -> Result<Option<Vec<Pattern>>, Box<dyn Error + Sync + Send>> {
+    let analyzed = sqlx::query!("SELECT beatmapset_id FROM pattern_analyses WHERE beatmapset_id = ?", beatmapset_id)
+        .fetch_optional(&mut *conn).await?;
+    if analyzed.is_none() {
+        return Ok(None);
+    }
+    let patterns = sqlx::query_as!(Pattern, r#"
+        SELECT beatmap_id, beatmapset_id, category, ln_ratio, jack_density, chordjack_ratio, stream_ratio, peak_nps, ss_pp
+        FROM patterns WHERE beatmapset_id = ?"#,
+        beatmapset_id
+    ).fetch_all(&mut *conn).await?;
+    Ok(Some(patterns))
+}
+
+// 各beatmapsetに難易度を付けてBeatmapにする
+async fn with_difficulties(db: &sqlx::SqlitePool, rows: Vec<BeatmapsetRow>) -> Result<Vec<Beatmap>, Box<dyn Error + Sync + Send>> {
+    let mut beatmapsets = Vec::new();
+    for row in rows {
+        let difficulties = sqlx::query_as!(Difficulty, r#"
+            SELECT id, beatmapset_id, mode as "mode: GameMode", version, difficulty_rating, keys,
+                cs, ar, od, hp, bpm, total_length, count_notes, count_lns
+            FROM beatmaps WHERE beatmapset_id = ? ORDER BY mode, keys, difficulty_rating"#,
+            row.id
+        ).fetch_all(db).await?;
+        beatmapsets.push(row.into_beatmap(difficulties));
+    }
+    Ok(beatmapsets)
+}
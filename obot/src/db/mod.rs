@@ -1 +1,4 @@
-pub mod handler;
\ No newline at end of file
+pub mod backup;
+pub mod handler;
+pub mod search;
+pub mod subscription;
@@ -0,0 +1,272 @@
+// /searchの検索文字列
+// "camellia status:ranked keys:7 stars>5.2 creator:xxx" のように，フィルタ以外の語は全文検索(title, artist, creator, tags, 難易度名)に使う
+// pattern:, ln>, nps>は配置の分析結果(ダウンロードして分析した譜面のみ)
+use crate::osu::pattern::CATEGORIES;
+use crate::web::mode::GameMode;
+use super::subscription::STATUSES;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchQuery {
+    pub terms: Vec<String>,
+    pub status: Option<String>,
+    pub mode: Option<GameMode>,
+    pub keys: Option<i64>,
+    pub stars: Range,
+    pub creator: Option<String>,
+    pub pattern: Option<String>,
+    pub ln: Range, // 0.0 ~ 1.0
+    pub nps: Range,
+}
+
+impl SearchQuery {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let mut q = SearchQuery::default();
+        for word in split_words(input) {
+            let lower = word.to_lowercase();
+            if let Some(range) = parse_comparison(&lower, "stars", &word, 1.0)? {
+                q.stars = q.stars.merge(range);
+                continue;
+            }
+            // LNの割合は%で書く
+            if let Some(range) = parse_comparison(&lower, "ln", &word, 100.0)? {
+                q.ln = q.ln.merge(range);
+                q.mode = Some(GameMode::Mania);
+                continue;
+            }
+            if let Some(range) = parse_comparison(&lower, "nps", &word, 1.0)? {
+                q.nps = q.nps.merge(range);
+                q.mode = Some(GameMode::Mania);
+                continue;
+            }
+            let (key, value) = match word.split_once(':') {
+                Some((k, v)) if !v.is_empty() => (k.to_lowercase(), v.to_string()),
+                _ => {
+                    q.terms.push(word);
+                    continue;
+                }
+            };
+            match key.as_str() {
+                "status" => {
+                    let v = value.to_lowercase();
+                    if !STATUSES.contains(&v.as_str()) {
+                        return Err(format!("Invalid status: {} (ranked, loved, qualified)", value));
+                    }
+                    q.status = Some(v);
+                },
+                "mode" => q.mode = Some(GameMode::from_name(&value).ok_or_else(|| format!("Invalid mode: {}", value))?),
+                "keys" | "key" | "k" => {
+                    let k = value.trim_end_matches('k').parse::<i64>().map_err(|_| format!("Invalid keys: {}", value))?;
+                    q.keys = Some(k);
+                    // キー数はmaniaのみ
+                    q.mode = Some(GameMode::Mania);
+                },
+                "creator" | "mapper" => q.creator = Some(value),
+                "pattern" => {
+                    let v = value.to_lowercase();
+                    if !CATEGORIES.contains(&v.as_str()) {
+                        return Err(format!("Invalid pattern: {} ({})", value, CATEGORIES.join(", ")));
+                    }
+                    q.pattern = Some(v);
+                    q.mode = Some(GameMode::Mania);
+                },
+                // フィルタでなければ普通の語("re:zero"など)
+                _ => q.terms.push(word),
+            }
+        }
+        Ok(q)
+    }
+
+    // FTS5のMATCHに渡す文字列(語がなければNone)
+    // 各語は"..."で囲み(記号をそのまま検索する)，前方一致にする
+    pub fn fts_query(&self) -> Option<String> {
+        if self.terms.is_empty() {
+            return None;
+        }
+        let q = self.terms.iter()
+            .map(|t| format!("\"{}\"*", t.replace('"', "\"\"")))
+            .collect::<Vec<String>>()
+            .join(" ");
+        Some(q)
+    }
+
+    // 難易度の条件があるか
+    pub fn has_difficulty_filter(&self) -> bool {
+        self.mode.is_some() || self.keys.is_some() || self.stars.is_set()
+            || self.pattern.is_some() || self.ln.is_set() || self.nps.is_set()
+    }
+}
+
+// 下限と上限．(値, 等号を含むか)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Range {
+    pub min: Option<(f64, bool)>,
+    pub max: Option<(f64, bool)>,
+}
+
+impl Range {
+    pub fn is_set(&self) -> bool {
+        self.min.is_some() || self.max.is_some()
+    }
+
+    // 後に書いた方を優先する
+    fn merge(self, other: Range) -> Range {
+        Range { min: other.min.or(self.min), max: other.max.or(self.max) }
+    }
+}
+
+// "stars>5.2", "ln<=30", "nps:20" => Range．nameで始まらなければNone
+// "="と":"は書いた桁までが一致する範囲("5.2" => 5.2以上5.3未満，"30" => 30以上31未満)
+// 値はscaleで割る(%で書くLNの割合は100)
+fn parse_comparison(lower: &str, name: &str, word: &str, scale: f64) -> Result<Option<Range>, String> {
+    let v = match lower.strip_prefix(name).filter(|v| v.starts_with(['>', '<', '=', ':'])) {
+        Some(v) => v,
+        None => return Ok(None),
+    };
+    let (op, value) = match v.find(|c: char| c.is_ascii_digit() || c == '.') {
+        Some(i) => v.split_at(i),
+        None => return Err(format!("Invalid {} filter: {} (e.g. {}>5)", name, word, name)),
+    };
+    let digits = 10_f64.powi(value.split_once('.').map_or(0, |(_, d)| d.len()) as i32);
+    let value = value.parse::<f64>().map_err(|_| format!("Invalid {} filter: {}", name, word))?;
+    let bound = |v: f64, inclusive: bool| Some((v / scale, inclusive));
+    match op {
+        ">" => Ok(Some(Range { min: bound(value, false), max: None })),
+        ">=" => Ok(Some(Range { min: bound(value, true), max: None })),
+        "<" => Ok(Some(Range { min: None, max: bound(value, false) })),
+        "<=" => Ok(Some(Range { min: None, max: bound(value, true) })),
+        // 上限は最後の桁を1つ上げた値(5.2 + 0.1だと5.300000000000001になるので整数で足す)
+        "=" | ":" => Ok(Some(Range { min: bound(value, true), max: bound(((value * digits).round() + 1.0) / digits, false) })),
+        _ => Err(format!("Invalid {} filter: {} (>, >=, <, <=, =)", name, word)),
+    }
+}
+
+// 空白で区切る("..."の中の空白は区切らない)
+fn split_words(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+    for c in input.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            },
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Bound = Option<(f64, bool)>;
+
+    fn close(range: Range, min: Bound, max: Bound) -> bool {
+        let eq = |a: Bound, b: Bound| match (a, b) {
+            (Some(a), Some(b)) => (a.0 - b.0).abs() < 1e-9 && a.1 == b.1,
+            (a, b) => a.is_none() && b.is_none(),
+        };
+        eq(range.min, min) && eq(range.max, max)
+    }
+
+    // SQLの比較と同じ
+    fn contains(range: Range, v: f64) -> bool {
+        range.min.map_or(true, |(m, inclusive)| if inclusive { v >= m } else { v > m })
+            && range.max.map_or(true, |(m, inclusive)| if inclusive { v <= m } else { v < m })
+    }
+
+    #[test]
+    fn parses_comparison() {
+        let p = |w: &str| parse_comparison(&w.to_lowercase(), "stars", w, 1.0).unwrap().unwrap();
+        assert!(close(p("stars>5.2"), Some((5.2, false)), None));
+        assert!(close(p("stars>=5"), Some((5.0, true)), None));
+        assert!(close(p("stars<3.5"), None, Some((3.5, false))));
+        assert!(close(p("stars<=.5"), None, Some((0.5, true))));
+        // 書いた桁までが一致する範囲
+        assert!(close(p("stars=5.2"), Some((5.2, true)), Some((5.3, false))));
+        assert!(close(p("stars:5.25"), Some((5.25, true)), Some((5.26, false))));
+        assert!(close(p("Stars=5"), Some((5.0, true)), Some((6.0, false))));
+    }
+
+    #[test]
+    fn compares_strictly() {
+        let p = |w: &str| parse_comparison(w, "stars", w, 1.0).unwrap().unwrap();
+        assert!(!contains(p("stars>5.2"), 5.2));
+        assert!(contains(p("stars>=5.2"), 5.2));
+        assert!(!contains(p("stars<5.2"), 5.2));
+        assert!(contains(p("stars<=5.2"), 5.2));
+        // 上限は含まない
+        assert!(contains(p("stars=5.2"), 5.2));
+        assert!(contains(p("stars=5.2"), 5.29));
+        assert!(!contains(p("stars=5.2"), 5.3));
+        assert!(!contains(p("stars=5"), 6.0));
+    }
+
+    #[test]
+    fn scales_comparison() {
+        let p = |w: &str| parse_comparison(w, "ln", w, 100.0).unwrap().unwrap();
+        assert!(close(p("ln:30"), Some((0.30, true)), Some((0.31, false))));
+        assert!(close(p("ln>=50"), Some((0.5, true)), None));
+        assert!(close(p("ln<12.5"), None, Some((0.125, false))));
+        assert!(!contains(p("ln:30"), 0.31));
+    }
+
+    #[test]
+    fn rejects_bad_comparison() {
+        assert_eq!(parse_comparison("starsfoo", "stars", "starsfoo", 1.0), Ok(None));
+        assert_eq!(parse_comparison("camellia", "stars", "camellia", 1.0), Ok(None));
+        assert!(parse_comparison("stars>", "stars", "stars>", 1.0).is_err());
+        assert!(parse_comparison("stars>5.2.1", "stars", "stars>5.2.1", 1.0).is_err());
+        assert!(parse_comparison("stars=>5", "stars", "stars=>5", 1.0).is_err());
+        assert!(parse_comparison("stars<>5", "stars", "stars<>5", 1.0).is_err());
+    }
+
+    #[test]
+    fn parses_query() {
+        let q = SearchQuery::parse(r#"camellia "re:zero" status:Ranked keys:7k stars>5.2 stars<6 creator:Xxx"#).unwrap();
+        assert_eq!(q.terms, vec!["camellia", "re:zero"]);
+        assert_eq!(q.status.as_deref(), Some("ranked"));
+        assert_eq!(q.keys, Some(7));
+        assert_eq!(q.mode, Some(GameMode::Mania));
+        assert_eq!(q.stars, Range { min: Some((5.2, false)), max: Some((6.0, false)) });
+        assert_eq!(q.creator.as_deref(), Some("Xxx"));
+        assert!(q.has_difficulty_filter());
+        assert_eq!(q.fts_query().as_deref(), Some(r#""camellia"* "re:zero"*"#));
+    }
+
+    #[test]
+    fn parses_pattern_filters() {
+        let q = SearchQuery::parse("pattern:JACK ln:30 nps>20").unwrap();
+        assert_eq!(q.pattern.as_deref(), Some("jack"));
+        assert_eq!(q.mode, Some(GameMode::Mania));
+        assert!(close(q.ln, Some((0.30, true)), Some((0.31, false))));
+        assert_eq!(q.nps, Range { min: Some((20.0, false)), max: None });
+        assert!(q.terms.is_empty());
+        assert_eq!(q.fts_query(), None);
+    }
+
+    #[test]
+    fn keeps_unknown_keys_as_terms() {
+        let q = SearchQuery::parse(r#"mode:taiko foo:bar say"hi" trailing:"#).unwrap();
+        assert_eq!(q.mode, Some(GameMode::Taiko));
+        assert_eq!(q.terms, vec!["foo:bar", "sayhi", "trailing:"]);
+        assert_eq!(q.fts_query().as_deref(), Some(r#""foo:bar"* "sayhi"* "trailing:"*"#));
+        assert_eq!(SearchQuery::parse("").unwrap(), SearchQuery::default());
+    }
+
+    #[test]
+    fn rejects_invalid_filters() {
+        assert!(SearchQuery::parse("status:pending").is_err());
+        assert!(SearchQuery::parse("mode:drums").is_err());
+        assert!(SearchQuery::parse("keys:abc").is_err());
+        assert!(SearchQuery::parse("pattern:trill").is_err());
+        assert!(SearchQuery::parse("stars>abc").is_err());
+    }
+}
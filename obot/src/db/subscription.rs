@@ -0,0 +1,246 @@
+
+// チャンネルごとの通知設定
+// statuses, keys, modesはカンマ区切り(keysが空なら全てのキー数，modesが空なら全てのmode)
+// keysはmaniaの難易度にだけ効く
+// digestがあれば，その間隔(cron式)ごとにまとめて送る
+// patternがあれば，その配置に分類された(ダウンロードして分析した)難易度がある譜面だけを送る
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serenity::{model::prelude::*, prelude::*};
+
+use crate::config;
+use crate::db::handler::DBHandler;
+use crate::osu::pattern::Pattern;
+use crate::scheduler::Schedule;
+use crate::web::{api::{Beatmap, Difficulty}, mode::GameMode};
+
+pub const STATUSES: [&str; 3] = ["ranked", "loved", "qualified"];
+// digestの略称(UTC)．weeklyは月曜(cronクレートでは曜日の1が日曜なので名前で書く)
+pub const DIGEST_PRESETS: [(&str, &str); 2] = [("daily", "0 0 * * *"), ("weekly", "0 0 * * Mon")];
+
+#[derive(Debug, Clone)]
+pub struct Subscription {
+    pub id: i64,
+    pub guild_id: i64,
+    pub channel_id: i64,
+    pub statuses: String,
+    pub keys: String,
+    pub min_stars: Option<f64>,
+    pub max_stars: Option<f64>,
+    pub created_at: i64,
+    pub modes: String,
+    pub digest: Option<String>,
+    pub last_digest: Option<i64>,
+    pub pattern: Option<String>,
+}
+
+impl Subscription {
+    pub fn status_list(&self) -> Vec<&str> {
+        self.statuses.split(',').filter(|s| !s.is_empty()).collect()
+    }
+
+    pub fn key_list(&self) -> Vec<i64> {
+        self.keys.split(',').filter_map(|k| k.parse::<i64>().ok()).collect()
+    }
+
+    pub fn mode_list(&self) -> Vec<GameMode> {
+        self.modes.split(',').filter_map(GameMode::from_name).collect()
+    }
+
+    // statusが一致し，条件(mode，キー数，星の範囲)に合う難易度が1つでもあれば通知する
+    pub fn matches(&self, beatmapset: &Beatmap, status: &str) -> bool {
+        self.status_list().contains(&status)
+            && beatmapset.difficulties.iter().any(|d| self.difficulty_matches(d))
+    }
+
+    pub fn difficulty_matches(&self, d: &Difficulty) -> bool {
+        let modes = self.mode_list();
+        let keys = self.key_list();
+        (modes.is_empty() || modes.contains(&d.mode))
+            && (keys.is_empty() || !d.mode.has_keys() || d.keys.is_some_and(|k| keys.contains(&k)))
+            && self.min_stars.map_or(true, |m| d.difficulty_rating >= m)
+            && self.max_stars.map_or(true, |m| d.difficulty_rating <= m)
+    }
+
+    // 条件に合う難易度の中に，patternに分類されたものがあるか
+    // patternsは難易度のid => 分析結果(まだ分析していなければNone)．分析を待つ必要があればNone
+    pub fn pattern_matches(&self, beatmapset: &Beatmap, patterns: Option<&HashMap<i64, Pattern>>) -> Option<bool> {
+        let pattern = match &self.pattern {
+            Some(p) => p,
+            None => return Some(true),
+        };
+        // 配置はmaniaの難易度にしかない
+        let candidates = beatmapset.difficulties.iter()
+            .filter(|d| d.mode == GameMode::Mania && self.difficulty_matches(d))
+            .collect::<Vec<&Difficulty>>();
+        if candidates.is_empty() {
+            return Some(false);
+        }
+        let patterns = patterns?;
+        Some(candidates.iter().any(|d| patterns.get(&d.id).is_some_and(|p| &p.category == pattern)))
+    }
+
+    // "osu!mania | ranked, loved | 4k, 7k | 3.00★ ~ 6.00★" のような説明
+    pub fn describe(&self) -> String {
+        let modes = match self.mode_list() {
+            m if m.is_empty() => "all modes".to_string(),
+            m => m.iter().map(|m| m.label()).collect::<Vec<&str>>().join(", "),
+        };
+        let keys = match self.key_list() {
+            k if k.is_empty() => "all keys".to_string(),
+            k => k.iter().map(|k| format!("{}k", k)).collect::<Vec<String>>().join(", "),
+        };
+        let stars = match (self.min_stars, self.max_stars) {
+            (None, None) => "all stars".to_string(),
+            (Some(min), None) => format!("{:.2}★ ~", min),
+            (None, Some(max)) => format!("~ {:.2}★", max),
+            (Some(min), Some(max)) => format!("{:.2}★ ~ {:.2}★", min, max),
+        };
+        let digest = match &self.digest {
+            Some(d) => format!(" | digest: {}", DIGEST_PRESETS.iter().find(|(_, e)| e == d).map_or(d.as_str(), |(n, _)| n)),
+            None => String::new(),
+        };
+        let pattern = match &self.pattern {
+            Some(p) => format!(" | pattern: {}", p),
+            None => String::new(),
+        };
+        format!("{} | {} | {} | {}{}{}", modes, self.status_list().join(", "), keys, stars, pattern, digest)
+    }
+
+    // digestを送る時刻になっているか
+    pub fn digest_due(&self, now: i64) -> bool {
+        let schedule = match self.digest.as_deref().map(Schedule::parse) {
+            Some(Ok(s)) => s,
+            Some(Err(e)) => {
+                warn!("Invalid digest schedule of subscription {}: {}", self.id, e);
+                return false;
+            }
+            None => return false,
+        };
+        let last = self.last_digest.unwrap_or(self.created_at);
+        schedule.next_after(last).is_some_and(|t| t <= now)
+    }
+}
+
+// 以前の.envのチャンネル設定 => (status, キー数)
+const LEGACY_CHANNELS: [(&str, &str, i64); 6] = [
+    ("DISCORD_4KMAP_RANKED_CHANNEL_ID", "ranked", 4),
+    ("DISCORD_4KMAP_LOVED_CHANNEL_ID", "loved", 4),
+    ("DISCORD_4KMAP_QUALIFIED_CHANNEL_ID", "qualified", 4),
+    ("DISCORD_7KMAP_RANKED_CHANNEL_ID", "ranked", 7),
+    ("DISCORD_7KMAP_LOVED_CHANNEL_ID", "loved", 7),
+    ("DISCORD_7KMAP_QUALIFIED_CHANNEL_ID", "qualified", 7),
+];
+
+// 以前のチャンネル設定(DISCORD_*KMAP_*_CHANNEL_ID)が残っていれば，subscriptionが1つも無いときだけそれを元に作る(maniaのみ)
+// 1チャンネル1つなので，同じチャンネルの設定はstatusとキー数をまとめる
+// 既にsubscriptionがあれば使わないことを警告する
+pub async fn seed_legacy(ctx: &Context) {
+    let dotenv = config::read_dotenv().unwrap_or_default();
+    let mut channels: BTreeMap<u64, (BTreeSet<&str>, BTreeSet<i64>)> = BTreeMap::new();
+    for (name, status, keys) in LEGACY_CHANNELS {
+        let value = match std::env::var(name).ok().or_else(|| dotenv.get(name).cloned()) {
+            Some(v) if !v.trim().is_empty() => v,
+            _ => continue,
+        };
+        match value.trim().parse::<u64>() {
+            Ok(id) => {
+                let (statuses, key_set) = channels.entry(id).or_default();
+                statuses.insert(status);
+                key_set.insert(keys);
+            },
+            Err(_) => warn!("Ignored {}: invalid channel id {}", name, value),
+        }
+    }
+    if channels.is_empty() {
+        return;
+    }
+
+    let db = DBHandler::new(ctx).await;
+    match db.all_subscriptions().await {
+        Ok(s) if s.is_empty() => {},
+        Ok(_) => {
+            warn!("DISCORD_*KMAP_*_CHANNEL_ID are no longer used (channels are set with /subscribe), remove them");
+            return;
+        },
+        Err(e) => {
+            error!("Failed to get subscriptions: {}", e);
+            return;
+        }
+    }
+    for (id, (statuses, keys)) in channels {
+        let guild_id = match ChannelId(id).to_channel(ctx).await {
+            Ok(Channel::Guild(c)) => c.guild_id,
+            Ok(_) => {
+                warn!("Skipped channel {} of the old settings (not a server channel)", id);
+                continue;
+            },
+            Err(e) => {
+                warn!("Skipped channel {} of the old settings: {}", id, e);
+                continue;
+            }
+        };
+        let sub = Subscription {
+            id: 0,
+            guild_id: guild_id.0 as i64,
+            channel_id: id as i64,
+            statuses: STATUSES.iter().filter(|s| statuses.contains(*s)).copied().collect::<Vec<&str>>().join(","),
+            keys: keys.iter().map(|k| k.to_string()).collect::<Vec<String>>().join(","),
+            min_stars: None,
+            max_stars: None,
+            created_at: 0,
+            modes: GameMode::Mania.as_str().to_string(),
+            digest: None,
+            last_digest: None,
+            pattern: None,
+        };
+        match db.upsert_subscription(&sub).await {
+            Ok(()) => info!("Subscribed channel {} from the old settings ({})", id, sub.describe()),
+            Err(e) => error!("Failed to subscribe channel {} from the old settings: {}", id, e),
+        }
+    }
+    warn!("Created subscriptions from DISCORD_*KMAP_*_CHANNEL_ID, these variables are no longer used, remove them");
+}
+
+// "off" => None，"daily"/"weekly"は対応するcron式，それ以外は間隔かcron式として確認する
+pub fn parse_digest(value: &str) -> Result<Option<String>, String> {
+    let value = value.trim();
+    if value.is_empty() || value == "off" {
+        return Ok(None);
+    }
+    if let Some((_, expr)) = DIGEST_PRESETS.iter().find(|(name, _)| *name == value) {
+        return Ok(Some(expr.to_string()));
+    }
+    Schedule::parse(value).map(|_| Some(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-03(水) 12:00 UTC
+    const WEDNESDAY_NOON: i64 = 1704283200;
+
+    fn next(digest: &str) -> i64 {
+        let expr = parse_digest(digest).unwrap().unwrap();
+        Schedule::parse(&expr).unwrap().next_after(WEDNESDAY_NOON).unwrap()
+    }
+
+    #[test]
+    fn weekly_runs_on_monday() {
+        // 2024-01-08(月) 00:00 UTC
+        assert_eq!(next("weekly"), 1704672000);
+        // 2024-01-04(木) 00:00 UTC
+        assert_eq!(next("daily"), 1704326400);
+    }
+
+    #[test]
+    fn parses_digest() {
+        assert_eq!(parse_digest(" off "), Ok(None));
+        assert_eq!(parse_digest(""), Ok(None));
+        assert_eq!(parse_digest("12h"), Ok(Some("12h".to_string())));
+        assert_eq!(parse_digest("0 12 * * Fri"), Ok(Some("0 12 * * Fri".to_string())));
+        assert!(parse_digest("sometimes").is_err());
+        assert!(parse_digest("0 99 * * *").is_err());
+    }
+}
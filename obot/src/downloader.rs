@@ -0,0 +1,202 @@
+use serenity::prelude::*;
+use std::{
+    collections::HashMap,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::Notify;
+
+use crate::cache::Downloads;
+use crate::config::Config;
+use crate::db::handler::{DBHandler, DownloadJob};
+use crate::utility::unix_now;
+use crate::web::{
+    api::{Api, Beatmap},
+    download::{self, DownloadOutcome, Progress},
+    handler as web_handler,
+};
+
+// 待ちがないときに次を確かめるまでの最大の時間(再試行の時刻を過ぎたものを拾う)
+const IDLE: Duration = Duration::from_secs(30);
+// 1回目の失敗から再試行までの時間(秒)．失敗するたびに倍にする
+const RETRY_BASE: i64 = 60;
+const RETRY_MAX: i64 = 6 * 60 * 60;
+
+// readyは再接続のたびに呼ばれるので，一度だけ起動する
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// ダウンロード待ちはDB(download_jobs)に入れておき，DOWNLOAD_WORKERS個のworkerが古い順に取り出す
+// ここにはダウンロード中のものの進み具合と，workerを起こすための通知だけを持つ
+#[derive(Debug, Default)]
+pub struct DownloadQueue {
+    notify: Notify,
+    active: Mutex<HashMap<i64, Arc<Progress>>>,
+}
+
+impl DownloadQueue {
+    // TypeMapに入っているqueueを取り出す
+    pub async fn shared(ctx: &Context) -> Result<Arc<Self>, Box<dyn Error + Send + Sync>> {
+        match ctx.data.read().await.get::<Downloads>() {
+            Some(queue) => Ok(queue.clone()),
+            None => Err("DownloadQueue is not found in TypeMap".into()),
+        }
+    }
+
+    // ダウンロード中のbeatmapset id => (ダウンロードしたバイト数, 全体のバイト数)
+    pub async fn progress(&self) -> HashMap<i64, (u64, u64)> {
+        self.active.lock().await.iter().map(|(id, p)| (*id, p.get())).collect()
+    }
+}
+
+// 譜面をダウンロード待ちに入れて，新しく入った数を返す
+pub async fn enqueue(ctx: &Context, maps: &[Beatmap]) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let db = DBHandler::new(ctx).await;
+    let now = unix_now();
+    let mut added = 0;
+    for map in maps {
+        if db.enqueue_download(map.id, &map.title, &map.status, now).await? {
+            added += 1;
+        }
+    }
+    if added > 0 {
+        DownloadQueue::shared(ctx).await?.notify.notify_waiters();
+    }
+    Ok(added)
+}
+
+// 諦めたダウンロードを全て待ちに戻す
+pub async fn retry_failed(ctx: &Context) -> Result<u64, Box<dyn Error + Send + Sync>> {
+    let db = DBHandler::new(ctx).await;
+    let n = db.retry_failed_downloads(unix_now()).await?;
+    if n > 0 {
+        DownloadQueue::shared(ctx).await?.notify.notify_waiters();
+    }
+    Ok(n)
+}
+
+// workerを起動する(2回目以降は何もしない)
+pub fn start(ctx: &Context) {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        info!("Download workers are already running");
+        return;
+    }
+    let ctx = Arc::new(ctx.clone());
+    tokio::spawn(async move {
+        // 前回ダウンロード中に止まったものは最初から(途中までのファイルがあれば続きから)
+        let db = DBHandler::new(&ctx).await;
+        match db.requeue_active_downloads().await {
+            Ok(0) => {},
+            Ok(n) => info!("Requeued {} interrupted downloads", n),
+            Err(e) => error!("Failed to requeue interrupted downloads: {}", e),
+        }
+        let workers = match Config::shared(&ctx).await {
+            Ok(c) => c.download_workers,
+            Err(e) => {
+                error!("Failed to get config: {}", e);
+                1
+            }
+        };
+        info!("Starting {} download workers", workers);
+        for _ in 0..workers {
+            tokio::spawn(worker(ctx.clone()));
+        }
+
+        // 配置の分析ができる前にダウンロードした譜面
+        match db.unanalyzed_downloads().await {
+            Ok(ids) if !ids.is_empty() => {
+                info!("Analyzing patterns of {} downloaded mapsets", ids.len());
+                for id in ids {
+                    analyze(&ctx, id).await;
+                }
+            },
+            Ok(_) => {},
+            Err(e) => error!("Failed to get unanalyzed downloads: {}", e),
+        }
+    });
+}
+
+async fn worker(ctx: Arc<Context>) {
+    let queue = match DownloadQueue::shared(&ctx).await {
+        Ok(q) => q,
+        Err(e) => {
+            error!("Failed to start download worker: {}", e);
+            return;
+        }
+    };
+    let db = DBHandler::new(&ctx).await;
+    loop {
+        let job = match db.claim_download_job(unix_now()).await {
+            Ok(Some(j)) => j,
+            Ok(None) => {
+                let _ = tokio::time::timeout(IDLE, queue.notify.notified()).await;
+                continue;
+            },
+            Err(e) => {
+                error!("Failed to get next download: {}", e);
+                tokio::time::sleep(IDLE).await;
+                continue;
+            }
+        };
+        run(&ctx, &db, &queue, &job).await;
+    }
+}
+
+async fn run(ctx: &Context, db: &DBHandler, queue: &DownloadQueue, job: &DownloadJob) {
+    let id = job.beatmapset_id;
+    let progress = Arc::new(Progress::default());
+    queue.active.lock().await.insert(id, progress.clone());
+    let res = download_job(ctx, db, job, &progress).await;
+    queue.active.lock().await.remove(&id);
+
+    match res {
+        Ok(DownloadOutcome::Downloaded(d)) => {
+            info!("Downloaded {} ({} bytes, sha256 {})", d.path, d.size, d.sha256);
+            analyze(ctx, id).await;
+        },
+        Ok(DownloadOutcome::Skipped(p)) => {
+            info!("{} is already downloaded", p.display());
+            analyze(ctx, id).await;
+        },
+        Err(e) => {
+            let attempts = job.attempts + 1;
+            let max = match Config::shared(ctx).await {
+                Ok(c) => c.download_max_attempts as i64,
+                Err(_) => 1,
+            };
+            let give_up = attempts >= max;
+            let delay = RETRY_BASE.saturating_mul(1 << (attempts - 1).min(20)).min(RETRY_MAX);
+            if give_up {
+                error!("Gave up downloading {} after {} attempts: {}", id, attempts, e);
+            } else {
+                warn!("Failed to download {} (attempt {}/{}), retrying in {}s: {}", id, attempts, max, delay, e);
+            }
+            if let Err(e) = db.fail_download_job(id, &e.to_string(), unix_now() + delay, give_up).await {
+                error!("Failed to update download job {}: {}", id, e);
+            }
+            return;
+        }
+    }
+    if let Err(e) = db.finish_download_job(id).await {
+        error!("Failed to finish download job {}: {}", id, e);
+    }
+}
+
+// ダウンロードした譜面の配置を分析する(失敗してもダウンロードは終わったものとする)
+async fn analyze(ctx: &Context, id: i64) {
+    match web_handler::analyze_patterns(ctx, id).await {
+        Ok(0) => {},
+        Ok(n) => info!("Analyzed patterns of {} difficulties in {}", n, id),
+        Err(e) => warn!("Failed to analyze patterns of {}: {}", id, e),
+    }
+}
+
+async fn download_job(ctx: &Context, db: &DBHandler, job: &DownloadJob, progress: &Progress) -> Result<DownloadOutcome, Box<dyn Error + Send + Sync>> {
+    // reloadで変わっているかもしれないので毎回取り出す
+    let api = Api::shared(ctx).await?;
+    let dir = Config::shared(ctx).await?.map_dir();
+    download::download(&api, db, job, &dir, progress).await
+}
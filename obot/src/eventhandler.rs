@@ -1,35 +1,32 @@
-use std::{
-    sync::Arc,
-};
-
 use serenity::{
     async_trait,
-    model::{gateway::Ready, prelude::*},
-    framework::{
-        standard::{
-            macros::{hook},
-        },
+    model::{
+        gateway::Ready,
+        application::{command::Command, interaction::Interaction},
     },
     prelude::*,
 };
 
+use crate::commands;
+use crate::downloader;
+use crate::fileserver;
 use crate::scheduler;
-use crate::cache::{CommandCounter};
-use crate::utility;
+use crate::config::Config;
+use crate::db::subscription;
 
 pub struct Handler;
 #[async_trait]
 impl EventHandler for Handler {
     // This is called when the bot starts up.
     async fn ready(&self, ctx: Context, ready: Ready) {
-        let log_channel_id: ChannelId = match utility::get_env_from_context(&ctx, "log_channel").await.parse() {
-            Ok(v) => v,
+        let config = match Config::shared(&ctx).await {
+            Ok(c) => c,
             Err(e) => {
-                error!("Failed to parse log_channel: {}", e);
+                error!("Failed to get config: {}", e);
                 return;
             }
         };
-        match log_channel_id.send_message(&ctx.http, |m| {
+        match config.log_channel.send_message(&ctx.http, |m| {
             m.embed(|e| {
                 e.title("Bot started")
                     .description(format!("{} is now online!", ready.user.name))
@@ -40,47 +37,31 @@ impl EventHandler for Handler {
             Err(e) => error!("Failed to log bot start: {}", e),
         }
 
-        // Start the scheduler
-        let ctx_clone = Arc::new(ctx);
-        let _ = scheduler::ascheduler(ctx_clone).await;
-    }
-
-    async fn message(&self, ctx: Context, msg: Message) {
-        if msg.is_own(&ctx.cache) || msg.author.bot {
-            return;
-        }
-        let self_id = ctx.http.get_current_user().await.unwrap().id;
-        for mention in msg.mentions.iter() {
-            if mention.id == self_id {
-                let content = &msg.content;
-                println!("{}: {}", msg.author.name, content);
-            }
+        // slash commandの登録
+        // DISCORD_GUILD_IDがあればそのサーバにだけ登録する(すぐに反映されるので開発用)
+        let res = match config.guild_id {
+            Some(guild_id) => guild_id.set_application_commands(&ctx.http, commands::register).await,
+            None => Command::set_global_application_commands(&ctx.http, commands::register).await,
+        };
+        match res {
+            Ok(c) => info!("Registered {} application commands", c.len()),
+            Err(e) => error!("Failed to register application commands: {}", e),
         }
+
+        // 以前の.envのチャンネル設定があればsubscriptionにする(最初のpollより前に)
+        subscription::seed_legacy(&ctx).await;
+
+        // Start the scheduler (再接続でreadyが呼ばれても一度だけ)
+        scheduler::start(&ctx);
+        downloader::start(&ctx);
+        fileserver::start(&ctx);
     }
-}
 
-#[hook]
-pub async fn unknown_command(ctx: &Context, msg: &Message, unknown_command_name: &str) {
-    match msg.channel_id.say(&ctx.http, format!("Unknown command: '{}'. Try `/help`", unknown_command_name))
-        .await {
-        Ok(_) => info!("Sent unknown command message to {}", msg.author.name),
-        Err(e) => error!("Failed to send unknown command message to {}: {}", msg.author.name, e),
+    async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
+        match interaction {
+            Interaction::ApplicationCommand(command) => commands::dispatch(&ctx, &command).await,
+            Interaction::Autocomplete(autocomplete) => commands::autocomplete(&ctx, &autocomplete).await,
+            _ => {},
+        }
     }
 }
-
-// Command Counter
-#[hook]
-async fn before(ctx: &Context, _msg: &Message, command_name: &str) -> bool {
-    let mut data = ctx.data.write().await;
-    let counter = match data.get_mut::<CommandCounter>() {
-        Some(c) => c,
-        None => {
-            error!("Expected CommandCounter in TypeMap.");
-            return false;
-        },
-    };
-    let entry = counter.entry(command_name.to_string()).or_insert(0);
-    *entry += 1;
-
-    true
-}
\ No newline at end of file
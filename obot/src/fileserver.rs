@@ -0,0 +1,350 @@
+use hyper::{
+    body::Bytes,
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use hmac::{Hmac, Mac};
+use serenity::prelude::*;
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    error::Error,
+    io::SeekFrom,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::config::Config;
+use crate::db::handler::DBHandler;
+use crate::utility::unix_now;
+
+// ダウンロード数の制限を数える期間(秒)
+pub const QUOTA_PERIOD: i64 = 24 * 60 * 60;
+// ファイルを送るときに一度に読む大きさ
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// readyは再接続のたびに呼ばれるので，一度だけ起動する
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// ダウンロードした譜面(.osz)を署名付きのリンクで配るHTTPサーバー
+// GET(HEAD) /maps/{beatmapset id}?u={discord user id}&e={有効期限(unix time)}&s={署名}
+// FILE_SERVER_ADDRがなければ起動しない(2回目以降も何もしない)
+pub fn start(ctx: &Context) {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let ctx = Arc::new(ctx.clone());
+    tokio::spawn(async move {
+        let addr = match Config::shared(&ctx).await {
+            Ok(c) => match c.file_server_addr {
+                Some(a) => a,
+                None => {
+                    info!("File server is disabled (FILE_SERVER_ADDR is not set)");
+                    return;
+                }
+            },
+            Err(e) => {
+                error!("Failed to get config: {}", e);
+                return;
+            }
+        };
+        let make_service = make_service_fn(move |_| {
+            let ctx = ctx.clone();
+            async move { Ok::<_, Infallible>(service_fn(move |req| handle(ctx.clone(), req))) }
+        });
+        match Server::try_bind(&addr) {
+            Ok(builder) => {
+                info!("File server is listening on {}", addr);
+                if let Err(e) = builder.serve(make_service).await {
+                    error!("File server stopped: {}", e);
+                }
+            },
+            Err(e) => error!("Failed to start file server on {}: {}", addr, e),
+        }
+    });
+}
+
+// user_idにbeatmapset idの譜面を配るリンク(サーバーが無効ならNone)
+pub fn link(config: &Config, id: i64, user_id: u64) -> Option<(String, i64)> {
+    let (base, secret) = match (&config.file_server_url, &config.file_link_secret) {
+        (Some(b), Some(s)) if config.file_server_addr.is_some() => (b, s),
+        _ => return None,
+    };
+    let expires = unix_now() + config.file_link_ttl.as_secs() as i64;
+    let signature = sign(secret, &message(id, user_id, expires));
+    let url = format!("{}/maps/{}?u={}&e={}&s={}", base.as_str().trim_end_matches('/'), id, user_id, expires, signature);
+    Some((url, expires))
+}
+
+// 24時間以内にダウンロードした数と，その中にidが入っているか(入っていれば数に含めない)
+pub async fn quota_used(db: &DBHandler, user_id: u64, id: i64) -> Result<(usize, bool), Box<dyn Error + Send + Sync>> {
+    let recent = db.recent_file_downloads(user_id as i64, unix_now() - QUOTA_PERIOD).await?;
+    Ok((recent.len(), recent.contains(&id)))
+}
+
+fn message(id: i64, user_id: u64, expires: i64) -> String {
+    format!("{}:{}:{}", id, user_id, expires)
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &str, message: &str) -> HmacSha256 {
+    // HMACの鍵の長さに制限は無い
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(message.as_bytes());
+    mac
+}
+
+// HMAC-SHA256(16進数)
+fn sign(secret: &str, message: &str) -> String {
+    hex::encode(mac(secret, message).finalize().into_bytes())
+}
+
+// 比較はverify_sliceに任せる(一致した長さで時間が変わらない)
+fn verify(secret: &str, message: &str, signature: &str) -> bool {
+    match hex::decode(signature) {
+        Ok(s) => mac(secret, message).verify_slice(&s).is_ok(),
+        Err(_) => false,
+    }
+}
+
+async fn handle(ctx: Arc<Context>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    match serve(&ctx, &req).await {
+        Ok(res) => Ok(res),
+        Err(e) => {
+            error!("File server failed to handle {}: {}", req.uri().path(), e);
+            Ok(text(StatusCode::INTERNAL_SERVER_ERROR, "internal server error"))
+        }
+    }
+}
+
+async fn serve(ctx: &Context, req: &Request<Body>) -> Result<Response<Body>, Box<dyn Error + Send + Sync>> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return Ok(text(StatusCode::METHOD_NOT_ALLOWED, "method not allowed"));
+    }
+    let id = match req.uri().path().strip_prefix("/maps/").and_then(|id| id.parse::<i64>().ok()) {
+        Some(id) => id,
+        None => return Ok(text(StatusCode::NOT_FOUND, "not found")),
+    };
+    let query = url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .into_owned()
+        .collect::<HashMap<String, String>>();
+    let (user_id, expires, signature) = match (
+        query.get("u").and_then(|u| u.parse::<u64>().ok()),
+        query.get("e").and_then(|e| e.parse::<i64>().ok()),
+        query.get("s"),
+    ) {
+        (Some(u), Some(e), Some(s)) => (u, e, s),
+        _ => return Ok(text(StatusCode::FORBIDDEN, "invalid link")),
+    };
+
+    let config = Config::shared(ctx).await?;
+    let secret = match &config.file_link_secret {
+        Some(s) => s,
+        None => return Ok(text(StatusCode::SERVICE_UNAVAILABLE, "file server is not configured")),
+    };
+    if !verify(secret, &message(id, user_id, expires), signature) {
+        return Ok(text(StatusCode::FORBIDDEN, "invalid link"));
+    }
+    if expires < unix_now() {
+        return Ok(text(StatusCode::GONE, "this link has expired, use /getmap again"));
+    }
+
+    let db = DBHandler::new(ctx).await;
+    let path = match db.get_download(id).await? {
+        Some(d) if Path::new(&d.path).exists() => d.path,
+        _ => return Ok(text(StatusCode::NOT_FOUND, "this mapset is not downloaded")),
+    };
+    let (used, counted) = quota_used(&db, user_id, id).await?;
+    if !counted && used >= config.download_quota as usize {
+        return Ok(text(StatusCode::TOO_MANY_REQUESTS, "download quota exceeded, try again later"));
+    }
+
+    let mut file = File::open(&path).await?;
+    let size = file.metadata().await?.len();
+    // 読めないRangeは無視して全体を送る
+    let range = match req.headers().get(header::RANGE).and_then(|r| r.to_str().ok()).map(|r| parse_range(r, size)) {
+        Some(Range::Satisfiable(start, end)) => Some((start, end)),
+        Some(Range::Unsatisfiable) => {
+            return Ok(Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+                .body(Body::empty())?);
+        },
+        Some(Range::Invalid) | None => None,
+    };
+    let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+    let len = if size == 0 { 0 } else { end - start + 1 };
+
+    let name = Path::new(&path).file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_else(|| format!("{}.osz", id));
+    let mut res = Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-osu-beatmap-archive")
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::CONTENT_DISPOSITION, content_disposition(&name));
+    res = match range {
+        Some(_) => res.status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+        None => res.status(StatusCode::OK),
+    };
+    if req.method() == Method::HEAD {
+        return Ok(res.body(Body::empty())?);
+    }
+
+    // 同じ譜面を続きから(Range)ダウンロードしても1回と数える
+    // 同時のリクエストでquotaを超えないように，数えるのと記録するのは同時に行う
+    let now = unix_now();
+    match db.add_file_download(user_id as i64, id, now - QUOTA_PERIOD, config.download_quota as i64, now).await? {
+        Some((used, true)) => info!("Serving {} to user {} ({}/{} in 24h)", name, user_id, used, config.download_quota),
+        Some((_, false)) => {},
+        None => return Ok(text(StatusCode::TOO_MANY_REQUESTS, "download quota exceeded, try again later")),
+    }
+    file.seek(SeekFrom::Start(start)).await?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut left = len;
+        let mut buf = vec![0; CHUNK_SIZE];
+        while left > 0 {
+            let n = match file.read(&mut buf[..(left as usize).min(CHUNK_SIZE)]).await {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Failed to read {}: {}", path, e);
+                    sender.abort();
+                    return;
+                }
+            };
+            // 相手が切断した
+            if sender.send_data(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                return;
+            }
+            left -= n as u64;
+        }
+    });
+    Ok(res.body(body)?)
+}
+
+#[derive(Debug, PartialEq)]
+enum Range {
+    // (start, end)(endを含む)
+    Satisfiable(u64, u64),
+    // 正しいがファイルの範囲外(416)
+    Unsatisfiable,
+    // 読めない，または対応していない(無視して全体を送る)
+    Invalid,
+}
+
+// "bytes=0-99", "bytes=100-", "bytes=-100"
+// 複数の範囲("bytes=0-1,5-6")は対応していないのでInvalid
+fn parse_range(value: &str, size: u64) -> Range {
+    let spec = match value.trim().strip_prefix("bytes=") {
+        Some(s) if !s.contains(',') => s,
+        _ => return Range::Invalid,
+    };
+    let (start, end) = match spec.split_once('-') {
+        Some((s, e)) => (s.trim(), e.trim()),
+        None => return Range::Invalid,
+    };
+    let number = |s: &str| if s.bytes().all(|b| b.is_ascii_digit()) { s.parse::<u64>().ok() } else { None };
+    match (start, end) {
+        ("", "") => Range::Invalid,
+        ("", suffix) => match number(suffix) {
+            None => Range::Invalid,
+            // 長さ0のsuffixや空のファイルは返せない
+            Some(0) => Range::Unsatisfiable,
+            Some(_) if size == 0 => Range::Unsatisfiable,
+            Some(n) => Range::Satisfiable(size - n.min(size), size - 1),
+        },
+        (start, "") => match number(start) {
+            None => Range::Invalid,
+            Some(s) if s >= size => Range::Unsatisfiable,
+            Some(s) => Range::Satisfiable(s, size - 1),
+        },
+        (start, end) => match (number(start), number(end)) {
+            (Some(s), Some(e)) if s <= e => {
+                if s >= size { Range::Unsatisfiable } else { Range::Satisfiable(s, e.min(size - 1)) }
+            },
+            // "bytes=5-3"など
+            _ => Range::Invalid,
+        },
+    }
+}
+
+// ASCII以外の文字を含む名前はfilename*で送る
+fn content_disposition(name: &str) -> String {
+    let ascii = name.chars().map(|c| if c.is_ascii() && c != '"' && c != '\\' { c } else { '_' }).collect::<String>();
+    let encoded = name.bytes().map(|b| match b {
+        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+        b => format!("%{:02X}", b),
+    }).collect::<String>();
+    format!("attachment; filename=\"{}\"; filename*=UTF-8''{}", ascii, encoded)
+}
+
+fn text(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut res = Response::new(Body::from(body));
+    *res.status_mut() = status;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(sign("Jefe", "what do ya want for nothing?"), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        // ブロック長(64)より長い鍵
+        let key = "k".repeat(100);
+        assert_eq!(sign(&key, "m").len(), 64);
+        assert_ne!(sign(&key, "m"), sign(&"k".repeat(101), "m"));
+    }
+
+    #[test]
+    fn parses_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Range::Satisfiable(0, 99));
+        assert_eq!(parse_range(" bytes=100-", 1000), Range::Satisfiable(100, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), Range::Satisfiable(900, 999));
+        // ファイルより長い範囲は切り詰める
+        assert_eq!(parse_range("bytes=900-2000", 1000), Range::Satisfiable(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), Range::Satisfiable(0, 999));
+    }
+
+    #[test]
+    fn ignores_invalid_range() {
+        for r in ["bytes=5-3", "bytes=abc", "bytes=a-b", "bytes=-", "bytes=1-2,4-5", "items=0-1", "bytes=+1-2", "bytes=--1", ""] {
+            assert_eq!(parse_range(r, 1000), Range::Invalid, "{}", r);
+        }
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_range() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=1000-1200", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-5", 0), Range::Unsatisfiable);
+    }
+
+    #[test]
+    fn verifies_signature() {
+        let m = message(1, 2, 3);
+        let s = sign("secret", &m);
+        assert!(verify("secret", &m, &s));
+        assert!(verify("secret", &m, &s.to_uppercase()));
+        assert!(!verify("other", &m, &s));
+        assert!(!verify("secret", &message(1, 2, 4), &s));
+        assert!(!verify("secret", &m, &s[..62]));
+        assert!(!verify("secret", &m, "not hex"));
+        assert!(!verify("secret", &m, ""));
+    }
+}
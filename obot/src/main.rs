@@ -1,75 +1,72 @@
 mod cache;
+mod config;
 mod owner;
 mod eventhandler;
 mod scheduler;
+mod downloader;
+mod fileserver;
+mod osu;
+mod pp;
 mod commands;
 mod web;
 mod db;
 mod utility;
 
 use std::{
-    env,
     collections::{HashSet, HashMap},
+    env,
     sync::{Arc},
     error::Error,
 };
 
+use tokio::signal::unix::{signal, SignalKind};
 use serenity::{
     model::{prelude::*},
-    framework::{
-        StandardFramework,
-        standard::{
-            macros::{group},
-        },
-    },
     http::Http,
     prelude::*,
 };
 
 use cache::*;
 use eventhandler::*;
-use crate::commands::{
-    dbg::*, help::*, game::*,
-};
-use crate::utility::*;
+use crate::config::Config;
+use crate::downloader::DownloadQueue;
 
 extern crate pretty_env_logger;
 #[macro_use]
 extern crate log;
 
-#[group]
-#[description("Owner commands")]
-#[summary("サーバの管理者のみが実行できるコマンドです(ほぼデバッグ用)")]
-#[commands(shutdown, delmsg, infoc, init_database, update_database, dbtop)]
-struct Owner;
-
-#[group]
-#[description("General commands")]
-#[summary("一般ユーザーが実行できるコマンドです")]
-#[commands(todo)]
-struct General;
-
-#[group]
-#[description("Game commands")]
-#[summary("ゲームに関するコマンドです(一部管理者権限が必要)")]
-#[commands(newmaps, dlmaps, mapset_info, dbsize)]
-struct Game;
-
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    if let Err(e) = dotenv::dotenv() {
-        warn!("Failed to load .env file: {}", e);
+    // .envの値はプロセスの環境変数に入れずに設定の読み込みに渡す
+    let dotenv = config::read_dotenv();
+    let mut logger = pretty_env_logger::formatted_builder();
+    if let Some(filters) = env::var("RUST_LOG").ok().or_else(|| dotenv.as_ref().ok().and_then(|v| v.get("RUST_LOG").cloned())) {
+        logger.parse_filters(&filters);
     }
+    logger.init();
+    let dotenv = match dotenv {
+        Ok(v) => v,
+        Err(e) => {
+            warn!("Failed to load .env file: {}", e);
+            HashMap::new()
+        }
+    };
 
-    pretty_env_logger::init();
-
-    let token = env::var("DISCORD_TOKEN").expect("DISCORD_TOKEN must be set");
+    // 設定の問題は全てまとめて表示して終了する
+    let config = match Config::load(&dotenv) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let token = config.discord_token.clone();
 
     let database = sqlx::sqlite::SqlitePoolOptions::new()
         .max_connections(5)
         .connect_with(
             sqlx::sqlite::SqliteConnectOptions::new()
-                .filename("database.sqlite")
+                .filename(&config.database_path)
                 .create_if_missing(true),
         )
         .await
@@ -82,7 +79,7 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let http = Http::new(&token);
     
-    let (owners, bot_id) = match http.get_current_application_info().await {
+    let owners = match http.get_current_application_info().await {
         Ok(info) => {
             let mut owners = HashSet::new();
             if let Some(team) = info.team {
@@ -90,71 +87,40 @@ async fn main() -> Result<(), Box<dyn Error>> {
             } else {
                 owners.insert(info.owner.id);
             }
-            match http.get_current_user().await {
-                Ok(bot_id) => (owners, bot_id.id),
-                Err(_) => {
-                    error!("Could not access the bot id");
-                    return Ok(());
-                }
-            }
+            owners
         },
         Err(why) => {
             warn!("Could not access application info: {:?}", why);
-            (HashSet::new(), UserId(0))
+            HashSet::new()
         }
     };
 
-    let framework = StandardFramework::new()
-        .configure(|c| c
-            .owners(owners.clone())
-            .prefix("/")
-            .on_mention(Some(bot_id))
-        )
-        .unrecognised_command(unknown_command)
-        .help(&MY_HELP)
-        .group(&OWNER_GROUP)
-        .group(&GENERAL_GROUP)
-        .group(&GAME_GROUP);
-
     // gatewayを通してどのデータにbotがアクセスできるようにするかを指定する
     // https://docs.rs/serenity/latest/serenity/model/gateway/struct.GatewayIntents.html
     let intents = GatewayIntents::GUILDS
         | GatewayIntents::GUILD_WEBHOOKS
-        | GatewayIntents::MESSAGE_CONTENT
-        | GatewayIntents::GUILD_MESSAGES
         | GatewayIntents::GUILD_MESSAGE_REACTIONS
         | GatewayIntents::DIRECT_MESSAGES
         | GatewayIntents::DIRECT_MESSAGE_REACTIONS;
 
     let mut client = Client::builder(&token, intents)
         .event_handler(Handler)
-        .framework(framework)
         .type_map_insert::<CommandCounter>(HashMap::default())
         .await
         .expect("Error creating client");
 
 
-    // 複数回使う環境変数は最初にロードしてcacheに入れておく
-    let mut env_hashmap = HashMap::new();
-    env_hashmap.insert("log_channel".to_string(), env_helper("DISCORD_LOG_CHANNEL_ID"));
-    env_hashmap.insert("4k_ranked".to_string(), env_helper("DISCORD_4KMAP_RANKED_CHANNEL_ID"));
-    env_hashmap.insert("4k_loved".to_string(), env_helper("DISCORD_4KMAP_LOVED_CHANNEL_ID"));
-    env_hashmap.insert("4k_qualified".to_string(), env_helper("DISCORD_4KMAP_QUALIFIED_CHANNEL_ID"));
-    env_hashmap.insert("7k_ranked".to_string(), env_helper("DISCORD_7KMAP_RANKED_CHANNEL_ID"));
-    env_hashmap.insert("7k_loved".to_string(), env_helper("DISCORD_7KMAP_LOVED_CHANNEL_ID"));
-    env_hashmap.insert("7k_qualified".to_string(), env_helper("DISCORD_7KMAP_QUALIFIED_CHANNEL_ID"));
-    env_hashmap.insert("api_base".to_string(), env_helper("API_BASE"));
-    env_hashmap.insert("download_base".to_string(), env_helper("DOWNLOAD_BASE"));
-    env_hashmap.insert("user_id".to_string(), env_helper("USER_ID"));
-    env_hashmap.insert("api_secret".to_string(), env_helper("API_SECRET"));
-    env_hashmap.insert("map_path".to_string(), env_helper("MAP_PATH"));
+    // osu! APIのclientは1つだけ作って使い回す(tokenのcacheのため)
+    let api = config.api();
 
     {
         let mut data = client.data.write().await;
         data.insert::<SharedManagerContainer>(Arc::clone(&client.shard_manager));
         data.insert::<Owners>(Arc::new(Mutex::new(owners)));
         data.insert::<Database>(Arc::new(Mutex::new(database)));
-        data.insert::<Env>(Arc::new(Mutex::new(env_hashmap)));
+        data.insert::<Settings>(Arc::new(config));
+        data.insert::<OsuApi>(Arc::new(api));
+        data.insert::<Downloads>(Arc::new(DownloadQueue::default()));
     }
 
     let shard_manager = client.shard_manager.clone();
@@ -165,6 +131,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
         shard_manager.lock().await.shutdown_all().await;
     });
 
+    // SIGHUPで設定を読み直す
+    let data = client.data.clone();
+    let http = client.cache_and_http.http.clone();
+    tokio::spawn(async move {
+        let mut hangup = match signal(SignalKind::hangup()) {
+            Ok(s) => s,
+            Err(e) => {
+                error!("Failed to register SIGHUP handler: {}", e);
+                return;
+            }
+        };
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading config");
+            let _ = config::reload(&data, &http).await;
+        }
+    });
+
     if let Err(why) = client.start().await {
         error!("Client error: {:?}", why);
     }
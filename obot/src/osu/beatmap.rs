@@ -0,0 +1,551 @@
+use std::{error::Error, fmt};
+
+use crate::web::mode::GameMode;
+
+// 時刻(ms)の範囲．osu!は時刻を32bit整数で読むのでそれを超える値やNaN, infは壊れた譜面とする
+const MAX_TIME: f64 = i32::MAX as f64;
+// maniaのキー数の範囲(osu!は18kまで)．CSがこれを外れる譜面はキー数が分からないものとする
+const MAX_KEYS: u32 = 18;
+
+// .osuファイル1つ(1難易度)
+// [General], [Metadata], [Difficulty], [Events]の休憩, [TimingPoints], [HitObjects]を読む
+// それ以外のsection([Editor], [Colours]など)と知らない項目は無視する
+#[derive(Debug, Clone, Default)]
+pub struct Beatmap {
+    pub format_version: u32,
+    pub general: General,
+    pub metadata: Metadata,
+    pub difficulty: Difficulty,
+    pub breaks: Vec<Break>,
+    pub timing_points: Vec<TimingPoint>,
+    pub hit_objects: Vec<HitObject>, // 時刻順
+}
+
+#[derive(Debug, Clone)]
+pub struct General {
+    pub audio_filename: String,
+    pub audio_lead_in: i64, // ms
+    pub preview_time: i64, // ms(-1なら未設定)
+    pub mode: GameMode,
+    pub special_style: bool, // maniaで1列目をスクラッチにする(7k+1など)
+}
+
+impl Default for General {
+    fn default() -> Self {
+        General {
+            audio_filename: String::new(),
+            audio_lead_in: 0,
+            preview_time: -1,
+            mode: GameMode::Osu,
+            special_style: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metadata {
+    pub title: String,
+    pub title_unicode: String,
+    pub artist: String,
+    pub artist_unicode: String,
+    pub creator: String,
+    pub version: String, // 難易度名
+    pub source: String,
+    pub tags: String, // 空白区切り
+    pub beatmap_id: Option<i64>, // 古い譜面やアップロード前は0か無い
+    pub beatmapset_id: Option<i64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Difficulty {
+    pub hp: f64,
+    pub cs: f64, // maniaではキー数
+    pub od: f64,
+    pub ar: f64,
+    pub slider_multiplier: f64,
+    pub slider_tick_rate: f64,
+}
+
+impl Default for Difficulty {
+    fn default() -> Self {
+        // ARが無い古い譜面はODと同じ
+        Difficulty { hp: 5.0, cs: 5.0, od: 5.0, ar: 5.0, slider_multiplier: 1.4, slider_tick_rate: 1.0 }
+    }
+}
+
+// 休憩時間(ms)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Break {
+    pub start: f64,
+    pub end: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimingPoint {
+    pub time: f64, // ms
+    // uninheritedなら1拍の長さ(ms)，inheritedなら負の値でSVを表す(-50 => 2倍)
+    pub beat_length: f64,
+    pub meter: u32,
+    pub uninherited: bool,
+    pub kiai: bool,
+}
+
+impl TimingPoint {
+    pub fn bpm(&self) -> Option<f64> {
+        if self.uninherited && self.beat_length > 0.0 {
+            Some(60000.0 / self.beat_length)
+        } else {
+            None
+        }
+    }
+
+    // inheritedのSV倍率
+    pub fn slider_velocity(&self) -> f64 {
+        if self.uninherited || self.beat_length >= 0.0 {
+            1.0
+        } else {
+            (-100.0 / self.beat_length).clamp(0.1, 10.0)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct HitObject {
+    pub x: f64,
+    pub y: f64,
+    pub time: f64, // ms
+    pub new_combo: bool,
+    pub hit_sound: u32,
+    pub kind: HitObjectKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HitObjectKind {
+    Circle,
+    // endはTimingPointsとSVから計算した終点(Beatmap::parseで埋める)
+    Slider { slides: u32, length: f64, end: f64 },
+    Spinner { end: f64 },
+    // maniaのLN
+    Hold { end: f64 },
+}
+
+impl HitObject {
+    pub fn end_time(&self) -> f64 {
+        match self.kind {
+            HitObjectKind::Circle => self.time,
+            HitObjectKind::Slider { end, .. } | HitObjectKind::Spinner { end } | HitObjectKind::Hold { end } => end,
+        }
+    }
+
+    pub fn is_hold(&self) -> bool {
+        matches!(self.kind, HitObjectKind::Hold { .. })
+    }
+
+    // maniaの列(0始まり)
+    pub fn column(&self, keys: u32) -> usize {
+        let keys = keys.max(1);
+        ((self.x * keys as f64 / 512.0).floor().max(0.0) as usize).min(keys as usize - 1)
+    }
+}
+
+// 読めなかった行
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize, // 1始まり
+    pub reason: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+impl Beatmap {
+    pub fn parse(text: &str) -> Result<Self, ParseError> {
+        let mut map = Beatmap::default();
+        let mut section = String::new();
+        let mut ar = None;
+        for (i, line) in text.lines().enumerate() {
+            let err = |reason: String| ParseError { line: i + 1, reason };
+            // BOMと行末の空白を除く(先頭の空白は[Events]で意味がある)
+            let line = line.trim_start_matches('\u{feff}').trim_end();
+            if line.trim().is_empty() || line.starts_with("//") {
+                continue;
+            }
+            if let Some(v) = line.strip_prefix("osu file format v") {
+                map.format_version = v.trim().parse().map_err(|_| err(format!("invalid format version: {}", v)))?;
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].to_string();
+                continue;
+            }
+            match section.as_str() {
+                "General" | "Metadata" | "Difficulty" => {
+                    let (key, value) = match line.split_once(':') {
+                        Some((k, v)) => (k.trim(), v.trim()),
+                        None => continue,
+                    };
+                    map.set(&section, key, value, &mut ar).map_err(err)?;
+                },
+                "Events" => {
+                    if let Some(b) = parse_break(line).map_err(err)? {
+                        map.breaks.push(b);
+                    }
+                },
+                "TimingPoints" => map.timing_points.push(parse_timing_point(line).map_err(err)?),
+                // 壊れたobjectは飛ばす(1行のために難易度全体を捨てない)
+                "HitObjects" => match parse_hit_object(line) {
+                    Ok(o) => map.hit_objects.push(o),
+                    Err(e) => warn!("Skipped line {}: {}", i + 1, e),
+                },
+                _ => {},
+            }
+        }
+        map.difficulty.ar = ar.unwrap_or(map.difficulty.od);
+
+        map.timing_points.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+        map.hit_objects.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap_or(std::cmp::Ordering::Equal));
+        map.fill_slider_ends();
+        Ok(map)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: &str, ar: &mut Option<f64>) -> Result<(), String> {
+        let num = |v: &str| v.parse::<f64>().map_err(|_| format!("invalid {}: {}", key, v));
+        let id = |v: &str| v.parse::<i64>().ok().filter(|id| *id > 0);
+        match (section, key) {
+            ("General", "AudioFilename") => self.general.audio_filename = value.to_string(),
+            ("General", "AudioLeadIn") => self.general.audio_lead_in = num(value)? as i64,
+            ("General", "PreviewTime") => self.general.preview_time = num(value)? as i64,
+            ("General", "Mode") => {
+                self.general.mode = GameMode::from_name(value).ok_or_else(|| format!("unknown mode: {}", value))?;
+            },
+            ("General", "SpecialStyle") => self.general.special_style = value == "1",
+            ("Metadata", "Title") => self.metadata.title = value.to_string(),
+            ("Metadata", "TitleUnicode") => self.metadata.title_unicode = value.to_string(),
+            ("Metadata", "Artist") => self.metadata.artist = value.to_string(),
+            ("Metadata", "ArtistUnicode") => self.metadata.artist_unicode = value.to_string(),
+            ("Metadata", "Creator") => self.metadata.creator = value.to_string(),
+            ("Metadata", "Version") => self.metadata.version = value.to_string(),
+            ("Metadata", "Source") => self.metadata.source = value.to_string(),
+            ("Metadata", "Tags") => self.metadata.tags = value.to_string(),
+            ("Metadata", "BeatmapID") => self.metadata.beatmap_id = id(value),
+            ("Metadata", "BeatmapSetID") => self.metadata.beatmapset_id = id(value),
+            ("Difficulty", "HPDrainRate") => self.difficulty.hp = num(value)?,
+            ("Difficulty", "CircleSize") => self.difficulty.cs = num(value)?,
+            ("Difficulty", "OverallDifficulty") => self.difficulty.od = num(value)?,
+            ("Difficulty", "ApproachRate") => *ar = Some(num(value)?),
+            ("Difficulty", "SliderMultiplier") => self.difficulty.slider_multiplier = num(value)?,
+            ("Difficulty", "SliderTickRate") => self.difficulty.slider_tick_rate = num(value)?,
+            _ => {},
+        }
+        Ok(())
+    }
+
+    // sliderの終点 = 始点 + 長さ / (SliderMultiplier * 100 * SV) * 1拍の長さ * 往復回数
+    fn fill_slider_ends(&mut self) {
+        let multiplier = self.difficulty.slider_multiplier.max(0.1);
+        let mut beat_length = self.timing_points.iter().find(|t| t.uninherited).map(|t| t.beat_length).unwrap_or(500.0);
+        let mut velocity = 1.0;
+        let mut next = 0;
+        for object in &mut self.hit_objects {
+            while next < self.timing_points.len() && self.timing_points[next].time <= object.time {
+                let t = &self.timing_points[next];
+                if t.uninherited {
+                    beat_length = t.beat_length;
+                    velocity = 1.0;
+                } else {
+                    velocity = t.slider_velocity();
+                }
+                next += 1;
+            }
+            if let HitObjectKind::Slider { slides, length, end } = &mut object.kind {
+                let e = object.time + *length / (multiplier * 100.0 * velocity) * beat_length * *slides as f64;
+                // 極端な長さやSVでも時刻の範囲に収める
+                *end = if e.is_finite() { e.clamp(object.time, MAX_TIME) } else { object.time };
+            }
+        }
+    }
+
+    pub fn mode(&self) -> GameMode {
+        self.general.mode
+    }
+
+    // maniaのキー数(mania以外とキー数が1~18でないものはNone)
+    pub fn keys(&self) -> Option<u32> {
+        let keys = self.difficulty.cs.round();
+        if self.mode() == GameMode::Mania && (1.0..=MAX_KEYS as f64).contains(&keys) {
+            Some(keys as u32)
+        } else {
+            None
+        }
+    }
+
+    // (通常のnoteの数, LNの数)．mania以外はcircleとsliderの数
+    pub fn count_notes(&self) -> (usize, usize) {
+        let long = self.hit_objects.iter()
+            .filter(|o| matches!(o.kind, HitObjectKind::Hold { .. } | HitObjectKind::Slider { .. }))
+            .count();
+        let spinners = self.hit_objects.iter().filter(|o| matches!(o.kind, HitObjectKind::Spinner { .. })).count();
+        (self.hit_objects.len() - long - spinners, long)
+    }
+
+    // (最小, 最大, 最も長く続くBPM)．uninheritedのtiming pointが無ければNone
+    pub fn bpm_range(&self) -> Option<(f64, f64, f64)> {
+        let points = self.timing_points.iter().filter(|t| t.bpm().is_some()).collect::<Vec<&TimingPoint>>();
+        if points.is_empty() {
+            return None;
+        }
+        let last = self.last_time().max(points[points.len() - 1].time);
+        let mut min = f64::MAX;
+        let mut max = f64::MIN;
+        let mut durations: Vec<(f64, f64)> = Vec::new();
+        for (i, t) in points.iter().enumerate() {
+            let bpm = t.bpm().unwrap_or_default();
+            min = min.min(bpm);
+            max = max.max(bpm);
+            let end = points.get(i + 1).map(|n| n.time).unwrap_or(last);
+            // 最初のtiming pointより前も同じBPM
+            let start = if i == 0 { t.time.min(self.first_time()) } else { t.time };
+            match durations.iter_mut().find(|(b, _)| (*b - bpm).abs() < 0.001) {
+                Some((_, d)) => *d += end - start,
+                None => durations.push((bpm, end - start)),
+            }
+        }
+        let main = durations.iter()
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
+            .map(|(b, _)| *b)
+            .unwrap_or(min);
+        Some((min, max, main))
+    }
+
+    pub fn first_time(&self) -> f64 {
+        self.hit_objects.first().map(|o| o.time).unwrap_or(0.0)
+    }
+
+    // 最後のobjectの終わり(LNやsliderの終点を含む)
+    pub fn last_time(&self) -> f64 {
+        self.hit_objects.iter().map(|o| o.end_time()).fold(0.0, f64::max)
+    }
+
+    // 最初のobjectから最後のobjectまでの時間(ms)
+    pub fn length(&self) -> f64 {
+        (self.last_time() - self.first_time()).max(0.0)
+    }
+
+    // lengthから休憩時間を除いたもの(ms)
+    pub fn drain_time(&self) -> f64 {
+        let (first, last) = (self.first_time(), self.last_time());
+        let breaks = self.breaks.iter()
+            .map(|b| (b.end.min(last) - b.start.max(first)).max(0.0))
+            .sum::<f64>();
+        (self.length() - breaks).max(0.0)
+    }
+}
+
+// "2,start,end" または "Break,start,end"(それ以外のeventはNone)
+fn parse_break(line: &str) -> Result<Option<Break>, String> {
+    let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+    if fields.len() < 3 || (fields[0] != "2" && fields[0] != "Break") {
+        return Ok(None);
+    }
+    let time = |v: &str| parse_time(v).map_err(|_| format!("invalid break: {}", line));
+    Ok(Some(Break { start: time(fields[1])?, end: time(fields[2])? }))
+}
+
+// 有限で±MAX_TIME以内の時刻
+fn parse_time(v: &str) -> Result<f64, String> {
+    match v.parse::<f64>() {
+        Ok(t) if t.is_finite() && t.abs() <= MAX_TIME => Ok(t),
+        _ => Err(format!("invalid time: {}", v)),
+    }
+}
+
+// time,beatLength,meter,sampleSet,sampleIndex,volume,uninherited,effects
+// 古い形式では後ろが省略されている
+fn parse_timing_point(line: &str) -> Result<TimingPoint, String> {
+    let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+    if fields.len() < 2 {
+        return Err(format!("invalid timing point: {}", line));
+    }
+    let invalid = || format!("invalid timing point: {}", line);
+    let time = parse_time(fields[0]).map_err(|_| invalid())?;
+    let beat_length = fields[1].parse::<f64>().ok().filter(|b| b.is_finite()).ok_or_else(invalid)?;
+    let meter = fields.get(2).and_then(|m| m.parse::<u32>().ok()).filter(|m| *m > 0).unwrap_or(4);
+    let uninherited = match fields.get(6) {
+        Some(v) => *v == "1",
+        None => beat_length > 0.0,
+    };
+    let kiai = fields.get(7).and_then(|e| e.parse::<u32>().ok()).map(|e| e & 1 != 0).unwrap_or(false);
+    Ok(TimingPoint { time, beat_length, meter, uninherited, kiai })
+}
+
+// x,y,time,type,hitSound,objectParams,hitSample
+// type: 1 circle, 2 slider, 8 spinner, 128 mania hold(bit 4 = new combo)
+fn parse_hit_object(line: &str) -> Result<HitObject, String> {
+    let fields = line.split(',').map(|f| f.trim()).collect::<Vec<&str>>();
+    if fields.len() < 4 {
+        return Err(format!("invalid hit object: {}", line));
+    }
+    let num = |v: &str| v.parse::<f64>().map_err(|_| format!("invalid hit object: {}", line));
+    let time_of = |v: &str| parse_time(v).map_err(|_| format!("invalid hit object time: {}", line));
+    let x = num(fields[0])?;
+    let y = num(fields[1])?;
+    let time = time_of(fields[2])?;
+    let kind_bits = fields[3].parse::<u32>().map_err(|_| format!("invalid hit object type: {}", line))?;
+    let hit_sound = fields.get(4).and_then(|h| h.parse::<u32>().ok()).unwrap_or(0);
+
+    let kind = if kind_bits & 128 != 0 {
+        // endTime:hitSample
+        let end = fields.get(5).and_then(|p| p.split(':').next()).map(time_of).transpose()?.unwrap_or(time);
+        HitObjectKind::Hold { end: end.max(time) }
+    } else if kind_bits & 8 != 0 {
+        let end = fields.get(5).map(|e| time_of(e)).transpose()?.unwrap_or(time);
+        HitObjectKind::Spinner { end: end.max(time) }
+    } else if kind_bits & 2 != 0 {
+        // curve,slides,length
+        let slides = fields.get(6).and_then(|s| s.parse::<u32>().ok()).unwrap_or(1).max(1);
+        let length = fields.get(7).and_then(|l| l.parse::<f64>().ok()).unwrap_or(0.0).max(0.0);
+        HitObjectKind::Slider { slides, length, end: time }
+    } else {
+        HitObjectKind::Circle
+    };
+    Ok(HitObject { x, y, time, new_combo: kind_bits & 4 != 0, hit_sound, kind })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STD: &str = "\u{feff}osu file format v14
+
+[General]
+AudioFilename: audio.mp3
+Mode: 0
+
+[Metadata]
+Title:Song
+Version:Hard
+BeatmapID:123
+BeatmapSetID:-1
+
+[Difficulty]
+HPDrainRate:5
+CircleSize:4
+OverallDifficulty:8
+SliderMultiplier:1.4
+
+[Events]
+//Break Periods
+2,3000,6000
+
+[TimingPoints]
+1000,500,4,2,0,50,1,0
+2000,-50,4,2,0,50,0,1
+8000,400,4,2,0,50,1,0
+
+[HitObjects]
+256,192,1000,1,0,0:0:0:0:
+256,192,2000,2,0,B|300:192,2,140
+100,100,7000,12,0,7500,0:0:0:0:
+10,10,9000,5,0
+";
+
+    #[test]
+    fn parses_sections() {
+        let map = Beatmap::parse(STD).unwrap();
+        assert_eq!(map.format_version, 14);
+        assert_eq!(map.mode(), GameMode::Osu);
+        assert_eq!(map.general.audio_filename, "audio.mp3");
+        assert_eq!(map.metadata.version, "Hard");
+        assert_eq!(map.metadata.beatmap_id, Some(123));
+        assert_eq!(map.metadata.beatmapset_id, None);
+        // ARが無ければOD
+        assert_eq!(map.difficulty.ar, 8.0);
+        assert_eq!(map.breaks, vec![Break { start: 3000.0, end: 6000.0 }]);
+        assert_eq!(map.timing_points.len(), 3);
+        assert!(map.timing_points[1].kiai);
+        assert_eq!(map.keys(), None);
+    }
+
+    #[test]
+    fn computes_object_times() {
+        let map = Beatmap::parse(STD).unwrap();
+        let times = map.hit_objects.iter().map(|o| (o.time, o.end_time())).collect::<Vec<(f64, f64)>>();
+        // slider: 140 / (1.4 * 100 * 2.0) * 500 * 2 = 500
+        assert_eq!(times, vec![(1000.0, 1000.0), (2000.0, 2500.0), (7000.0, 7500.0), (9000.0, 9000.0)]);
+        assert_eq!(map.count_notes(), (2, 1));
+        assert_eq!(map.bpm_range(), Some((120.0, 150.0, 120.0)));
+        assert_eq!(map.length(), 8000.0);
+        assert_eq!(map.drain_time(), 5000.0);
+    }
+
+    #[test]
+    fn parses_mania_columns_and_holds() {
+        let text = "[General]\nMode: 3\n[Difficulty]\nCircleSize:7\n[HitObjects]\n36,192,100,128,0,500:0:0:0:0:\n475,192,200,1,0,0:0:0:0:\n";
+        let map = Beatmap::parse(text).unwrap();
+        assert_eq!(map.keys(), Some(7));
+        assert_eq!(map.count_notes(), (1, 1));
+        assert!(map.hit_objects[0].is_hold());
+        assert_eq!(map.hit_objects[0].end_time(), 500.0);
+        assert_eq!(map.hit_objects.iter().map(|o| o.column(7)).collect::<Vec<usize>>(), vec![0, 6]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_keys() {
+        let keys = |cs: &str| Beatmap::parse(&format!("[General]\nMode: 3\n[Difficulty]\nCircleSize:{}\n", cs)).unwrap().keys();
+        assert_eq!(keys("1"), Some(1));
+        assert_eq!(keys("18"), Some(18));
+        assert_eq!(keys("0"), None);
+        assert_eq!(keys("19"), None);
+        assert_eq!(keys("1e9"), None);
+        assert_eq!(keys("-4"), None);
+    }
+
+    #[test]
+    fn skips_broken_hit_objects() {
+        let text = "[General]\nMode: 3\n[HitObjects]\n1,2\n64,192,100,1,0\nx,192,200,1,0\n";
+        let map = Beatmap::parse(text).unwrap();
+        assert_eq!(map.hit_objects.len(), 1);
+        assert_eq!(map.hit_objects[0].time, 100.0);
+    }
+
+    #[test]
+    fn reports_line_of_invalid_value() {
+        let e = Beatmap::parse("[General]\nMode: 0\n\n[Difficulty]\nCircleSize:abc\n").unwrap_err();
+        assert_eq!(e.line, 5);
+        assert!(Beatmap::parse("[General]\nMode: 9\n").is_err());
+        assert!(Beatmap::parse("osu file format vX\n").is_err());
+    }
+
+    #[test]
+    fn rejects_broken_times() {
+        let text = "[General]\nMode: 3\n[HitObjects]\n64,192,NaN,1,0\n64,192,inf,1,0\n64,192,1e12,1,0\n64,192,100,128,0,1e12:0:0:0:0:\n64,192,200,1,0\n";
+        let map = Beatmap::parse(text).unwrap();
+        assert_eq!(map.hit_objects.len(), 1);
+        assert_eq!(map.hit_objects[0].time, 200.0);
+        assert!(Beatmap::parse("[TimingPoints]\nNaN,500\n").is_err());
+        assert!(Beatmap::parse("[TimingPoints]\n0,inf\n").is_err());
+        assert!(Beatmap::parse("[Events]\n2,0,inf\n").is_err());
+    }
+
+    #[test]
+    fn clamps_slider_end() {
+        let text = "[TimingPoints]\n0,1e10,4,2,0,50,1,0\n[HitObjects]\n0,0,100,2,0,B|1:1,1,1e20\n";
+        assert_eq!(Beatmap::parse(text).unwrap().last_time(), MAX_TIME);
+        // infになるときは始点
+        let text = "[Difficulty]\nSliderMultiplier:0.0001\n[TimingPoints]\n0,1e300,4,2,0,50,1,0\n[HitObjects]\n0,0,100,2,0,B|1:1,1,1e300\n";
+        assert_eq!(Beatmap::parse(text).unwrap().last_time(), 100.0);
+    }
+
+    #[test]
+    fn empty_file_has_no_objects() {
+        let map = Beatmap::parse("").unwrap();
+        assert!(map.hit_objects.is_empty());
+        assert_eq!(map.bpm_range(), None);
+        assert_eq!(map.length(), 0.0);
+    }
+}
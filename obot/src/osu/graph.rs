@@ -0,0 +1,249 @@
+use std::{error::Error, io::Write};
+
+use flate2::{write::ZlibEncoder, Compression, Crc};
+
+use crate::db::handler::DBHandler;
+use crate::web::api::Difficulty;
+use super::{beatmap::{Beatmap, HitObjectKind}, osz};
+
+// 1難易度のnote密度(NPS)のグラフをPNGで描く
+// 1秒ごとのnote数を積み上げ棒グラフにする(下が通常のnote，上がLN / slider)
+
+// 画像の大きさと余白(px)
+const WIDTH: usize = 800;
+const HEIGHT: usize = 240;
+const MARGIN: usize = 8;
+// 1本の棒の長さ(ms)
+const BIN_LENGTH: f64 = 1000.0;
+// 棒の数の上限(3時間)．これより後のobjectは描かない
+const MAX_BINS: usize = 3 * 60 * 60;
+// 目盛りの間隔
+const GRID_SECONDS: usize = 30;
+const GRID_NPS: usize = 5;
+
+// 色(RGB)
+const BACKGROUND: [u8; 3] = [0x2b, 0x2d, 0x31];
+const BREAK: [u8; 3] = [0x23, 0x24, 0x28];
+const GRID: [u8; 3] = [0x3f, 0x41, 0x47];
+const RICE: [u8; 3] = [0x5e, 0xa8, 0xff];
+const LONG: [u8; 3] = [0xff, 0x9f, 0x43];
+
+// 1秒ごとの(通常のnote, LN / slider)の数
+#[derive(Debug, Clone)]
+pub struct Density {
+    pub start: f64, // 最初のobjectの時刻(ms)
+    pub bins: Vec<(usize, usize)>,
+}
+
+impl Density {
+    pub fn new(map: &Beatmap) -> Option<Self> {
+        let start = map.hit_objects.first()?.time;
+        // NaNはclampしても残るので0にする
+        let bin_of = |time: f64| {
+            let i = ((time - start) / BIN_LENGTH).floor();
+            if i.is_nan() { 0.0 } else { i.clamp(0.0, MAX_BINS as f64) }
+        };
+        let count = (bin_of(map.last_time()) as usize + 1).min(MAX_BINS);
+        let mut bins = vec![(0, 0); count];
+        for o in &map.hit_objects {
+            let i = bin_of(o.time) as usize;
+            if i >= count {
+                continue;
+            }
+            match o.kind {
+                HitObjectKind::Hold { .. } | HitObjectKind::Slider { .. } => bins[i].1 += 1,
+                HitObjectKind::Circle => bins[i].0 += 1,
+                // spinnerは数えない
+                HitObjectKind::Spinner { .. } => {},
+            }
+        }
+        Some(Density { start, bins })
+    }
+
+    pub fn peak(&self) -> usize {
+        self.bins.iter().map(|(r, l)| r + l).max().unwrap_or(0)
+    }
+}
+
+// ダウンロード済みのbeatmapsetからdifficultyを探して描く(ダウンロードしていなければNone)
+pub async fn load(db: &DBHandler, difficulty: &Difficulty) -> Result<Option<(Beatmap, Vec<u8>)>, Box<dyn Error + Send + Sync>> {
+    let mut maps = match osz::load(db, difficulty.beatmapset_id).await? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    // .osuにBeatmapIDが無いときは難易度名で探す
+    let i = maps.iter().position(|(_, m)| m.metadata.beatmap_id == Some(difficulty.id))
+        .or_else(|| maps.iter().position(|(_, m)| m.metadata.beatmap_id.is_none() && m.metadata.version == difficulty.version))
+        .ok_or_else(|| format!("{} is not found in the .osz", difficulty.version))?;
+    let (_, map) = maps.swap_remove(i);
+    // 描くのは時間がかかるのでasyncの処理を止めないようにする
+    let (map, png) = tokio::task::spawn_blocking(move || {
+        let png = render(&map);
+        (map, png)
+    }).await?;
+    let png = png.ok_or_else(|| format!("{} has no objects", difficulty.version))?;
+    Ok(Some((map, png)))
+}
+
+// objectが無ければNone
+pub fn render(map: &Beatmap) -> Option<Vec<u8>> {
+    let density = Density::new(map)?;
+    let (w, h) = (WIDTH - MARGIN * 2, HEIGHT - MARGIN * 2);
+    // 上限は目盛りの倍数に切り上げる
+    let top = density.peak().div_ceil(GRID_NPS).max(1) * GRID_NPS;
+    let bins = density.bins.len();
+    let px_per_note = h as f64 / top as f64;
+
+    let mut image = Image::new(WIDTH, HEIGHT, BACKGROUND);
+    let x_of = |time: f64| MARGIN as f64 + (time - density.start) / (bins as f64 * BIN_LENGTH) * w as f64;
+    for b in &map.breaks {
+        let (x0, x1) = (x_of(b.start).max(MARGIN as f64), x_of(b.end).min((MARGIN + w) as f64));
+        if x1 > x0 {
+            image.fill(x0 as usize, MARGIN, x1 as usize, MARGIN + h, BREAK);
+        }
+    }
+    for nps in (GRID_NPS..=top).step_by(GRID_NPS) {
+        let y = MARGIN + h - (nps as f64 * px_per_note).round() as usize;
+        image.fill(MARGIN, y, MARGIN + w, y + 1, GRID);
+    }
+    for s in (GRID_SECONDS..bins).step_by(GRID_SECONDS) {
+        let x = x_of(density.start + s as f64 * BIN_LENGTH) as usize;
+        image.fill(x, MARGIN, x + 1, MARGIN + h, GRID);
+    }
+
+    let bottom = MARGIN + h;
+    for (i, (rice, long)) in density.bins.iter().enumerate() {
+        let x0 = MARGIN + i * w / bins;
+        // 棒が多いときは隙間を空けない
+        let x1 = (MARGIN + (i + 1) * w / bins).max(x0 + 1);
+        let x1 = if x1 - x0 > 2 { x1 - 1 } else { x1 };
+        let rice_h = (*rice as f64 * px_per_note).round() as usize;
+        let total_h = ((rice + long) as f64 * px_per_note).round() as usize;
+        image.fill(x0, bottom - rice_h, x1, bottom, RICE);
+        image.fill(x0, bottom - total_h, x1, bottom - rice_h, LONG);
+    }
+
+    match image.encode() {
+        Ok(png) => Some(png),
+        Err(e) => {
+            error!("Failed to encode graph: {}", e);
+            None
+        }
+    }
+}
+
+// RGBの画像
+struct Image {
+    width: usize,
+    height: usize,
+    pixels: Vec<[u8; 3]>,
+}
+
+impl Image {
+    fn new(width: usize, height: usize, color: [u8; 3]) -> Self {
+        Image { width, height, pixels: vec![color; width * height] }
+    }
+
+    // [x0, x1) × [y0, y1)を塗る(はみ出した部分は無視する)
+    fn fill(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: [u8; 3]) {
+        for y in y0..y1.min(self.height) {
+            for x in x0..x1.min(self.width) {
+                self.pixels[y * self.width + x] = color;
+            }
+        }
+    }
+
+    // 8bit RGBのPNG(フィルタなし)
+    fn encode(&self) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut raw = Vec::with_capacity((self.width * 3 + 1) * self.height);
+        for row in self.pixels.chunks(self.width) {
+            raw.push(0);
+            for p in row {
+                raw.extend_from_slice(p);
+            }
+        }
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&raw)?;
+        let idat = encoder.finish()?;
+
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // bit depth 8, color type 2 (RGB), compression, filter, interlace
+        ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [(b"IHDR", &ihdr), (b"IDAT", &idat), (b"IEND", &Vec::new())] {
+            png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            png.extend_from_slice(kind);
+            png.extend_from_slice(data);
+            let mut crc = Crc::new();
+            crc.update(kind);
+            crc.update(data);
+            png.extend_from_slice(&crc.sum().to_be_bytes());
+        }
+        Ok(png)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(objects: &str) -> Beatmap {
+        Beatmap::parse(&format!("[General]\nMode: 3\n[Difficulty]\nCircleSize:4\n[HitObjects]\n{}", objects)).unwrap()
+    }
+
+    #[test]
+    fn counts_notes_per_second() {
+        let map = parse("64,192,1000,1,0\n192,192,1500,128,0,1600:0:0:0:0:\n320,192,1999,1,0\n64,192,3500,1,0\n");
+        let d = Density::new(&map).unwrap();
+        assert_eq!(d.start, 1000.0);
+        assert_eq!(d.bins, vec![(2, 1), (0, 0), (1, 0)]);
+        assert_eq!(d.peak(), 3);
+    }
+
+    #[test]
+    fn long_note_end_extends_bins() {
+        let map = parse("64,192,0,128,0,2500:0:0:0:0:\n");
+        assert_eq!(Density::new(&map).unwrap().bins, vec![(0, 1), (0, 0), (0, 0)]);
+    }
+
+    #[test]
+    fn caps_bins_of_huge_times() {
+        let map = parse("64,192,0,1,0\n64,192,100,128,0,2000000000:0:0:0:0:\n64,192,1999999999,1,0\n");
+        let d = Density::new(&map).unwrap();
+        assert_eq!(d.bins.len(), MAX_BINS);
+        assert_eq!(d.bins[0], (1, 1));
+        assert_eq!(d.peak(), 2);
+    }
+
+    #[test]
+    fn empty_map_has_no_graph() {
+        assert!(Density::new(&parse("")).is_none());
+        assert!(render(&parse("")).is_none());
+    }
+
+    #[test]
+    fn encodes_png() {
+        let mut image = Image::new(3, 2, BACKGROUND);
+        image.fill(1, 0, 9, 1, RICE);
+        assert_eq!(image.pixels[1], RICE);
+        assert_eq!(image.pixels[3], BACKGROUND);
+        let png = image.encode().unwrap();
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n"));
+        // IHDR: 長さ13, 幅3, 高さ2, 8bit RGB
+        assert_eq!(&png[8..16], b"\x00\x00\x00\x0dIHDR");
+        assert_eq!(&png[16..29], &[0, 0, 0, 3, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+        let mut crc = Crc::new();
+        crc.update(&png[12..29]);
+        assert_eq!(&png[29..33], &crc.sum().to_be_bytes());
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+
+    #[test]
+    fn renders_map() {
+        let png = render(&parse("64,192,0,1,0\n192,192,500,128,0,900:0:0:0:0:\n")).unwrap();
+        assert!(png.starts_with(b"\x89PNG"));
+    }
+}
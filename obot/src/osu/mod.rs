@@ -0,0 +1,4 @@
+pub mod beatmap;
+pub mod graph;
+pub mod osz;
+pub mod pattern;
@@ -0,0 +1,231 @@
+use std::{
+    error::Error,
+    io::Read,
+    path::Path,
+};
+
+use flate2::read::DeflateDecoder;
+
+use crate::db::handler::DBHandler;
+use super::beatmap::Beatmap;
+
+// zipの各レコードの先頭
+const LOCAL_HEADER: &[u8] = b"PK\x03\x04";
+const CENTRAL_HEADER: &[u8] = b"PK\x01\x02";
+const END_OF_CENTRAL_DIRECTORY: &[u8] = b"PK\x05\x06";
+// End of central directoryの大きさ(コメントを除く)とコメントの最大長
+const EOCD_LEN: usize = 22;
+const MAX_COMMENT_LEN: usize = 0xffff;
+// 展開後の1ファイルの上限(壊れた.oszやzip bomb対策．.osuは普通数MB以下)
+const MAX_ENTRY_SIZE: u64 = 32 * 1024 * 1024;
+
+// .osz(zip)の中のファイル1つ
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// ダウンロード済みのbeatmapsetの全難易度(ダウンロードしていなければNone)
+// 読めない.osuは飛ばす
+pub async fn load(db: &DBHandler, id: i64) -> Result<Option<Vec<(String, Beatmap)>>, Box<dyn Error + Send + Sync>> {
+    let path = match db.get_download(id).await? {
+        Some(d) if Path::new(&d.path).exists() => d.path,
+        _ => return Ok(None),
+    };
+    // 展開は時間がかかるのでasyncの処理を止めないようにする
+    let maps = tokio::task::spawn_blocking(move || read_beatmaps(Path::new(&path))).await??;
+    Ok(Some(maps))
+}
+
+// .oszの中の.osuを全て(ファイル名, 譜面)で読む
+pub fn read_beatmaps(path: &Path) -> Result<Vec<(String, Beatmap)>, Box<dyn Error + Send + Sync>> {
+    let mut maps = Vec::new();
+    for entry in read(path, |name| name.to_lowercase().ends_with(".osu"))? {
+        let text = String::from_utf8_lossy(&entry.data);
+        match Beatmap::parse(&text) {
+            Ok(map) => maps.push((entry.name, map)),
+            Err(e) => warn!("Failed to parse {} in {}: {}", entry.name, path.display(), e),
+        }
+    }
+    Ok(maps)
+}
+
+// filterに合う名前のファイルを展開する(stored / deflateのみ)
+pub fn read(path: &Path, filter: impl Fn(&str) -> bool) -> Result<Vec<Entry>, Box<dyn Error + Send + Sync>> {
+    read_bytes(&std::fs::read(path)?, filter)
+}
+
+// readの本体(zip全体がdata)
+// ヘッダの値は信用せず，大きさの確認なしに確保しない
+pub fn read_bytes(data: &[u8], filter: impl Fn(&str) -> bool) -> Result<Vec<Entry>, Box<dyn Error + Send + Sync>> {
+    let u16_at = |i: usize| -> Result<usize, Box<dyn Error + Send + Sync>> {
+        data.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize).ok_or_else(|| "Unexpected end of zip".into())
+    };
+    let u32_at = |i: usize| -> Result<usize, Box<dyn Error + Send + Sync>> {
+        data.get(i..i + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize).ok_or_else(|| "Unexpected end of zip".into())
+    };
+
+    // 末尾のEnd of central directoryからファイルの一覧(central directory)の位置を探す
+    let eocd = (0..(data.len() + 1).saturating_sub(EOCD_LEN)).rev()
+        .take(MAX_COMMENT_LEN + 1)
+        .find(|&i| data[i..].starts_with(END_OF_CENTRAL_DIRECTORY))
+        .ok_or("End of central directory is not found")?;
+    let count = u16_at(eocd + 10)?;
+    let mut pos = u32_at(eocd + 16)?;
+
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        if !data.get(pos..).map(|d| d.starts_with(CENTRAL_HEADER)).unwrap_or(false) {
+            return Err("Broken central directory".into());
+        }
+        let flags = u16_at(pos + 8)?;
+        let method = u16_at(pos + 10)?;
+        let compressed = u32_at(pos + 20)?;
+        let name_len = u16_at(pos + 28)?;
+        let extra_len = u16_at(pos + 30)?;
+        let comment_len = u16_at(pos + 32)?;
+        let local = u32_at(pos + 42)?;
+        let raw_name = data.get(pos + 46..pos + 46 + name_len).ok_or("Unexpected end of zip")?;
+        // bit 11が立っていればUTF-8，そうでなければ(古いツールでは)CP437だが，ほぼASCIIなのでlossyで読む
+        let name = if flags & 0x800 != 0 {
+            String::from_utf8_lossy(raw_name).to_string()
+        } else {
+            raw_name.iter().map(|&b| if b.is_ascii() { b as char } else { '_' }).collect()
+        };
+        pos += 46 + name_len + extra_len + comment_len;
+        if name.ends_with('/') || !filter(&name) {
+            continue;
+        }
+        if flags & 1 != 0 {
+            warn!("Skipped {} (encrypted)", name);
+            continue;
+        }
+
+        // 中身の位置はlocal file headerの後ろ(extraの長さはcentral directoryと違うことがある)
+        if !data.get(local..).map(|d| d.starts_with(LOCAL_HEADER)).unwrap_or(false) {
+            return Err(format!("Broken local file header of {}", name).into());
+        }
+        let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+        let raw = data.get(start..start + compressed).ok_or("Unexpected end of zip")?;
+        let data = match method {
+            0 => raw.to_vec(),
+            8 => {
+                let mut out = Vec::new();
+                DeflateDecoder::new(raw).take(MAX_ENTRY_SIZE + 1).read_to_end(&mut out)?;
+                out
+            },
+            m => {
+                warn!("Skipped {} (unsupported compression method {})", name, m);
+                continue;
+            }
+        };
+        if data.len() as u64 > MAX_ENTRY_SIZE {
+            return Err(format!("{} is too large (over {} bytes)", name, MAX_ENTRY_SIZE).into());
+        }
+        entries.push(Entry { name, data });
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::DeflateEncoder, Compression};
+
+    use super::*;
+
+    // (名前, 中身, deflateで圧縮するか)からzipを作る(CRCは読まないので0)
+    fn zip(files: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut central = Vec::new();
+        for (name, content, deflate) in files {
+            let (method, body) = if *deflate {
+                let mut e = DeflateEncoder::new(Vec::new(), Compression::default());
+                e.write_all(content).unwrap();
+                (8u16, e.finish().unwrap())
+            } else {
+                (0u16, content.to_vec())
+            };
+            let local = out.len() as u32;
+            out.extend_from_slice(LOCAL_HEADER);
+            out.extend_from_slice(&[20, 0, 0, 0]);
+            out.extend_from_slice(&method.to_le_bytes());
+            out.extend_from_slice(&[0; 8]);
+            out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            out.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            out.extend_from_slice(&[0, 0]);
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(&body);
+
+            central.extend_from_slice(CENTRAL_HEADER);
+            central.extend_from_slice(&[20, 0, 20, 0, 0, 0]);
+            central.extend_from_slice(&method.to_le_bytes());
+            central.extend_from_slice(&[0; 8]);
+            central.extend_from_slice(&(body.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&local.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let offset = out.len() as u32;
+        out.extend_from_slice(&central);
+        out.extend_from_slice(END_OF_CENTRAL_DIRECTORY);
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        out.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out
+    }
+
+    #[test]
+    fn reads_stored_entry() {
+        let data = zip(&[("a.osu", b"osu file format v14", false)]);
+        let entries = read_bytes(&data, |_| true).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "a.osu");
+        assert_eq!(entries[0].data, b"osu file format v14");
+    }
+
+    #[test]
+    fn reads_deflated_entry_and_filters() {
+        let content = b"[HitObjects]\n".repeat(100);
+        let data = zip(&[("bg.jpg", b"\xff\xd8", false), ("b.osu", &content, true)]);
+        let entries = read_bytes(&data, |n| n.ends_with(".osu")).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "b.osu");
+        assert_eq!(entries[0].data, content);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let data = zip(&[("a.osu", b"osu file format v14", true)]);
+        assert!(read_bytes(&data[..data.len() - 10], |_| true).is_err());
+        assert!(read_bytes(&data[..20], |_| true).is_err());
+        assert!(read_bytes(&[], |_| true).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_offset() {
+        let mut data = zip(&[("a.osu", b"osu file format v14", false)]);
+        // central directoryの位置(EOCDの+16)をずらす
+        let eocd = data.len() - EOCD_LEN;
+        data[eocd + 16] += 1;
+        assert!(read_bytes(&data, |_| true).is_err());
+        // 範囲外
+        data[eocd + 16..eocd + 20].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(read_bytes(&data, |_| true).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_entry() {
+        let content = vec![0u8; MAX_ENTRY_SIZE as usize + 1];
+        let data = zip(&[("bomb.osu", &content, true)]);
+        assert!(read_bytes(&data, |_| true).is_err());
+    }
+}
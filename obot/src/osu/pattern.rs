@@ -0,0 +1,218 @@
+use super::beatmap::Beatmap;
+
+// maniaの難易度の配置の分類(DBのpatterns.category)
+pub const CATEGORIES: [&str; 5] = ["stream", "jack", "chordjack", "ln", "mixed"];
+
+// 同じ時刻とみなす差(ms)
+const CHORD_EPSILON: f64 = 2.0;
+// 同じ列をこの間隔(ms)以内に続けて押すものをjackとする
+const JACK_GAP: f64 = 250.0;
+// 区間ごとに分類する長さ(ms)とNPSを数える窓
+const SECTION_LENGTH: f64 = 1000.0;
+// これより少ない区間は分類しない(休憩など)
+const MIN_SECTION_NOTES: usize = 6;
+// 分類のしきい値
+const LN_THRESHOLD: f64 = 0.4;
+const CHORDJACK_THRESHOLD: f64 = 0.3;
+const JACK_THRESHOLD: f64 = 0.25;
+const STREAM_THRESHOLD: f64 = 0.4;
+
+// 1難易度の分析結果(patternsテーブルの1行)
+// 割合は0.0 ~ 1.0
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub beatmap_id: i64,
+    pub beatmapset_id: i64,
+    pub category: String,
+    pub ln_ratio: f64, // LNの割合
+    pub jack_density: f64, // 直前の行と同じ列のnoteの割合
+    pub chordjack_ratio: f64, // chordjackの区間の割合
+    pub stream_ratio: f64, // streamの区間の割合
+    pub peak_nps: f64, // 1秒間の最大のnote数
+    pub ss_pp: Option<f64>, // SSのpp(NM)．analyzeでは計算しない
+}
+
+impl Pattern {
+    // "chordjack | LN 12% | jack 30% | CJ 40% / stream 10% | peak 18 NPS"
+    pub fn summary(&self) -> String {
+        let pct = |v: f64| (v * 100.0).round();
+        format!("{} | LN {}% | jack {}% | CJ {}% / stream {}% | peak {:.0} NPS",
+            self.category, pct(self.ln_ratio), pct(self.jack_density), pct(self.chordjack_ratio), pct(self.stream_ratio), self.peak_nps)
+    }
+}
+
+// 同じ時刻のnote(chord)
+struct Row {
+    time: f64,
+    columns: Vec<usize>,
+    jacks: usize, // 直前の行と同じ列のnoteの数
+}
+
+// mania以外(またはnoteがない)ならNone
+pub fn analyze(map: &Beatmap, beatmapset_id: i64, beatmap_id: i64) -> Option<Pattern> {
+    let keys = map.keys()?;
+    if map.hit_objects.is_empty() {
+        return None;
+    }
+    let total = map.hit_objects.len();
+    let holds = map.hit_objects.iter().filter(|o| o.is_hold()).count();
+
+    let mut rows: Vec<Row> = Vec::new();
+    for o in &map.hit_objects {
+        let column = o.column(keys);
+        match rows.last_mut() {
+            Some(r) if (o.time - r.time).abs() < CHORD_EPSILON => {
+                if !r.columns.contains(&column) {
+                    r.columns.push(column);
+                }
+            },
+            _ => rows.push(Row { time: o.time, columns: vec![column], jacks: 0 }),
+        }
+    }
+    for i in 1..rows.len() {
+        if rows[i].time - rows[i - 1].time <= JACK_GAP {
+            let jacks = rows[i].columns.iter().filter(|c| rows[i - 1].columns.contains(c)).count();
+            rows[i].jacks = jacks;
+        }
+    }
+    let notes = rows.iter().map(|r| r.columns.len()).sum::<usize>().max(1);
+    let jack_density = rows.iter().map(|r| r.jacks).sum::<usize>() as f64 / notes as f64;
+
+    // 区間ごとの分類
+    let (mut active, mut chordjack, mut stream) = (0, 0, 0);
+    let start = rows[0].time;
+    let mut i = 0;
+    while i < rows.len() {
+        let end = start + ((rows[i].time - start) / SECTION_LENGTH).floor() * SECTION_LENGTH + SECTION_LENGTH;
+        // 時刻が壊れていても必ず1行は進める
+        let section = rows[i..].iter().take_while(|r| r.time < end).collect::<Vec<&Row>>();
+        if section.is_empty() {
+            i += 1;
+            continue;
+        }
+        i += section.len();
+        let n = section.iter().map(|r| r.columns.len()).sum::<usize>();
+        if n < MIN_SECTION_NOTES {
+            continue;
+        }
+        active += 1;
+        let chords = section.iter().filter(|r| r.columns.len() >= 2).count() as f64 / section.len() as f64;
+        let jacks = section.iter().map(|r| r.jacks).sum::<usize>() as f64 / n as f64;
+        if chords >= 0.5 && jacks >= 0.3 {
+            chordjack += 1;
+        } else if jacks < 0.15 {
+            // jumpstreamやhandstreamも含む
+            stream += 1;
+        }
+    }
+    let ratio = |n: usize| if active == 0 { 0.0 } else { n as f64 / active as f64 };
+    let (chordjack_ratio, stream_ratio) = (ratio(chordjack), ratio(stream));
+
+    // 1秒の窓を動かして最大のnote数を数える
+    let times = map.hit_objects.iter().map(|o| o.time).collect::<Vec<f64>>();
+    let mut peak = 0;
+    let mut left = 0;
+    for right in 0..times.len() {
+        while left < right && times[right] - times[left] >= SECTION_LENGTH {
+            left += 1;
+        }
+        peak = peak.max(right - left + 1);
+    }
+
+    let ln_ratio = holds as f64 / total as f64;
+    let category = if ln_ratio >= LN_THRESHOLD {
+        "ln"
+    } else if chordjack_ratio >= CHORDJACK_THRESHOLD && chordjack_ratio >= stream_ratio {
+        "chordjack"
+    } else if jack_density >= JACK_THRESHOLD {
+        "jack"
+    } else if stream_ratio >= STREAM_THRESHOLD {
+        "stream"
+    } else {
+        "mixed"
+    };
+    Some(Pattern {
+        beatmap_id,
+        beatmapset_id,
+        category: category.to_string(),
+        ln_ratio,
+        jack_density,
+        chordjack_ratio,
+        stream_ratio,
+        peak_nps: peak as f64 * 1000.0 / SECTION_LENGTH,
+        ss_pp: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::osu::beatmap::{HitObject, HitObjectKind};
+
+    // 4k, (時刻, 列, LNか)のnote
+    fn mania(notes: &[(f64, usize, bool)]) -> Beatmap {
+        let mut text = String::from("[General]\nMode: 3\n[Difficulty]\nCircleSize:4\n[HitObjects]\n");
+        for (time, column, hold) in notes {
+            let x = column * 128 + 64;
+            if *hold {
+                text.push_str(&format!("{},192,{},128,0,{}:0:0:0:0:\n", x, time, time + 200.0));
+            } else {
+                text.push_str(&format!("{},192,{},1,0,0:0:0:0:\n", x, time));
+            }
+        }
+        Beatmap::parse(&text).unwrap()
+    }
+
+    #[test]
+    fn classifies_stream() {
+        let notes = (0..200).map(|i| (i as f64 * 100.0, i % 4, false)).collect::<Vec<_>>();
+        let p = analyze(&mania(&notes), 1, 2).unwrap();
+        assert_eq!((p.beatmapset_id, p.beatmap_id), (1, 2));
+        assert_eq!(p.category, "stream");
+        assert_eq!(p.jack_density, 0.0);
+        assert_eq!(p.stream_ratio, 1.0);
+        assert_eq!(p.peak_nps, 10.0);
+    }
+
+    #[test]
+    fn classifies_jack() {
+        let notes = (0..200).map(|i| (i as f64 * 100.0, (i / 4) % 4, false)).collect::<Vec<_>>();
+        let p = analyze(&mania(&notes), 1, 2).unwrap();
+        assert_eq!(p.category, "jack");
+        assert!(p.jack_density >= 0.7);
+    }
+
+    #[test]
+    fn classifies_chordjack() {
+        let notes = (0..100)
+            .flat_map(|i| [(i as f64 * 150.0, 0, false), (i as f64 * 150.0, 1 + i % 3, false)])
+            .collect::<Vec<_>>();
+        let p = analyze(&mania(&notes), 1, 2).unwrap();
+        assert_eq!(p.category, "chordjack");
+        assert_eq!(p.chordjack_ratio, 1.0);
+    }
+
+    #[test]
+    fn classifies_ln() {
+        let notes = (0..100).map(|i| (i as f64 * 300.0, i % 4, i % 2 == 0)).collect::<Vec<_>>();
+        let p = analyze(&mania(&notes), 1, 2).unwrap();
+        assert_eq!(p.category, "ln");
+        assert_eq!(p.ln_ratio, 0.5);
+    }
+
+    #[test]
+    fn ignores_other_modes_and_empty_maps() {
+        assert!(analyze(&Beatmap::parse("[General]\nMode: 0\n[HitObjects]\n0,0,0,1,0\n").unwrap(), 1, 2).is_none());
+        assert!(analyze(&mania(&[]), 1, 2).is_none());
+    }
+
+    #[test]
+    fn finishes_with_broken_times() {
+        // parseでは弾かれるが，直接作られた場合も止まらない
+        let mut map = mania(&[(0.0, 0, false), (100.0, 1, false)]);
+        let object = |time: f64| HitObject { x: 64.0, y: 192.0, time, new_combo: false, hit_sound: 0, kind: HitObjectKind::Circle };
+        map.hit_objects.push(object(f64::NAN));
+        map.hit_objects.push(object(200.0));
+        assert!(analyze(&map, 1, 2).is_some());
+    }
+}
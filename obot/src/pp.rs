@@ -0,0 +1,268 @@
+use std::{error::Error, fmt};
+
+use crate::db::handler::DBHandler;
+use crate::osu::{beatmap::Beatmap, osz};
+
+// maniaの難易度(star rating)とppをダウンロードした.oszから計算する
+// star ratingはstrain(400ms区間ごとの最大値の重み付き和)，ppは2022年以降のmaniaの式
+// 公式の値とは少しずれることがある
+
+// /ppで表示する精度
+pub const ACCURACIES: [f64; 3] = [0.95, 0.97, 0.99];
+
+// strainを区切る区間の長さ(ms)
+const SECTION_LENGTH: f64 = 400.0;
+// 1秒あたりのstrainの減衰
+const INDIVIDUAL_DECAY: f64 = 0.125;
+const OVERALL_DECAY: f64 = 0.30;
+// 区間の最大値を大きい順に足すときの重み
+const DECAY_WEIGHT: f64 = 0.9;
+const STAR_SCALING_FACTOR: f64 = 0.018;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Mods {
+    pub nf: bool,
+    pub ez: bool,
+    pub ht: bool,
+    pub dt: bool, // NCも同じ
+}
+
+impl Mods {
+    // "DTNF", "+ht,nf" などを読む(難易度とppに関係しないmodは無視する)
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim().trim_start_matches('+').to_uppercase().replace([',', ' '], "");
+        if s.len() % 2 != 0 || !s.is_ascii() {
+            return Err(format!("Invalid mods: {}", s));
+        }
+        let mut mods = Mods::default();
+        for i in (0..s.len()).step_by(2) {
+            match &s[i..i + 2] {
+                "NM" => {},
+                "NF" => mods.nf = true,
+                "EZ" => mods.ez = true,
+                "HT" => mods.ht = true,
+                "DT" | "NC" => mods.dt = true,
+                "HR" | "HD" | "FI" | "FL" | "SD" | "PF" | "MR" | "CO" | "RD" => {},
+                m if m.ends_with('K') && m[..1].parse::<u32>().is_ok() => {},
+                m => return Err(format!("Unknown mod: {}", m)),
+            }
+        }
+        if mods.ht && mods.dt {
+            return Err("HT and DT cannot be used together".to_string());
+        }
+        Ok(mods)
+    }
+
+    pub fn clock_rate(&self) -> f64 {
+        if self.dt {
+            1.5
+        } else if self.ht {
+            0.75
+        } else {
+            1.0
+        }
+    }
+}
+
+impl fmt::Display for Mods {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut s = String::new();
+        for (on, name) in [(self.ez, "EZ"), (self.nf, "NF"), (self.ht, "HT"), (self.dt, "DT")] {
+            if on {
+                s.push_str(name);
+            }
+        }
+        if s.is_empty() {
+            write!(f, "NM")
+        } else {
+            write!(f, "+{}", s)
+        }
+    }
+}
+
+// 1難易度の計算結果
+#[derive(Debug, Clone)]
+pub struct Attributes {
+    pub beatmap_id: Option<i64>, // .osuに書いていなければNone
+    pub version: String,
+    pub keys: u32,
+    pub stars: f64,
+    pub total_hits: usize, // LNも1つと数える
+}
+
+impl Attributes {
+    // acc(0.0 ~ 1.0)でのpp
+    pub fn pp(&self, acc: f64, mods: &Mods) -> f64 {
+        let mut multiplier = 8.0;
+        if mods.nf {
+            multiplier *= 0.75;
+        }
+        if mods.ez {
+            multiplier *= 0.5;
+        }
+        let difficulty = (self.stars - 0.15).max(0.05).powf(2.2)
+            * (5.0 * acc - 4.0).max(0.0)
+            * (1.0 + 0.1 * (self.total_hits as f64 / 1500.0).min(1.0));
+        difficulty * multiplier
+    }
+}
+
+// ダウンロード済みのbeatmapsetのmania譜面を全て計算する(ダウンロードしていなければNone)
+pub async fn mapset_attributes(db: &DBHandler, id: i64, mods: Mods) -> Result<Option<Vec<Attributes>>, Box<dyn Error + Send + Sync>> {
+    let maps = match osz::load(db, id).await? {
+        Some(m) => m,
+        None => return Ok(None),
+    };
+    // 譜面が多いと時間がかかるのでasyncの処理を止めないようにする
+    let mut attributes = tokio::task::spawn_blocking(move || {
+        maps.iter().filter_map(|(_, map)| calculate(map, &mods)).collect::<Vec<Attributes>>()
+    }).await?;
+    attributes.sort_by(|a, b| a.keys.cmp(&b.keys).then(a.stars.partial_cmp(&b.stars).unwrap_or(std::cmp::Ordering::Equal)));
+    Ok(Some(attributes))
+}
+
+// maniaのnote(endはLNの終点，通常のnoteならstartと同じ)
+#[derive(Debug, Clone, Copy)]
+struct Note {
+    column: usize,
+    start: f64,
+    end: f64,
+}
+
+// mania以外はNone
+pub fn calculate(map: &Beatmap, mods: &Mods) -> Option<Attributes> {
+    let keys = map.keys()?;
+    let notes = map.hit_objects.iter()
+        .map(|o| Note { column: o.column(keys), start: o.time, end: o.end_time() })
+        .collect::<Vec<Note>>();
+    Some(Attributes {
+        beatmap_id: map.metadata.beatmap_id,
+        version: map.metadata.version.clone(),
+        keys,
+        stars: stars(&notes, keys, mods.clock_rate()),
+        total_hits: notes.len(),
+    })
+}
+
+// 各noteの後のstrain(列ごとのものと全体のもの)
+struct Strain {
+    start: f64,
+    column: usize,
+    individual: Vec<f64>,
+    overall: f64,
+    held_until: Vec<f64>,
+}
+
+impl Strain {
+    fn value(&self) -> f64 {
+        self.individual[self.column] + self.overall
+    }
+
+    // time(map上の時刻)まで減衰させた値
+    fn decayed(&self, time: f64, clock_rate: f64) -> f64 {
+        let elapsed = (time - self.start) / clock_rate / 1000.0;
+        self.individual[self.column] * INDIVIDUAL_DECAY.powf(elapsed) + self.overall * OVERALL_DECAY.powf(elapsed)
+    }
+
+    // noteを押した後の値に更新する
+    fn next(&mut self, note: &Note, clock_rate: f64) {
+        let elapsed = (note.start - self.start) / clock_rate / 1000.0;
+        let mut hold_factor = 1.0;
+        let mut hold_addition = 0.0;
+        // LNを押している間に押すnoteは難しい
+        for &held in &self.held_until {
+            if note.start < held && note.end > held {
+                hold_addition = 1.0;
+            }
+            if note.end == held {
+                hold_addition = 0.0;
+            }
+            if held > note.end {
+                hold_factor = 1.25;
+            }
+        }
+        self.held_until[note.column] = note.end;
+
+        let decay = INDIVIDUAL_DECAY.powf(elapsed);
+        for s in self.individual.iter_mut() {
+            *s *= decay;
+        }
+        self.individual[note.column] += 2.0 * hold_factor;
+        self.overall = self.overall * OVERALL_DECAY.powf(elapsed) + (1.0 + hold_addition) * hold_factor;
+        self.start = note.start;
+        self.column = note.column;
+    }
+}
+
+fn stars(notes: &[Note], keys: u32, clock_rate: f64) -> f64 {
+    let first = match notes.first() {
+        Some(n) => n,
+        None => return 0.0,
+    };
+    let keys = keys as usize;
+    let mut held_until = vec![0.0; keys];
+    held_until[first.column] = first.end;
+    let mut strain = Strain { start: first.start, column: first.column, individual: vec![0.0; keys], overall: 1.0, held_until };
+
+    // 区間の長さはmap上の時間なので速度を変えると伸び縮みする
+    let section = SECTION_LENGTH * clock_rate;
+    let mut section_end = (first.start / section).ceil() * section;
+    let mut peaks = Vec::new();
+    let mut peak = strain.value();
+    for note in &notes[1..] {
+        while note.start > section_end {
+            peaks.push(peak);
+            peak = strain.decayed(section_end, clock_rate);
+            section_end += section;
+        }
+        strain.next(note, clock_rate);
+        peak = peak.max(strain.value());
+    }
+    peaks.push(peak);
+
+    peaks.sort_by(|a, b| b.partial_cmp(a).unwrap_or(std::cmp::Ordering::Equal));
+    let mut weight = 1.0;
+    let mut difficulty = 0.0;
+    for p in peaks {
+        difficulty += p * weight;
+        weight *= DECAY_WEIGHT;
+    }
+    difficulty * STAR_SCALING_FACTOR
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_mods() {
+        assert_eq!(Mods::parse("DTNF").unwrap(), Mods { dt: true, nf: true, ..Mods::default() });
+        assert_eq!(Mods::parse(" +ht, nf").unwrap(), Mods { ht: true, nf: true, ..Mods::default() });
+        assert_eq!(Mods::parse("nc").unwrap(), Mods { dt: true, ..Mods::default() });
+        assert_eq!(Mods::parse("ez").unwrap(), Mods { ez: true, ..Mods::default() });
+        // 難易度とppに関係しないmodは無視する
+        assert_eq!(Mods::parse("NM").unwrap(), Mods::default());
+        assert_eq!(Mods::parse("HDHR7K").unwrap(), Mods::default());
+        assert_eq!(Mods::parse("").unwrap(), Mods::default());
+    }
+
+    #[test]
+    fn rejects_invalid_mods() {
+        assert!(Mods::parse("DTN").is_err());
+        assert!(Mods::parse("XX").is_err());
+        assert!(Mods::parse("KK").is_err());
+        assert!(Mods::parse("HTDT").is_err());
+        // 2文字ずつ区切れないもの
+        assert!(Mods::parse("ＤＴ").is_err());
+        assert!(Mods::parse("Dé").is_err());
+    }
+
+    #[test]
+    fn formats_mods() {
+        assert_eq!(Mods::default().to_string(), "NM");
+        assert_eq!(Mods::parse("NFDTEZ").unwrap().to_string(), "+EZNFDT");
+        assert_eq!(Mods::parse("DT").unwrap().clock_rate(), 1.5);
+        assert_eq!(Mods::parse("HT").unwrap().clock_rate(), 0.75);
+        assert_eq!(Mods::default().clock_rate(), 1.0);
+    }
+}
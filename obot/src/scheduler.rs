@@ -1,29 +1,232 @@
-use clokwerk::{TimeUnits, AsyncScheduler};
+use chrono::{TimeZone, Utc};
 use serenity::prelude::*;
 use std::{
-    time::Duration,
-    sync::Arc,
     error::Error,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use crate::web::{
-    handler,
-};
+use crate::config::{self, Config};
+use crate::db::{backup, handler::DBHandler};
+use crate::utility::unix_now;
+use crate::web::handler;
+
+// 次の実行時刻までの最大の待ち時間(reloadでscheduleが変わっても1分以内に反映される)
+const TICK: i64 = 60;
+
+// readyは再接続のたびに呼ばれるので，一度だけ起動する
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+// jobの実行間隔
+// "30m"のような間隔か，cron式("0 0 * * *"のような5項目，または秒から始まる6-7項目，UTC)
+#[derive(Debug, Clone)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(String, Box<cron::Schedule>),
+}
+
+impl Schedule {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if !s.contains(char::is_whitespace) {
+            return config::parse_duration(s).map(Schedule::Every);
+        }
+        // 5項目なら秒(0)を補う
+        let expr = if s.split_whitespace().count() == 5 { format!("0 {}", s) } else { s.to_string() };
+        match cron::Schedule::from_str(&expr) {
+            Ok(c) => Ok(Schedule::Cron(s.to_string(), Box::new(c))),
+            Err(e) => Err(format!("invalid cron expression: {}", e)),
+        }
+    }
+
+    // last(unix time)の次に実行する時刻
+    pub fn next_after(&self, last: i64) -> Option<i64> {
+        match self {
+            Schedule::Every(d) => Some(last + d.as_secs() as i64),
+            Schedule::Cron(_, c) => {
+                let last = Utc.timestamp_opt(last, 0).single()?;
+                c.after(&last).next().map(|t| t.timestamp())
+            },
+        }
+    }
+}
+
+impl PartialEq for Schedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.to_string() == other.to_string()
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Every(d) => write!(f, "every {}s", d.as_secs()),
+            Schedule::Cron(expr, _) => write!(f, "cron \"{}\"", expr),
+        }
+    }
+}
+
+// 定期実行するjob
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Job {
+    Poll,
+    RefreshQualified,
+    DailyDigest,
+    DbBackup,
+    QualifiedReminder,
+    SubscriptionDigest,
+}
+
+impl Job {
+    pub const ALL: [Job; 6] = [
+        Job::Poll, Job::RefreshQualified, Job::DailyDigest, Job::DbBackup, Job::QualifiedReminder, Job::SubscriptionDigest,
+    ];
+
+    // DBに最後の実行時刻を保存するときの名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Job::Poll => "poll",
+            Job::RefreshQualified => "refresh_qualified",
+            Job::DailyDigest => "daily_digest",
+            Job::DbBackup => "db_backup",
+            Job::QualifiedReminder => "qualified_reminder",
+            Job::SubscriptionDigest => "subscription_digest",
+        }
+    }
+
+    pub fn schedule(self, config: &Config) -> &Schedule {
+        match self {
+            Job::Poll => &config.poll_schedule,
+            Job::RefreshQualified => &config.refresh_qualified_schedule,
+            Job::DailyDigest => &config.daily_digest_schedule,
+            Job::DbBackup => &config.db_backup_schedule,
+            Job::QualifiedReminder => &config.qualified_reminder_schedule,
+            Job::SubscriptionDigest => &config.subscription_digest_schedule,
+        }
+    }
+
+    async fn run(self, ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match self {
+            Job::Poll => handler::check_maps(ctx).await,
+            Job::RefreshQualified => handler::refresh_qualified(ctx).await,
+            Job::DailyDigest => handler::daily_digest(ctx).await,
+            Job::DbBackup => backup::backup_database(ctx).await,
+            Job::QualifiedReminder => handler::remind_qualified(ctx).await,
+            Job::SubscriptionDigest => handler::send_digests(ctx).await,
+        }
+    }
+}
+
+// 全てのjobを起動する(2回目以降は何もしない)
+pub fn start(ctx: &Context) {
+    if STARTED.swap(true, Ordering::SeqCst) {
+        info!("Scheduler is already running");
+        return;
+    }
+    let ctx = Arc::new(ctx.clone());
+    for job in Job::ALL {
+        tokio::spawn(run_job(ctx.clone(), job));
+    }
+}
+
+// jobごとのloop
+// 最後の実行時刻はDBに保存し，停止中に実行時刻を過ぎていれば起動後すぐに1回だけ実行する
+async fn run_job(ctx: Arc<Context>, job: Job) {
+    let db = DBHandler::new(&ctx).await;
+    let mut last_run = match db.job_last_run(job.name()).await {
+        Ok(Some(t)) => t,
+        // 初めて起動したときは，そこから数える
+        Ok(None) => {
+            let now = unix_now();
+            if let Err(e) = db.set_job_last_run(job.name(), now).await {
+                error!("Failed to save last run of {}: {}", job.name(), e);
+            }
+            now
+        },
+        Err(e) => {
+            error!("Failed to get last run of {}: {}", job.name(), e);
+            unix_now()
+        }
+    };
+
+    loop {
+        let schedule = match Config::shared(&ctx).await {
+            Ok(c) => job.schedule(&c).clone(),
+            Err(e) => {
+                error!("Failed to get config: {}", e);
+                tokio::time::sleep(Duration::from_secs(TICK as u64)).await;
+                continue;
+            }
+        };
+        let now = unix_now();
+        let next = match schedule.next_after(last_run) {
+            Some(t) => t,
+            None => {
+                warn!("Job {} ({}) has no next run", job.name(), schedule);
+                tokio::time::sleep(Duration::from_secs(TICK as u64)).await;
+                continue;
+            }
+        };
+        if next > now {
+            tokio::time::sleep(Duration::from_secs((next - now).min(TICK) as u64)).await;
+            continue;
+        }
+
+        if now - next > TICK {
+            info!("Job {} missed its run at {}, catching up", job.name(), next);
+        }
+        info!("Running job {} ({})", job.name(), schedule);
+        if let Err(e) = job.run(&ctx).await {
+            error!("Job {} failed: {}", job.name(), e);
+        }
+        // 失敗しても次の実行時刻まで待つ(すぐに繰り返さない)
+        last_run = now;
+        if let Err(e) = db.set_job_last_run(job.name(), last_run).await {
+            error!("Failed to save last run of {}: {}", job.name(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-03 12:00 UTC
+    const NOON: i64 = 1704283200;
+
+    #[test]
+    fn parses_interval() {
+        let s = Schedule::parse(" 30m ").unwrap();
+        assert!(matches!(s, Schedule::Every(d) if d == Duration::from_secs(30 * 60)));
+        assert_eq!(s.next_after(NOON), Some(NOON + 30 * 60));
+        assert_eq!(s.to_string(), "every 1800s");
+        assert!(Schedule::parse("0m").is_err());
+        assert!(Schedule::parse("soon").is_err());
+    }
+
+    #[test]
+    fn parses_cron() {
+        // 5項目は分から(秒は0)
+        let s = Schedule::parse("0 0 * * *").unwrap();
+        assert_eq!(s.next_after(NOON), Some(NOON + 12 * 60 * 60));
+        assert_eq!(s.to_string(), "cron \"0 0 * * *\"");
+        // 6項目は秒から
+        let s = Schedule::parse("30 15 12 * * *").unwrap();
+        assert_eq!(s.next_after(NOON), Some(NOON + 15 * 60 + 30));
+        // 実行時刻ちょうどは含まない
+        assert_eq!(Schedule::parse("0 12 * * *").unwrap().next_after(NOON), Some(NOON + 24 * 60 * 60));
+        assert_eq!(Schedule::parse("0 0 * * *").unwrap(), Schedule::parse(" 0 0 * * * ").unwrap());
+    }
 
-pub async fn ascheduler(ctx: Arc<Context>) -> Result<(), Box<dyn Error>> {
-    let mut scheduler = AsyncScheduler::new();
-    let ctx_clone = ctx.clone();
-    scheduler.every(30.minutes()).run(move || {
-        let ctx = ctx_clone.clone();
-        async move {
-            let _ = handler::check_maps(&ctx).await;
-        }
-    });
-    tokio::spawn(async move {
-        loop {
-            scheduler.run_pending().await;
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
-    });
-    Ok(())
-}
\ No newline at end of file
+    #[test]
+    fn rejects_invalid_cron() {
+        assert!(Schedule::parse("0 0 * *").is_err());
+        assert!(Schedule::parse("0 25 * * *").is_err());
+        assert!(Schedule::parse("every day").is_err());
+    }
+}
@@ -1,23 +1,94 @@
-use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use serenity::prelude::*;
+// Embedのfieldの値の上限(文字数)
+pub const EMBED_FIELD_LIMIT: usize = 1024;
 
-use crate::cache::*;
+// 現在時刻(unix time, 秒)
+pub fn unix_now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
 
-pub fn env_helper(key: &str) -> String {
-    match env::var(key) {
-        Ok(v) => v,
-        Err(e) => {
-            panic!("Failed to load environment variable {}: {}", key, e);
+// 行をmax_lines行，max_chars文字以内でつなげる
+// 入りきらない行は"...and N more"にまとめ，最初の1行だけで超えるときは切り詰める
+pub fn join_lines(lines: &[String], max_lines: usize, max_chars: usize) -> String {
+    let total = lines.iter().map(|l| l.chars().count() + 1).sum::<usize>();
+    if lines.len() <= max_lines && total <= max_chars + 1 {
+        return lines.join("\n");
+    }
+    // "...and N more"の分を空けておく
+    let budget = max_chars.saturating_sub(20);
+    let mut out = String::new();
+    let mut len = 0;
+    for (i, line) in lines.iter().enumerate() {
+        let n = line.chars().count() + usize::from(i > 0);
+        if (i > 0 && i >= max_lines) || len + n > budget {
+            if i == 0 {
+                out = line.chars().take(budget.saturating_sub(1)).collect::<String>() + "~";
+                len = budget;
+                if lines.len() == 1 {
+                    return out;
+                }
+                continue;
+            }
+            out.push_str(&format!("\n...and {} more", lines.len() - i));
+            return out;
         }
+        if i > 0 {
+            out.push('\n');
+        }
+        out.push_str(line);
+        len += n;
     }
+    out
 }
 
-pub async fn get_env_from_context(ctx: &Context, key: &str) -> String {
-    match ctx.data.read().await.get::<Env>().unwrap().lock().await.get(key) {
-        Some(v) => v.clone(),
-        None => {
-            panic!("Failed to load environment variable {}: {}", key, "Not found");
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(n: usize, len: usize) -> Vec<String> {
+        (0..n).map(|i| format!("{:0>width$}", i, width = len)).collect()
+    }
+
+    #[test]
+    fn keeps_short_lines() {
+        assert_eq!(join_lines(&lines(3, 2), 10, 1024), "00\n01\n02");
+        assert_eq!(join_lines(&[], 10, 1024), "");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn fits_exactly() {
+        // 3 * 10 + 改行2 = 32
+        let l = lines(3, 10);
+        assert_eq!(join_lines(&l, 3, 32).chars().count(), 32);
+    }
+
+    #[test]
+    fn summarizes_overflow() {
+        let s = join_lines(&lines(100, 30), usize::MAX, EMBED_FIELD_LIMIT);
+        assert!(s.chars().count() <= EMBED_FIELD_LIMIT);
+        assert!(s.ends_with("more"));
+        // 日本語も文字数で数える
+        let l = (0..100).map(|_| "あ".repeat(30)).collect::<Vec<String>>();
+        assert!(join_lines(&l, usize::MAX, EMBED_FIELD_LIMIT).chars().count() <= EMBED_FIELD_LIMIT);
+    }
+
+    #[test]
+    fn caps_line_count() {
+        assert_eq!(join_lines(&lines(12, 2), 10, 1024), "00\n01\n02\n03\n04\n05\n06\n07\n08\n09\n...and 2 more");
+        assert_eq!(join_lines(&lines(10, 2), 10, 1024).lines().count(), 10);
+    }
+
+    #[test]
+    fn truncates_long_line() {
+        let s = join_lines(&["a".repeat(2000)], 10, EMBED_FIELD_LIMIT);
+        assert!(s.chars().count() <= EMBED_FIELD_LIMIT);
+        assert!(s.ends_with('~'));
+        let s = join_lines(&["a".repeat(2000), "b".to_string()], 10, EMBED_FIELD_LIMIT);
+        assert!(s.chars().count() <= EMBED_FIELD_LIMIT);
+        assert!(s.ends_with("...and 1 more"));
+    }
+}
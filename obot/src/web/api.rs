@@ -3,14 +3,18 @@
 use std::{
     collections::{HashMap},
     error::Error,
-    fs::File,
-    mem,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use futures::future;
-use serde_json::{Value};
+use serde::de::DeserializeOwned;
 use serenity::prelude::*;
 
-use crate::utility;
+use crate::cache::OsuApi;
+use crate::config::{self, Config};
+use super::error::ApiError;
+use super::mode::GameMode;
+use super::models::{ApiBeatmapset, BeatmapsetSearch, TokenResponse};
+use super::ratelimit::RateLimiter;
 
 #[derive(Debug, Clone)]
 pub struct Beatmap {
@@ -18,99 +22,249 @@ pub struct Beatmap {
     pub title: String,
     pub artist: String,
     pub creator: String,
-    pub stars: String, // ","で区切って文字列にして保存
-    pub keys: String,  // starと同じ(順番は揃えること！)
     pub mp3_url: String,
     pub card_url: String,
     pub cursor: String,
-    pub statu: String, // db的には不要
+    pub status: String, // ranked, loved, qualified...
+    pub ranked_date: Option<i64>, // unix time．qualifiedならqualifiedになった日時
+    pub tags: String, // 空白区切り
+    pub user_id: Option<i64>, // mapper
+    pub difficulties: Vec<Difficulty>,
 }
 
+// beatmapset内の各難易度(DBのbeatmapsテーブルの1行)
+#[derive(Debug, Clone)]
+pub struct Difficulty {
+    pub id: i64,
+    pub beatmapset_id: i64,
+    pub mode: GameMode,
+    pub version: String,
+    pub difficulty_rating: f64,
+    pub keys: Option<i64>, // maniaのみ
+    pub cs: f64,
+    pub ar: f64,
+    pub od: f64,
+    pub hp: f64,
+    pub bpm: f64,
+    pub total_length: i64, // 秒
+    pub count_notes: i64,
+    pub count_lns: i64,
+}
+
+
+impl Difficulty {
+    pub fn label(&self) -> String {
+        self.mode.group_label(self.keys)
+    }
+}
+
+// 期限切れの少し前に取り直す
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(5 * 60);
 
 #[derive(Debug, Clone)]
+struct Token {
+    access_token: String,
+    expires_at: Instant,
+}
+
+impl Token {
+    fn is_fresh(&self) -> bool {
+        Instant::now() + TOKEN_REFRESH_MARGIN < self.expires_at
+    }
+}
+
+// bot全体で1つだけ作ってTypeMap(OsuApi)に入れて使い回す
+// tokenは必要になった時点で取得し，期限が近づいたら取り直す
+// APIもdownloadも全てlimiterを通す
+#[derive(Debug)]
 pub struct Api {
     pub http: reqwest::Client,
     pub user_id: u64,
     pub secret: String,
     pub base_url: String,
     pub download_base_url: String,
-    token: String,
+    token: RwLock<Option<Token>>,
+    limiter: RateLimiter,
 }
 
 pub async fn get_url(ctx: &Context, beatmap: &Beatmap) -> String {
-    let base_url = utility::get_env_from_context(ctx, "api_base").await;
-    format!("{}/beatmapsets/{}", base_url, beatmap.id)
+    format!("{}/beatmapsets/{}", base_url(ctx).await, beatmap.id)
 }
 
-impl Api {
-    pub async fn new(ctx: &Context) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let secret = utility::get_env_from_context(ctx, "api_secret").await;
-        let user_id = utility::get_env_from_context(ctx, "user_id").await.parse::<u64>()?;
-        let base_url = utility::get_env_from_context(ctx, "api_base").await;
-        let download_base_url = utility::get_env_from_context(ctx, "download_base").await;
-        
-        // get token
-        let url = format!("{}/oauth/token", base_url);
-        let mut params = HashMap::new();
-        params.insert("client_id", user_id.to_string());
-        params.insert("client_secret", secret.to_string());
-        params.insert("grant_type", "client_credentials".to_string());
-        params.insert("scope", "public".to_string());
-        let token = match reqwest::Client::new().post(&url)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .form(&params)
-            .send()
-            .await {
-                Ok(res) => {
-                    match res.text().await {
-                        Ok(text) => {
-                            let json: Value = serde_json::from_str(&text)?;
-                            let token = json["access_token"].as_str().unwrap();
-                            token.to_string()
-                        },
-                        Err(e) => return Err(Box::new(e)),
-                    }
-                }
-                Err(e) => return Err(Box::new(e)),
-            };
+// mapperのプロフィール(user_idが分からない古いデータは名前で)
+pub async fn get_user_url(ctx: &Context, beatmap: &Beatmap) -> String {
+    match beatmap.user_id {
+        Some(id) => format!("{}/users/{}", base_url(ctx).await, id),
+        None => format!("{}/users/{}", base_url(ctx).await, beatmap.creator.replace(' ', "%20")),
+    }
+}
 
-        let http = reqwest::Client::new();
-        let api = Api {
-            http,
+async fn base_url(ctx: &Context) -> String {
+    match Config::shared(ctx).await {
+        Ok(c) => c.api_base_str().to_string(),
+        Err(e) => {
+            warn!("Failed to get config: {}", e);
+            config::DEFAULT_API_BASE.to_string()
+        }
+    }
+}
+
+impl Beatmap {
+    // APIのbeatmapsetから変換
+    // preview_urlやcoversが欠けている場合は空文字列にしておく
+    pub fn from_api(beatmapset: &ApiBeatmapset, cursor: &str) -> Self {
+        let card_url = beatmapset.covers.as_ref()
+            .and_then(|c| c.card_2x.clone().or_else(|| c.card.clone()))
+            .unwrap_or_default();
+        let mp3_url = match &beatmapset.preview_url {
+            Some(p) if p.starts_with("//") => format!("https:{}", p),
+            Some(p) => p.clone(),
+            None => String::new(),
+        };
+        // 知らないmodeの難易度は捨てる
+        let difficulties = beatmapset.beatmaps.iter()
+            .filter_map(|b| GameMode::from_name(&b.mode).map(|mode| (mode, b)))
+            .map(|(mode, b)| Difficulty {
+                id: b.id,
+                beatmapset_id: beatmapset.id,
+                mode,
+                version: b.version.clone(),
+                difficulty_rating: b.difficulty_rating,
+                // maniaではcsがキー数
+                keys: mode.has_keys().then(|| b.cs.unwrap_or_default().round() as i64),
+                cs: b.cs.unwrap_or_default(),
+                ar: b.ar.unwrap_or_default(),
+                od: b.accuracy.unwrap_or_default(),
+                hp: b.drain.unwrap_or_default(),
+                bpm: b.bpm.unwrap_or_default(),
+                total_length: b.total_length.unwrap_or_default(),
+                count_notes: b.count_circles.unwrap_or_default(),
+                count_lns: b.count_sliders.unwrap_or_default(),
+            })
+            .collect::<Vec<Difficulty>>();
+
+        Beatmap {
+            id: beatmapset.id,
+            title: beatmapset.title.clone(),
+            artist: beatmapset.artist.clone(),
+            creator: beatmapset.creator.clone(),
+            mp3_url,
+            card_url,
+            cursor: cursor.to_string(),
+            status: beatmapset.status.clone(),
+            ranked_date: beatmapset.ranked_date.as_deref()
+                .and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+                .map(|d| d.timestamp()),
+            tags: beatmapset.tags.clone().unwrap_or_default(),
+            user_id: beatmapset.user_id,
+            difficulties,
+        }
+    }
+}
+
+// statusを確認してからJSONをTに変換する
+// 200以外やJSONの形が違う場合はApiErrorを返す
+async fn parse_response<T: DeserializeOwned>(res: reqwest::Response) -> Result<T, ApiError> {
+    let status = res.status();
+    let text = res.text().await?;
+    if !status.is_success() {
+        return Err(ApiError::from_status(status, &text));
+    }
+    serde_json::from_str::<T>(&text).map_err(|e| ApiError::decode(e, &text))
+}
+
+impl Api {
+    pub fn new(
+        user_id: u64,
+        secret: String,
+        base_url: String,
+        download_base_url: String,
+        requests_per_minute: u32,
+    ) -> Self {
+        Api {
+            http: reqwest::Client::new(),
             user_id,
             secret,
             base_url,
             download_base_url,
-            token,
-        };
+            token: RwLock::new(None),
+            limiter: RateLimiter::new(requests_per_minute),
+        }
+    }
 
-        Ok(api)
+    // TypeMapに入っている共有clientを取り出す
+    pub async fn shared(ctx: &Context) -> Result<Arc<Self>, Box<dyn Error + Send + Sync>> {
+        match ctx.data.read().await.get::<OsuApi>() {
+            Some(api) => Ok(api.clone()),
+            None => Err("Api is not found in TypeMap".into()),
+        }
+    }
+
+    // 有効なtokenを返す(期限が近ければ取り直す)
+    async fn access_token(&self) -> Result<String, ApiError> {
+        if let Some(token) = self.token.read().await.as_ref() {
+            if token.is_fresh() {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let mut token = self.token.write().await;
+        // 他のtaskが先に取り直しているかもしれない
+        if let Some(t) = token.as_ref() {
+            if t.is_fresh() {
+                return Ok(t.access_token.clone());
+            }
+        }
+        let new_token = self.request_token().await?;
+        let access_token = new_token.access_token.clone();
+        *token = Some(new_token);
+        Ok(access_token)
+    }
+
+    // 401が返ってきたときなど，cacheしているtokenを捨てる
+    async fn invalidate_token(&self) {
+        *self.token.write().await = None;
+    }
+
+    async fn request_token(&self) -> Result<Token, ApiError> {
+        let url = format!("{}/oauth/token", self.base_url);
+        let mut params = HashMap::new();
+        params.insert("client_id", self.user_id.to_string());
+        params.insert("client_secret", self.secret.to_string());
+        params.insert("grant_type", "client_credentials".to_string());
+        params.insert("scope", "public".to_string());
+        let res = self.limiter.send(|| {
+            self.http.post(&url)
+                .header("Accept", "application/json")
+                .form(&params)
+        }).await?;
+        let token = parse_response::<TokenResponse>(res).await?;
+        info!("Fetched new osu! api token (expires in {}s)", token.expires_in);
+
+        Ok(Token {
+            access_token: token.access_token,
+            expires_at: Instant::now() + Duration::from_secs(token.expires_in),
+        })
     }
 
     pub async fn get_beatmapsets_with_cursor(
         &self,
-        mode: &str, // mania: 3
+        mode: GameMode,
         status: &str, // ranked, loved, qualified, pending, graveyard
-        key: &str, // 4, 7...
+        key: &str, // 4, 7... (maniaのみ，空なら全てのキー数)
         cursor_string: &str,
-    ) -> Result<(Vec<Beatmap>, String), Box<dyn Error + Send + Sync>> {
-        let url = format!("{}/api/v2/beatmapsets/search?m={}&s={}&q=key%3D{}&nsfw=&cursor_string={}",
-        self.base_url, mode, status, key, cursor_string);
-        let text = match self.req_with_token(&url).await {
-            Ok(text) => text,
-            Err(e) => return Err(e),
-        };
-        let beatmapsets = match self.text2beatmapsets(&text) {
-            Ok((beatmapsets, cursor)) => {
-                let mut mapsets = beatmapsets.clone();
-                mapsets.reverse(); // 新しいものを配列中で最後尾にする => DBへの追加順を考慮
-                (mapsets, cursor)
-            },
-            Err(e) => return Err(e.to_string().into()),
-        };
+    ) -> Result<(Vec<Beatmap>, String), ApiError> {
+        let query = if key.is_empty() || !mode.has_keys() { String::new() } else { format!("&q=key%3D{}", key) };
+        let url = format!("{}/api/v2/beatmapsets/search?m={}&s={}{}&nsfw=&cursor_string={}",
+        self.base_url, mode.id(), status, query, cursor_string);
+        let search: BeatmapsetSearch = self.req_with_token(&url).await?;
+        let cursor = search.cursor_string.unwrap_or_default();
+        let mut mapsets = search.beatmapsets.iter()
+            .map(|b| Beatmap::from_api(b, &cursor))
+            .collect::<Vec<Beatmap>>();
+        mapsets.reverse(); // 新しいものを配列中で最後尾にする => DBへの追加順を考慮
 
-        Ok(beatmapsets)
+        Ok((mapsets, cursor))
     }
 
     // idからbeatmapset構造体を取得
@@ -121,138 +275,56 @@ impl Api {
         let mut bmsets = Vec::new();
         for id in ids {
             let url = &format!("{}/api/v2/beatmapsets/{}", self.base_url, id);
-            let text = match self.req_with_token(&url).await {
-                Ok(text) => text,
+            let beatmapset: ApiBeatmapset = match self.req_with_token(url).await {
+                Ok(b) => b,
                 Err(e) => {
-                    error!("req_with_token error: {}", e);
+                    error!("Failed to get beatmapset {}: {}", id, e);
                     continue;
                 }
             };
-            let beatmapsets = match self.text2beatmapsets(&text) {
-                Ok((beatmapsets, _)) => beatmapsets,
-                Err(e) => {
-                    error!("text2beatmapsets error: {}", e);
-                    continue;
-                }
-            };
-            bmsets.extend(beatmapsets);
+            bmsets.push(Beatmap::from_api(&beatmapset, ""));
         }
         Ok(bmsets)
     }
 
-    // 譜面download用method
-    pub async fn download_beatmaps(
-        &self,
-        maps: Vec<Beatmap>,
-        path: &str,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let mut tasks = Vec::new();
-        for (i, beatmapset) in maps.iter().enumerate() {
-            tasks.push(self.download(beatmapset, path));
-            if (i + 1) % 5 == 0 || i == maps.len() - 1 {
-                // 5つずつ
-                // TODO: この不細工な実装をどうにかする
-                let mut tasks_copy = Vec::new();
-                mem::swap(&mut tasks, &mut tasks_copy);
-                let ret = future::join_all(tasks_copy)
-                    .await
-                    .into_iter()
-                    .collect::<Result<Vec<()>, Box<dyn Error + Send + Sync>>>();
-                match ret {
-                    Ok(_) => (),
-                    Err(e) => warn!("download was failed: {}", e),
-                }
-                tasks.clear();
-            }
-        }
-
-        Ok(())
-    }
-
     // private
-    async fn req_with_token(
+    // 401の場合はtokenを取り直して1回だけretryする
+    async fn req_with_token<T: DeserializeOwned>(
         &self,
         url: &str,
-    ) -> Result<String, Box<dyn Error + Send + Sync>> {
-        let res = self.http.get(url)
-            .header("Content-Type", "application/json")
-            .header("Accept", "application/json")
-            .header("Authorization", format!("Bearer {}", self.token))
-            .send()
-            .await?;
-        let text = res.text().await?;
-        Ok(text)
-    }
-
-    // private
-    async fn download(&self, beatmapset: &Beatmap, path: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let url = format!("{}/{}?n=1", self.download_base_url, beatmapset.id);
-        let res = self.http.get(&url).send().await?;
-        let _ = std::fs::create_dir_all(format!("{}{}", path, beatmapset.statu));
-        let mut file = File::create(format!("{}{}/{}-{}.osz", path, beatmapset.statu, beatmapset.id, beatmapset.title))?;
-        let mut content = std::io::Cursor::new(res.bytes().await?);
-        std::io::copy(&mut content, &mut file)?;
-        Ok(())
-    }
-
-    fn text2beatmapsets(&self, text: &str) -> Result<(Vec<Beatmap>, String), Box<dyn Error>> {
-        let json: Value = serde_json::from_str(&text)?;
-        let mut beatmapsets = Vec::new();
-    
-        if let Some(_) = json["artist"].as_str() {
-            beatmapsets.push(&json);
-        } else {
-            for beatmapset in json["beatmapsets"].as_array().unwrap() {
-                beatmapsets.push(beatmapset);
+    ) -> Result<T, ApiError> {
+        let mut retried = false;
+        loop {
+            let token = self.access_token().await?;
+            let res = self.limiter.send(|| {
+                self.http.get(url)
+                    .header("Content-Type", "application/json")
+                    .header("Accept", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+            }).await?;
+            if res.status() == reqwest::StatusCode::UNAUTHORIZED && !retried {
+                warn!("osu! api returned 401, refreshing token");
+                self.invalidate_token().await;
+                retried = true;
+                continue;
             }
+            return parse_response(res).await;
         }
-
-        let cursor_string = match json["cursor_string"].as_str() {
-            Some(cursor_string) => cursor_string.to_string(),
-            None => "".to_string(),
-        };
-    
-        let beatmapsets = beatmapsets.iter().map(|beatmapset| {
-            let title = beatmapset["title"].as_str().unwrap();
-            let artist = beatmapset["artist"].as_str().unwrap();
-            let creator = beatmapset["creator"].as_str().unwrap();
-            let card_url = beatmapset["covers"]["card@2x"].as_str().unwrap();
-            let mp3_url = format!("https:{}",
-                beatmapset["preview_url"].as_str().unwrap()
-            );
-            let id = beatmapset["id"].as_u64().unwrap();
-            let beatmaps = beatmapset["beatmaps"].as_array().unwrap();
-            let status = beatmaps[0]["status"].as_str().unwrap();
-            let star = beatmaps.iter().map(|beatmap| {
-                beatmap["difficulty_rating"].as_f64().unwrap() as f64
-            }).collect::<Vec<f64>>();
-            let key = beatmaps.iter().map(|beatmap| {
-                beatmap["cs"].as_f64().unwrap() as f64
-            }).collect::<Vec<f64>>();
-
-            let mut star_str = String::new();
-            let mut key_str = String::new();
-            for (s, k) in star.iter().zip(key.iter()) {
-                star_str.push_str(&format!("{},", s));
-                key_str.push_str(&format!("{},", k));
-            }
-            star_str = star_str[0..star_str.len() - 1].to_string();
-            key_str = key_str[0..key_str.len() - 1].to_string();
-
-            Beatmap {
-                id: id as i64,
-                title: title.to_string(),
-                artist: artist.to_string(),
-                creator: creator.to_string(),
-                stars: star_str,
-                keys: key_str,
-                card_url: card_url.to_string(),
-                mp3_url,
-                cursor: cursor_string.to_string(),
-                statu: status.to_string(),
-            }
-        }).collect::<Vec<Beatmap>>();
-        Ok((beatmapsets, cursor_string))
     }
 
+    // .oszのダウンロードを始める(offsetが0でなければその位置から)
+    // 200(全体)か206(offsetから)以外はエラー
+    pub async fn download_response(&self, id: i64, offset: u64) -> Result<reqwest::Response, ApiError> {
+        let url = format!("{}/{}?n=1", self.download_base_url, id);
+        let res = self.limiter.send(|| {
+            let req = self.http.get(&url);
+            if offset > 0 { req.header("Range", format!("bytes={}-", offset)) } else { req }
+        }).await?;
+        let status = res.status();
+        if status != reqwest::StatusCode::OK && status != reqwest::StatusCode::PARTIAL_CONTENT {
+            let body = res.text().await.unwrap_or_default();
+            return Err(ApiError::from_status(status, &body));
+        }
+        Ok(res)
+    }
 }
@@ -0,0 +1,213 @@
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::{self, File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use crate::db::handler::{DBHandler, Download, DownloadJob};
+use crate::utility::unix_now;
+use super::api::Api;
+
+// .osz(zip)の先頭4バイト
+const ZIP_MAGIC: [u8; 4] = *b"PK\x03\x04";
+// 途中までダウンロードしたファイルの拡張子(次回はここから続ける)
+const PART_SUFFIX: &str = ".part";
+// ファイル名(拡張子を除く)の最大バイト数
+// 多くのファイルシステムの上限は255バイト(ASCIIだけの名前は以前と同じ長さで切る)
+const MAX_NAME_LEN: usize = 150;
+
+// ダウンロード中の進み具合(/downloadsで表示する)
+#[derive(Debug, Default)]
+pub struct Progress {
+    pub done: AtomicU64,
+    pub total: AtomicU64, // 0なら不明
+}
+
+impl Progress {
+    pub fn get(&self) -> (u64, u64) {
+        (self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DownloadOutcome {
+    Downloaded(Download),
+    // ダウンロード済み(statusが変わって別のディレクトリに入っている場合も含む)
+    Skipped(PathBuf),
+}
+
+// {dir}{status}/{id}-{title}.osz にダウンロードしてdownloadsテーブルに記録する
+// 一時ファイルに書いてから，zipであることを確かめて名前を変える
+pub async fn download(api: &Api, db: &DBHandler, job: &DownloadJob, dir: &str, progress: &Progress) -> Result<DownloadOutcome, Box<dyn Error + Send + Sync>> {
+    let id = job.beatmapset_id;
+    if let Some(d) = db.get_download(id).await? {
+        if Path::new(&d.path).exists() {
+            return Ok(DownloadOutcome::Skipped(PathBuf::from(d.path)));
+        }
+        warn!("Downloaded file of {} is missing ({}), downloading again", id, d.path);
+    }
+
+    let path = Path::new(dir).join(&job.status).join(file_name(id, &job.title));
+    // downloadsテーブルができる前にダウンロードしたもの
+    if path.exists() {
+        let (size, sha256) = hash_file(&path).await?;
+        record(db, id, &path, size, sha256).await?;
+        return Ok(DownloadOutcome::Skipped(path));
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let part = PathBuf::from(format!("{}{}", path.display(), PART_SUFFIX));
+    let (size, sha256) = match fetch(api, id, &part, progress).await {
+        Ok(r) => r,
+        Err(e) => return Err(format!("Failed to download {}: {}", id, e).into()),
+    };
+    if !is_zip(&part).await? {
+        // エラーページなどをそのまま保存しないように消す
+        let _ = fs::remove_file(&part).await;
+        return Err(format!("Downloaded file of {} is not a zip", id).into());
+    }
+    fs::rename(&part, &path).await?;
+
+    let d = record(db, id, &path, size, sha256).await?;
+    Ok(DownloadOutcome::Downloaded(d))
+}
+
+// partに書き込みながらsha256を計算する
+// partが残っていれば続きから(サーバーが対応していなければ最初から)
+async fn fetch(api: &Api, id: i64, part: &Path, progress: &Progress) -> Result<(i64, String), Box<dyn Error + Send + Sync>> {
+    // 残っているpartがzipでなければ(エラーページなど)最初から
+    let offset = match fs::metadata(part).await {
+        Ok(m) if is_zip(part).await? => m.len(),
+        _ => 0,
+    };
+    let mut res = api.download_response(id, offset).await?;
+
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut file = if offset > 0 && res.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        info!("Resuming download of {} from {} bytes", id, offset);
+        size = hash_into(part, &mut hasher).await?;
+        OpenOptions::new().append(true).open(part).await?
+    } else {
+        File::create(part).await?
+    };
+    progress.done.store(size as u64, Ordering::Relaxed);
+    progress.total.store(res.content_length().map(|l| l + size as u64).unwrap_or(0), Ordering::Relaxed);
+
+    while let Some(chunk) = res.chunk().await? {
+        hasher.update(&chunk);
+        file.write_all(&chunk).await?;
+        size += chunk.len() as i64;
+        progress.done.store(size as u64, Ordering::Relaxed);
+    }
+    file.flush().await?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+async fn record(db: &DBHandler, id: i64, path: &Path, size: i64, sha256: String) -> Result<Download, Box<dyn Error + Send + Sync>> {
+    let d = Download {
+        beatmapset_id: id,
+        path: path.to_string_lossy().to_string(),
+        size,
+        sha256,
+        downloaded_at: unix_now(),
+    };
+    db.insert_download(&d).await?;
+    Ok(d)
+}
+
+async fn hash_file(path: &Path) -> Result<(i64, String), Box<dyn Error + Send + Sync>> {
+    let mut hasher = Sha256::new();
+    let size = hash_into(path, &mut hasher).await?;
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+// ファイルの内容をhasherに入れてサイズを返す
+async fn hash_into(path: &Path, hasher: &mut Sha256) -> Result<i64, Box<dyn Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    let mut buf = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        size += n as i64;
+    }
+    Ok(size)
+}
+
+async fn is_zip(path: &Path) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    let mut file = File::open(path).await?;
+    let mut magic = [0; 4];
+    match file.read_exact(&mut magic).await {
+        Ok(_) => Ok(magic == ZIP_MAGIC),
+        // 4バイトもない
+        Err(_) => Ok(false),
+    }
+}
+
+// "123-title.osz"(以前と同じ名前なので，前にダウンロードしたファイルもそのまま使える)
+pub fn file_name(id: i64, title: &str) -> String {
+    format!("{}.osz", sanitize(&format!("{}-{}", id, title)))
+}
+
+// ファイル名に使えない文字(/ \ : * ? " < > | と制御文字)を_にする
+// MAX_NAME_LENバイトを超える分は文字の途中で切らないように削る
+// 末尾の.と空白はWindowsで消えてしまうので取り除く
+pub fn sanitize(name: &str) -> String {
+    let mut s = String::new();
+    for c in name.chars() {
+        let c = match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        };
+        if s.len() + c.len_utf8() > MAX_NAME_LEN {
+            break;
+        }
+        s.push(c);
+    }
+    s.trim_end_matches(['.', ' ']).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replaces_invalid_chars() {
+        assert_eq!(sanitize(r#"a/b\c:d*e?f"g<h>i|j"#), "a_b_c_d_e_f_g_h_i_j");
+        assert_eq!(sanitize("tab\there\nnew"), "tab_here_new");
+        assert_eq!(sanitize("Camellia - Ghost (feat. 初音ミク)"), "Camellia - Ghost (feat. 初音ミク)");
+    }
+
+    #[test]
+    fn trims_trailing_dots_and_spaces() {
+        assert_eq!(sanitize("title... "), "title");
+        assert_eq!(sanitize(" . "), "");
+    }
+
+    #[test]
+    fn truncates_by_bytes() {
+        assert_eq!(sanitize(&"a".repeat(300)).len(), MAX_NAME_LEN);
+        // 3バイトの文字は途中で切らない
+        let s = sanitize(&"あ".repeat(100));
+        assert_eq!(s.len(), MAX_NAME_LEN / 3 * 3);
+        assert!(s.chars().all(|c| c == 'あ'));
+        let s = sanitize(&format!("a{}", "🎵".repeat(100)));
+        assert!(s.len() <= MAX_NAME_LEN);
+        assert_eq!(s.chars().count(), 1 + (MAX_NAME_LEN - 1) / 4);
+        // 拡張子と".part"を付けても255バイトに収まる
+        assert!(sanitize(&"あ".repeat(200)).len() + ".osz.part".len() <= 255);
+    }
+}
@@ -0,0 +1,67 @@
+use std::{
+    error::Error,
+    fmt,
+};
+
+use super::models::ApiErrorBody;
+
+// osu! APIとのやりとりで起こるエラー
+// check_mapsなどではこれをlogに出して次の譜面に進む
+#[derive(Debug)]
+pub enum ApiError {
+    // 通信自体に失敗
+    Http(reqwest::Error),
+    // 200以外が返ってきた
+    Status {
+        status: reqwest::StatusCode,
+        message: Option<String>,
+    },
+    // JSONが期待した形ではなかった(フィールド欠け，型違いなど)
+    Decode {
+        source: serde_json::Error,
+        body: String, // 先頭だけ保持
+    },
+}
+
+impl ApiError {
+    pub fn from_status(status: reqwest::StatusCode, body: &str) -> Self {
+        let message = serde_json::from_str::<ApiErrorBody>(body)
+            .ok()
+            .and_then(|b| b.message());
+        ApiError::Status { status, message }
+    }
+
+    pub fn decode(source: serde_json::Error, body: &str) -> Self {
+        ApiError::Decode {
+            source,
+            body: body.chars().take(200).collect(),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::Http(e) => write!(f, "request failed: {}", e),
+            ApiError::Status { status, message: Some(m) } => write!(f, "api returned {}: {}", status, m),
+            ApiError::Status { status, message: None } => write!(f, "api returned {}", status),
+            ApiError::Decode { source, body } => write!(f, "malformed response ({}): {}", source, body),
+        }
+    }
+}
+
+impl Error for ApiError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ApiError::Http(e) => Some(e),
+            ApiError::Decode { source, .. } => Some(source),
+            ApiError::Status { .. } => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Http(e)
+    }
+}
@@ -1,100 +1,295 @@
 use std::{
+    borrow::Cow,
     error::Error,
     time,
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
 };
 use serenity::{
+    builder::CreateEmbed,
     model::{prelude::*},
     prelude::*,
 };
-use itertools::Itertools;
 
+use crate::config::Config;
+use crate::scheduler::Job;
+use crate::downloader;
+use crate::osu::{graph, osz, pattern::{self, Pattern}};
+use crate::pp::{self, Mods};
 use crate::utility;
-use crate::db::handler::DBHandler;
+use crate::db::{
+    handler::{DBHandler, DigestEntry, StatusChange},
+    subscription::{Subscription, STATUSES},
+};
 use crate::web::api;
 use api::Api;
 
-use super::api::Beatmap;
-
-// Beatmap構造体からいい感じにEmbed Messageを送る
-pub async fn send_beatmap(ctx: &Context, beatmapset: &Beatmap, channel_id: &ChannelId) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let (color, title_str) = match beatmapset.statu.as_str() {
-        "ranked" => (0x00ff00, "Ranked"),
-        "loved" => (0xff00ff, "Loved"),
-        "qualified" => (0xffff00, "Qualified"),
-        _ => (0xeeeeee, "Graveyard"),
-    };
-
-    let star_str = star_string(&beatmapset.stars, &beatmapset.keys);
-    let url = api::get_url(ctx, &beatmapset).await;
-
-    match channel_id.send_message(&ctx.http, |m| {
-        m.embed(|e| {
-            e.title(&format!("[{}] {} ({})", beatmapset.id, beatmapset.title, title_str))
-                .color(color)
-                .image(&beatmapset.card_url)
-                .url(&url)
-                .field("Artist", &beatmapset.artist, true)
-                .field("Creator", &beatmapset.creator, true)
-                .field("Star ", &star_str, false)
-        });
+use super::api::{Beatmap, Difficulty};
+use super::mode::GameMode;
+use super::revision::{self, DifficultyChange};
+use super::status::{StatusEvent, status_label, projected_rank_time};
+
+// statusの変化(Ranked, Disqualifiedなど)が分かるようにEmbed Messageを送る
+// embedとグラフ(PNG)は1回だけ作り，全てのチャンネルで同じものを使う(EventMessage)
+pub async fn send_status_event(ctx: &Context, embed: &CreateEmbed, graph: Option<&[u8]>, channel_id: &ChannelId) -> Result<Message, Box<dyn Error + Send + Sync>> {
+    let res = channel_id.send_message(&ctx.http, |m| {
+        m.set_embed(embed.clone());
+        if let Some(png) = graph {
+            m.add_file(AttachmentType::Bytes { data: Cow::from(png), filename: GRAPH_FILENAME.to_string() });
+        }
         m
-    }).await {
+    }).await;
+    match res {
+        Ok(m) => Ok(m),
+        Err(e) => Err(Box::new(e)),
+    }
+}
+
+// 難易度の追加・削除，星の変更を知らせる
+pub async fn send_beatmap_update(ctx: &Context, beatmapset: &Beatmap, changes: &[DifficultyChange], channel_id: &ChannelId) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let embed = beatmap_update_embed(ctx, beatmapset, changes).await;
+    match channel_id.send_message(&ctx.http, |m| m.set_embed(embed)).await {
         Ok(_) => Ok(()),
         Err(e) => Err(Box::new(e)),
     }
 }
 
+// 以下はEmbedを作るだけ(チャンネルへの送信とinteractionへの返信の両方で使う)
+pub async fn status_event_embed(ctx: &Context, beatmapset: &Beatmap, event: &StatusEvent) -> CreateEmbed {
+    let (color, title_str) = event.label(&beatmapset.status);
+    let description = event.description(&beatmapset.status);
+
+    let star_str = star_string(&beatmapset.difficulties);
+    let url = api::get_url(ctx, beatmapset).await;
+
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} ({})", beatmapset.id, beatmapset.title, title_str))
+        .color(color)
+        .image(&beatmapset.card_url)
+        .url(&url)
+        .field("Artist", &beatmapset.artist, true)
+        .field("Creator", &beatmapset.creator, true)
+        .field("Star ", &star_str, false);
+    if let Some(ss) = ss_pp_field(beatmapset, &ss_pp(ctx, beatmapset).await) {
+        e.field("SS pp", ss, false);
+    }
+    if let Some(d) = &description {
+        e.description(d);
+    }
+    if let (true, Some(t)) = (beatmapset.status == "qualified", beatmapset.ranked_date) {
+        let p = projected_rank_time(t);
+        e.field("Projected rank", format!("<t:{}:f> (<t:{}:R>)", p, p), false);
+    }
+    e
+}
+
+// ranking予定時刻が近いqualified譜面
+pub async fn ranking_soon_embed(ctx: &Context, beatmapset: &Beatmap, projected: i64) -> CreateEmbed {
+    let url = api::get_url(ctx, beatmapset).await;
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} (Ranking soon)", beatmapset.id, beatmapset.title))
+        .color(0xffa500)
+        .thumbnail(&beatmapset.card_url)
+        .url(&url)
+        .description(format!("Projected to be ranked <t:{}:R> (<t:{}:f>)", projected, projected))
+        .field("Artist", &beatmapset.artist, true)
+        .field("Creator", &beatmapset.creator, true)
+        .field("Star ", star_string(&beatmapset.difficulties), false);
+    e
+}
 
-// 複数件のBeatmapset情報を送りたい場合
-pub async fn simple_beatmap_send(ctx: &Context, beatmapsets: &Vec<Beatmap>, channel_id: &ChannelId) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let status = beatmapsets[0].statu.as_str();
-    let (color, title_str) = match status {
-        "ranked" => (0x00ff00, "Ranked"),
-        "loved" => (0xff00ff, "Loved"),
-        "qualified" => (0xffff00, "Qualified"),
-        _ => (0xeeeeee, "Graveyard"),
-    };
+pub async fn beatmap_update_embed(ctx: &Context, beatmapset: &Beatmap, changes: &[DifficultyChange]) -> CreateEmbed {
+    let url = api::get_url(ctx, beatmapset).await;
+    let lines = changes.iter().map(|c| c.line()).collect::<Vec<String>>().join("\n");
+
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} (Updated)", beatmapset.id, beatmapset.title))
+        .color(0x00bfff)
+        .thumbnail(&beatmapset.card_url)
+        .url(&url)
+        .description(format!("```diff\n{}\n```", lines))
+        .field("Artist", &beatmapset.artist, true)
+        .field("Creator", &beatmapset.creator, true);
+    e
+}
 
-    let mut msg = String::new();
-    for beatmapset in beatmapsets {
-        let star_str = simple_starstr(&beatmapset.stars, &beatmapset.keys);
-        let url = api::get_url(ctx, &beatmapset).await;
-        msg.push_str(&format!("[({}) {}]({}) {}\n", beatmapset.id, beatmapset.title, url, star_str));
+// SHOW_PPが有効で分析済みなら，難易度のid => SSのpp(NM)
+// ppはダウンロード後の分析(analyze_patterns)で計算してDBに入れたものを使う
+async fn ss_pp(ctx: &Context, beatmapset: &Beatmap) -> HashMap<i64, f64> {
+    match Config::shared(ctx).await {
+        Ok(c) if c.show_pp => {},
+        _ => return HashMap::new(),
+    }
+    let db = DBHandler::new(ctx).await;
+    match map_patterns(&db, beatmapset.id).await {
+        Some(p) => p.into_values().filter_map(|p| p.ss_pp.map(|pp| (p.beatmap_id, pp))).collect(),
+        None => HashMap::new(),
     }
+}
 
-    match channel_id.send_message(&ctx.http, |m| {
-        m.embed(|e| {
-            e.title(&format!("{} Beatmapsets ({})", title_str, beatmapsets.len()))
-                .color(color)
-                .description(&msg)
-        });
-        m
-    }).await {
-        Ok(_) => {},
-        Err(e) => return Err(Box::new(e)),
-    };
+// "SS pp"のfield(1行1難易度，fieldの上限を超える分は省略する)
+fn ss_pp_field(beatmapset: &Beatmap, ss: &HashMap<i64, f64>) -> Option<String> {
+    if ss.is_empty() {
+        return None;
+    }
+    let lines = sorted_difficulties(beatmapset).iter()
+        .filter_map(|d| ss.get(&d.id).map(|pp| format!("[{}] {}: {:.0}pp", d.label(), d.version, pp)))
+        .collect::<Vec<String>>();
+    Some(utility::join_lines(&lines, usize::MAX, utility::EMBED_FIELD_LIMIT))
+}
 
-    Ok(())
+// embedに添付するグラフのファイル名
+pub const GRAPH_FILENAME: &str = "graph.png";
+
+// SHOW_GRAPHが有効でダウンロード済みなら，最も星の高い難易度(maniaがあればmaniaの中で)とそのNPSのグラフ(PNG)
+async fn event_graph(ctx: &Context, beatmapset: &Beatmap) -> Option<(Difficulty, Vec<u8>)> {
+    match Config::shared(ctx).await {
+        Ok(c) if c.show_graph => {},
+        _ => return None,
+    }
+    let d = beatmapset.difficulties.iter()
+        .max_by(|a, b| {
+            (a.mode == GameMode::Mania).cmp(&(b.mode == GameMode::Mania))
+                .then(a.difficulty_rating.partial_cmp(&b.difficulty_rating).unwrap_or(std::cmp::Ordering::Equal))
+        })?;
+    let db = DBHandler::new(ctx).await;
+    match graph::load(&db, d).await {
+        Ok(Some((_, png))) => Some((d.clone(), png)),
+        Ok(None) => None,
+        Err(e) => {
+            warn!("Failed to render graph of {}: {}", d.id, e);
+            None
+        }
+    }
 }
 
+// mapset_infoの難易度表の最大の長さ(descriptionの上限4096に余裕を持たせる)
+const MAX_TABLE_LEN: usize = 4000;
+
+// /mapset_infoの詳細表示
+// 全難易度の表と，selected番目(sorted_difficultiesの順)の難易度の詳細
+pub async fn mapset_info_embed(ctx: &Context, beatmapset: &Beatmap, selected: usize) -> CreateEmbed {
+    let (color, title_str) = status_label(&beatmapset.status);
+    let url = api::get_url(ctx, beatmapset).await;
+    let user_url = api::get_user_url(ctx, beatmapset).await;
+    let difficulties = sorted_difficulties(beatmapset);
+
+    let mut e = CreateEmbed::default();
+    e.title(format!("[{}] {} ({})", beatmapset.id, beatmapset.title, title_str))
+        .color(color)
+        .thumbnail(&beatmapset.card_url)
+        .url(&url)
+        .description(difficulty_table(&difficulties))
+        .field("Artist", &beatmapset.artist, true)
+        .field("Creator", format!("[{}]({})", beatmapset.creator, user_url), true)
+        .field("Preview", if beatmapset.mp3_url.is_empty() { "-".to_string() } else { format!("[mp3]({})", beatmapset.mp3_url) }, true);
+
+    if let Some(d) = difficulties.get(selected) {
+        e.field("Difficulty", format!("[[{}] {}]({}#{}/{})", d.label(), d.version, url, d.mode, d.id), false)
+            .field("Star", format!("{:.2}", d.difficulty_rating), true)
+            .field("OD", d.od.to_string(), true)
+            .field("HP", d.hp.to_string(), true);
+        if !d.mode.has_keys() {
+            e.field("CS", d.cs.to_string(), true)
+                .field("AR", d.ar.to_string(), true);
+        }
+        if let Some(pp) = ss_pp(ctx, beatmapset).await.get(&d.id) {
+            e.field("SS pp", format!("{:.0}", pp), true);
+        }
+        let db = DBHandler::new(ctx).await;
+        if let Some(p) = map_patterns(&db, beatmapset.id).await.and_then(|mut p| p.remove(&d.id)) {
+            e.field("Pattern", p.summary(), false);
+        }
+        e.field("Length", format_length(d.total_length), true)
+            .field("BPM", d.bpm.to_string(), true);
+        // maniaではcircles = notes, sliders = LN
+        let (notes, lns) = if d.mode.has_keys() { ("Notes", "LNs") } else { ("Circles", "Sliders") };
+        e.field(notes, d.count_notes.to_string(), true)
+            .field(lns, d.count_lns.to_string(), true);
+    }
+    e
+}
 
-// starから色付き文字列を返す
-// キー数ごとに難易度を表示
-pub fn star_string(star: &str, keys: &str) -> String {
-    let star = star_to_vec(&star);
-    let keys = keys_to_vec(&keys);
+// 表示する難易度の順(mode，キー数，starの順)
+pub fn sorted_difficulties(beatmapset: &Beatmap) -> Vec<&Difficulty> {
+    let mut difficulties = beatmapset.difficulties.iter().collect::<Vec<&Difficulty>>();
+    difficulties.sort_by(|a, b| {
+        (a.mode, a.keys).cmp(&(b.mode, b.keys))
+            .then(a.difficulty_rating.partial_cmp(&b.difficulty_rating).unwrap_or(std::cmp::Ordering::Equal))
+    });
+    difficulties
+}
 
-    let mut key: HashMap<&String, Vec<f32>> = HashMap::new();
-    for (k, s) in keys.iter().zip(star.iter()) {
-        key.entry(k).or_insert(Vec::new()).push(*s);
+// 全難易度を1行ずつ("Insane  4k 4.52 8.0 8.0 2:31 180 1234 321")
+// descriptionの上限を超える分は省略する
+fn difficulty_table(difficulties: &[&Difficulty]) -> String {
+    let mut table = String::from("```\n");
+    table.push_str(&format!("{:<16} {:>6} {:>5} {:>4} {:>4} {:>5} {:>4} {:>5} {:>5}\n", "Name", "Keys", "SR", "OD", "HP", "Len", "BPM", "Notes", "LNs"));
+    for (i, d) in difficulties.iter().enumerate() {
+        let name = if d.version.chars().count() > 16 {
+            format!("{}~", d.version.chars().take(15).collect::<String>())
+        } else {
+            d.version.clone()
+        };
+        // mania以外はキー数の代わりにmode
+        let keys = d.keys.map(|k| format!("{}k", k)).unwrap_or_else(|| d.mode.as_str().to_string());
+        let line = format!("{:<16} {:>6} {:>5.2} {:>4.1} {:>4.1} {:>5} {:>4.0} {:>5} {:>5}\n",
+            name, keys, d.difficulty_rating, d.od, d.hp, format_length(d.total_length), d.bpm, d.count_notes, d.count_lns);
+        if table.len() + line.len() > MAX_TABLE_LEN {
+            table.push_str(&format!("...and {} more\n", difficulties.len() - i));
+            break;
+        }
+        table.push_str(&line);
     }
+    table.push_str("```");
+    table
+}
+
+// 秒 => "m:ss"
+pub fn format_length(secs: i64) -> String {
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+// 1譜面1行("[(id) title](url) 4k: 3.1 ~ 4.5")
+pub async fn simple_beatmap_lines(ctx: &Context, beatmapsets: &[Beatmap]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for beatmapset in beatmapsets {
+        let star_str = simple_starstr(&beatmapset.difficulties);
+        let url = api::get_url(ctx, beatmapset).await;
+        lines.push(format!("[({}) {}]({}) {}", beatmapset.id, beatmapset.title, url, star_str));
+    }
+    lines
+}
+
+// digestのページ(見出しとキー数ごとに，DIGEST_PAGE_SIZE件ずつ)
+pub async fn digest_embeds(ctx: &Context, groups: &[(String, Vec<Beatmap>)]) -> Vec<CreateEmbed> {
+    let mut embeds = Vec::new();
+    for (title, maps) in groups {
+        let (color, _) = match maps.first() {
+            Some(b) => status_label(&b.status),
+            None => status_label(""),
+        };
+        let pages = maps.chunks(DIGEST_PAGE_SIZE).collect::<Vec<&[Beatmap]>>();
+        for (i, page) in pages.iter().enumerate() {
+            let mut e = CreateEmbed::default();
+            e.title(format!("Digest: {} ({}) [{}/{}]", title, maps.len(), i + 1, pages.len()))
+                .color(color)
+                .description(simple_beatmap_lines(ctx, page).await.join("\n"));
+            embeds.push(e);
+        }
+    }
+    embeds
+}
+
+
+// starから色付き文字列を返す
+// キー数(mania以外はmode)ごとに難易度を表示
+pub fn star_string(difficulties: &[Difficulty]) -> String {
+    let key = stars_by_keys(difficulties);
 
     let mut star_str = String::new();
     star_str.push_str("```ansi\n");
 
-    for k in key.keys().sorted() {
-        let stars = key.get(k).unwrap();
+    for (k, stars) in key.iter() {
         let max_star = stars.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
         let min_star = stars.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
         let mxsc = if *max_star >= 0.0 && *max_star <= 1.75 {
@@ -125,7 +320,7 @@ pub fn star_string(star: &str, keys: &str) -> String {
             String::from("[0;30;45m")
         };
 
-        star_str.push_str(&format!("{}k: ", k));
+        star_str.push_str(&format!("{}: ", k));
 
         if max_star == min_star {
             star_str.push_str(&format!("{}{}[0m\n", mxsc, max_star));
@@ -135,142 +330,635 @@ pub fn star_string(star: &str, keys: &str) -> String {
     }
 
 
-    star_str.push_str(&format!("```"));
+    star_str.push_str("```");
     star_str
 }
 
 
 // gen string like "(key): (star) ~ (star)"
-pub fn simple_starstr(star: &str, keys: &str) -> String {
-    let star = star_to_vec(&star);
-    let keys = keys_to_vec(&keys);
-
-    let mut key: HashMap<&String, Vec<f32>> = HashMap::new();
-    for (k, s) in keys.iter().zip(star.iter()) {
-        key.entry(k).or_insert(Vec::new()).push(*s);
-    }
+pub fn simple_starstr(difficulties: &[Difficulty]) -> String {
+    let key = stars_by_keys(difficulties);
 
     let mut star_str = String::new();
-    for k in key.keys().sorted() {
-        let stars = key.get(k).unwrap();
+    for (k, stars) in key.iter() {
         let max_star = stars.iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
         let min_star = stars.iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap();
 
         if max_star == min_star {
-            star_str.push_str(&format!("{}k: {} ", k, max_star));
+            star_str.push_str(&format!("{}: {} ", k, max_star));
         } else {
-            star_str.push_str(&format!("{}k: {} ~ {} ", k, min_star, max_star));
+            star_str.push_str(&format!("{}: {} ~ {} ", k, min_star, max_star));
         }
     }
 
     star_str
 }
 
-pub fn keys_to_vec(keys: &str) -> Vec<String> {
-    let mut key_vec = Vec::new();
-    for k in keys.split(',') {
-        key_vec.push(k.to_string());
+// キー数(mania以外はmode)ごとに星をまとめる(mode順，キー数の昇順)
+fn stars_by_keys(difficulties: &[Difficulty]) -> Vec<(String, Vec<f64>)> {
+    let mut key: BTreeMap<(GameMode, Option<i64>), (String, Vec<f64>)> = BTreeMap::new();
+    for d in difficulties {
+        key.entry((d.mode, d.keys))
+            .or_insert_with(|| (d.label(), Vec::new()))
+            .1.push(d.difficulty_rating);
+    }
+    key.into_values().collect()
+}
+
+// 変更前がqualifiedならqualifiedになった時刻を調べる
+async fn status_event(db: &DBHandler, map: &Beatmap, old: Option<&str>) -> StatusEvent {
+    let qualified_since = if old == Some("qualified") {
+        db.status_since(map.id, "qualified").await.unwrap_or_else(|e| {
+            error!("Failed to get qualified date: {}", e);
+            None
+        })
+    } else {
+        None
+    };
+    StatusEvent::new(old, &map.status, qualified_since)
+}
+
+// 1つのeventで全てのチャンネルに送るもの
+struct EventMessage {
+    embed: CreateEmbed,
+    graph: Option<Vec<u8>>,
+    // SS ppやグラフがまだ無い(ダウンロードしていない)ので，送った通知を分析の後に編集する
+    edit_later: bool,
+}
+
+impl EventMessage {
+    async fn new(ctx: &Context, map: &Beatmap, event: &StatusEvent, analyzed: bool) -> Self {
+        let (show_pp, show_graph) = match Config::shared(ctx).await {
+            Ok(c) => (c.show_pp, c.show_graph),
+            Err(_) => (false, false),
+        };
+        let mut embed = status_event_embed(ctx, map, event).await;
+        let graph = match event_graph(ctx, map).await {
+            Some((d, png)) => {
+                attach_graph(&mut embed, map, &d);
+                Some(png)
+            },
+            None => None,
+        };
+        let edit_later = !analyzed && (show_pp || (show_graph && graph.is_none()));
+        EventMessage { embed, graph, edit_later }
+    }
+}
+
+// SHOW_GRAPHのグラフを画像にする(card_urlはthumbnailにする)
+fn attach_graph(embed: &mut CreateEmbed, beatmapset: &Beatmap, d: &Difficulty) {
+    embed.thumbnail(&beatmapset.card_url)
+        .attachment(GRAPH_FILENAME)
+        .footer(|f| f.text(format!("NPS graph: [{}] {} (blue: notes, orange: LNs / sliders)", d.label(), d.version)));
+}
+
+// statusと条件が合う全てのsubscriptionのチャンネルに送る
+// digestのsubscriptionには溜めておき，後でまとめて送る
+// 配置の条件があり，まだ分析していない譜面は分析が終わってから送る(analyze_patterns)
+async fn fan_out_event(ctx: &Context, db: &DBHandler, subs: &[Subscription], map: &Beatmap, status: &str, old: Option<&str>, event: &StatusEvent) {
+    let targets = subs.iter().filter(|s| s.matches(map, status)).collect::<Vec<&Subscription>>();
+    if targets.is_empty() {
+        return;
+    }
+    let mut patterns = map_patterns(db, map.id).await;
+    let message = EventMessage::new(ctx, map, event, patterns.is_some()).await;
+    for sub in targets {
+        let matched = match sub.pattern_matches(map, patterns.as_ref()) {
+            Some(m) => m,
+            // 分析を待つ(その間に分析が終わっていればその結果で判定する)
+            None => match db.add_pattern_pending(sub.id, map.id, old).await {
+                Ok(None) => continue,
+                Ok(Some(p)) => {
+                    patterns = Some(p.into_iter().map(|p| (p.beatmap_id, p)).collect());
+                    sub.pattern_matches(map, patterns.as_ref()) == Some(true)
+                },
+                Err(e) => {
+                    error!("Failed to queue {} for pattern analysis of {}: {}", map.id, sub.channel_id, e);
+                    continue;
+                }
+            },
+        };
+        if matched {
+            deliver_event(ctx, db, sub, map, event, &message).await;
+        }
+    }
+}
+
+async fn deliver_event(ctx: &Context, db: &DBHandler, sub: &Subscription, map: &Beatmap, event: &StatusEvent, message: &EventMessage) {
+    if sub.digest.is_some() {
+        let (_, kind) = event.label(&map.status);
+        queue_digest(db, sub, map, &kind).await;
+        return;
+    }
+    let channel_id = ChannelId(sub.channel_id as u64);
+    let sent = match send_status_event(ctx, &message.embed, message.graph.as_deref(), &channel_id).await {
+        Ok(m) => m,
+        Err(e) => {
+            error!("Failed to send beatmap to {}: {}", channel_id, e);
+            return;
+        }
+    };
+    if message.edit_later {
+        if let Err(e) = db.add_event_message(map.id, sub.channel_id, sent.id.0 as i64).await {
+            error!("Failed to record message {} of {}: {}", sent.id, map.id, e);
+        }
+    }
+}
+
+// 難易度のid => 配置の分析結果(分析していなければNone)
+async fn map_patterns(db: &DBHandler, id: i64) -> Option<HashMap<i64, Pattern>> {
+    match db.get_patterns(id).await {
+        Ok(p) => p.map(|p| p.into_iter().map(|p| (p.beatmap_id, p)).collect()),
+        Err(e) => {
+            error!("Failed to get patterns of {}: {}", id, e);
+            None
+        }
     }
-    key_vec
 }
 
-pub fn star_to_vec(star: &str) -> Vec<f32> {
-    let mut star_vec = Vec::new();
-    for s in star.split(',') {
-        if s.is_empty() {
+// 難易度の変更は分析済みの譜面だけを配置で絞り込む
+async fn fan_out_update(ctx: &Context, db: &DBHandler, subs: &[Subscription], map: &Beatmap, changes: &[DifficultyChange]) {
+    let patterns = map_patterns(db, map.id).await;
+    for sub in subs.iter().filter(|s| s.matches(map, &map.status) && s.pattern_matches(map, patterns.as_ref()) == Some(true)) {
+        if sub.digest.is_some() {
+            queue_digest(db, sub, map, "Updated").await;
             continue;
         }
-        star_vec.push(s.parse::<f32>().unwrap());
+        let channel_id = ChannelId(sub.channel_id as u64);
+        if let Err(e) = send_beatmap_update(ctx, map, changes, &channel_id).await {
+            error!("Failed to send beatmap update to {}: {}", channel_id, e);
+        }
     }
-    star_vec
 }
 
-// スケジューラから呼び出される関数
-// 各status, 4k, 7kの最新譜面50件を取得し，DBと比較して新規譜面があればDBに追加して特定のチャンネルに通知
+async fn queue_digest(db: &DBHandler, sub: &Subscription, map: &Beatmap, kind: &str) {
+    if let Err(e) = db.add_digest_entry(sub.id, map.id, kind).await {
+        error!("Failed to queue {} for digest of {}: {}", map.id, sub.channel_id, e);
+    }
+}
+
+// 取得するmode(subscriptionが必要とするmode．maniaは常に取得する)
+fn poll_modes(subs: &[Subscription]) -> BTreeSet<GameMode> {
+    let mut modes = BTreeSet::from([GameMode::Mania]);
+    for sub in subs {
+        match sub.mode_list() {
+            m if m.is_empty() => return GameMode::ALL.into_iter().collect(),
+            m => modes.extend(m),
+        }
+    }
+    modes
+}
+
+// DBの難易度と比べた変更点(DBを更新する必要がなければNone)
+// DBに無いmodeの難易度だけが増えたときは通知せずにDBに入れるので空のVecを返す
+async fn difficulty_changes(db: &DBHandler, map: &Beatmap) -> Option<Vec<DifficultyChange>> {
+    let stored = match db.get_difficulties(map.id).await {
+        Ok(d) => d,
+        Err(e) => {
+            error!("Failed to get difficulties: {}", e);
+            return None;
+        }
+    };
+    let changes = revision::diff_difficulties(&stored, &map.difficulties);
+    if changes.is_empty() && !revision::has_new_modes(&stored, &map.difficulties) { None } else { Some(changes) }
+}
+
+// スケジューラから呼び出される関数(poll)
+// 各mode，各statusの最新譜面50件を取得し，DBと比較して新規譜面やstatusが変わった譜面があればDBを更新して条件の合うチャンネル(subscriptions)に通知
 pub async fn check_maps(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
 
     let now = time::SystemTime::now();
-    info!("{}", format!("check_maps started at {:?}", now));
+    info!("check_maps started at {:?}", now);
 
-    let api = match Api::new(ctx).await {
+    let api = match Api::shared(ctx).await {
         Ok(a) => a,
         Err(e) => return Err(e),
     };
 
     let db = DBHandler::new(ctx).await;
+    let subs = match db.all_subscriptions().await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to get subscriptions: {}", e);
+            Vec::new()
+        }
+    };
 
-    let statuses = ["ranked", "loved", "qualified"];
-    let keys = ["4", "7"];
-    let mode = "3"; // mania only
-    let mut download_maps = Vec::new();
-    for status in statuses.iter() {
-        for key in keys.iter() {
-            let maps = match api.get_beatmapsets_with_cursor(mode, status, key, "").await {
+    let modes = poll_modes(&subs);
+    let mut updated_maps: HashMap<i64, Beatmap> = HashMap::new();
+    let mut revisions: HashMap<i64, Vec<DifficultyChange>> = HashMap::new();
+    let mut seen = HashSet::new();
+    for mode in modes.iter() {
+        for status in STATUSES.iter() {
+            // キー数はsubscriptionごとに絞り込むので，ここでは全てのキー数を取得する
+            let maps = match api.get_beatmapsets_with_cursor(*mode, status, "", "").await {
                 Ok(m) => m,
-                Err(_e) => {
-                    warn!("get_beatmapsets_with_cursor [{}] failed", status);
+                Err(e) => {
+                    warn!("get_beatmapsets_with_cursor [{}, {}] failed: {}", mode, status, e);
                     continue;
                 }
             };
 
-            let mut new_maps = Vec::new();
+            let mut events = Vec::new();
+            let mut updates = Vec::new();
             for map in maps.0.iter() {
-                if map.statu != status.to_string() {
+                // 複数のmodeを含む譜面は最初に見つかったmodeで処理する
+                if map.status != *status || !seen.insert(map.id) {
                     continue;
                 }
-                let res = match db.check_existence(&map.id, status).await {
-                    Ok(b) => b,
+                let old = match db.get_status(map.id).await {
+                    Ok(s) => s,
                     Err(e) => {
-                        error!("{}", format!("Failed to check existence: {}", e));
+                        error!("Failed to get status: {}", e);
                         continue;
                     }
                 };
-                if !res {
-                    new_maps.push(map.clone());
-                    download_maps.push(map.clone());
-                }
-            }
-
-            // 送信だけ
-            let env_name = format!("{}k_{}", key, status);
-            let channel_id = match utility::get_env_from_context(ctx, &env_name).await.parse::<ChannelId>() {
-                Ok(c) => c,
-                Err(_e) => {
-                    warn!("get_env_from_context [{}] failed", env_name);
-                    continue;
-                }
-            };
-            if new_maps.len() > 0 {
-                info!("{}", format!("{} new {} maps ({}k)", new_maps.len(), status, key));
-                for map in new_maps {
-                    match send_beatmap(ctx, &map, &channel_id).await {
-                        Ok(_) => {},
-                        Err(e) => {
-                            error!("{}", format!("Failed to send beatmap: {}", e));
-                            continue;
+                if old.as_deref() == Some(*status) {
+                    // statusはそのまま => 難易度の変更を確認
+                    if let Some(changes) = difficulty_changes(&db, map).await {
+                        if !changes.is_empty() {
+                            updates.push((map.clone(), changes.clone()));
+                            revisions.insert(map.id, changes);
                         }
+                        updated_maps.insert(map.id, map.clone());
                     }
+                    continue;
                 }
+                let event = status_event(&db, map, old.as_deref()).await;
+                events.push((map.clone(), old, event));
+                updated_maps.insert(map.id, map.clone());
+            }
+
+            if !events.is_empty() {
+                info!("{} new {} maps ({})", events.len(), status, mode);
             } else {
-                info!("{}", format!("No new {} maps", status));
+                info!("No new {} maps ({})", status, mode);
+            }
+            for (map, old, event) in events {
+                fan_out_event(ctx, &db, &subs, &map, status, old.as_deref(), &event).await;
+            }
+            for (map, changes) in updates {
+                info!("{} was updated ({} changes)", map.id, changes.len());
+                fan_out_update(ctx, &db, &subs, &map, &changes).await;
             }
         }
     }
 
     // DBに追加
+    let download_maps = updated_maps.into_values().collect::<Vec<Beatmap>>();
     for map in download_maps.iter() {
-        match db.insert(map).await {
-            Ok(_) => {},
-            Err(e) => error!("{}", format!("Failed to insert map: {}", e)),
+        db_update(&db, map).await;
+        if let Some(changes) = revisions.get(&map.id) {
+            if let Err(e) = db.insert_revisions(map.id, changes).await {
+                error!("Failed to insert revisions: {}", e);
+            }
+        }
+    }
+
+    // download maps(workerがダウンロードする)
+    // 編集する通知(add_event_message)を記録した後に入れる
+    match downloader::enqueue(ctx, &download_maps).await {
+        Ok(n) => info!("Queued {} maps for download", n),
+        Err(e) => error!("Failed to queue maps for download: {}", e),
+    }
+
+    Ok(())
+}
+
+// スケジューラから呼び出される関数(refresh_qualified)
+// DBでqualifiedの譜面を全て個別に取得し直す
+// 一覧の50件に入らなかった譜面も含めて，statusの変化(Disqualified, Rankedなど)と難易度の変更を通知する
+pub async fn refresh_qualified(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let api = Api::shared(ctx).await?;
+    let db = DBHandler::new(ctx).await;
+    let subs = match db.all_subscriptions().await {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to get subscriptions: {}", e);
+            Vec::new()
+        }
+    };
+
+    let modes = poll_modes(&subs);
+    let ids = db.select("*", "qualified", "").await?
+        .into_iter()
+        .filter(|m| m.difficulties.iter().any(|d| modes.contains(&d.mode)))
+        .map(|m| m.id.to_string())
+        .collect::<Vec<String>>();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    info!("Refreshing {} qualified maps", ids.len());
+
+    for map in api.get_beatmaps_by_ids(ids).await? {
+        if map.status != "qualified" {
+            // qualifiedを購読しているチャンネルに通知する
+            let event = status_event(&db, &map, Some("qualified")).await;
+            info!("{} is no longer qualified ({})", map.id, map.status);
+            fan_out_event(ctx, &db, &subs, &map, "qualified", Some("qualified"), &event).await;
+            db_update(&db, &map).await;
+            continue;
+        }
+        if let Some(changes) = difficulty_changes(&db, &map).await.filter(|c| !c.is_empty()) {
+            info!("{} was updated ({} changes)", map.id, changes.len());
+            fan_out_update(ctx, &db, &subs, &map, &changes).await;
+            if let Err(e) = db.insert_revisions(map.id, &changes).await {
+                error!("Failed to insert revisions: {}", e);
+            }
+        }
+        // ranked_dateも入れ直す
+        db_update(&db, &map).await;
+    }
+    Ok(())
+}
+
+// digestの1ページの譜面数
+const DIGEST_PAGE_SIZE: usize = 10;
+
+// 見出しの順番(それ以外は後ろに名前順)
+const DIGEST_KIND_ORDER: [&str; 4] = ["Ranked", "Qualified", "Loved", "Updated"];
+
+// スケジューラから呼び出される関数(subscription_digest)
+// digestを設定したsubscriptionのうち，送る時刻になったものに溜まっている譜面をまとめて送る
+pub async fn send_digests(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let db = DBHandler::new(ctx).await;
+    let now = utility::unix_now();
+    for sub in db.all_subscriptions().await?.iter().filter(|s| s.digest_due(now)) {
+        let entries = db.digest_entries(sub.id).await?;
+        let last_entry = match entries.last() {
+            Some(e) => e.id,
+            None => {
+                // 何もなければ送らずに次の期間へ
+                db.finish_digest(sub.id, 0, now).await?;
+                continue;
+            }
+        };
+
+        let groups = digest_groups(&db, sub, &entries).await;
+        let channel_id = ChannelId(sub.channel_id as u64);
+        info!("Sending digest to {} ({} entries)", channel_id, entries.len());
+        for embed in digest_embeds(ctx, &groups).await {
+            if let Err(e) = channel_id.send_message(&ctx.http, |m| m.set_embed(embed)).await {
+                error!("Failed to send digest to {}: {}", channel_id, e);
+                break;
+            }
+        }
+        // 送れなかった場合も溜め続けないように消す
+        db.finish_digest(sub.id, last_entry, now).await?;
+    }
+    Ok(())
+}
+
+// 見出し(Ranked, Disqualified...)とキー数(mania以外はmode)ごとにまとめる
+// 1つの譜面が条件に合う複数のキー数に入ることもある
+async fn digest_groups(db: &DBHandler, sub: &Subscription, entries: &[DigestEntry]) -> Vec<(String, Vec<Beatmap>)> {
+    let mut groups: BTreeMap<(usize, String, GameMode, Option<i64>), Vec<Beatmap>> = BTreeMap::new();
+    let mut seen = HashSet::new();
+    for entry in entries {
+        let map = match db.get_beatmapset(entry.beatmapset_id).await {
+            Ok(Some(m)) => m,
+            Ok(None) => {
+                warn!("{} is not found in DB (digest)", entry.beatmapset_id);
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to get {}: {}", entry.beatmapset_id, e);
+                continue;
+            }
+        };
+        let order = DIGEST_KIND_ORDER.iter().position(|k| *k == entry.kind).unwrap_or(DIGEST_KIND_ORDER.len());
+        let mut labels = map.difficulties.iter()
+            .filter(|d| sub.difficulty_matches(d))
+            .map(|d| (d.mode, d.keys))
+            .collect::<BTreeSet<(GameMode, Option<i64>)>>();
+        if labels.is_empty() {
+            labels = map.difficulties.iter().map(|d| (d.mode, d.keys)).collect();
+        }
+        for (mode, keys) in labels {
+            if seen.insert((entry.kind.clone(), mode, keys, map.id)) {
+                groups.entry((order, entry.kind.clone(), mode, keys)).or_default().push(map.clone());
+            }
+        }
+    }
+    groups.into_iter()
+        .map(|((_, kind, mode, keys), maps)| (format!("{} {}", kind, mode.group_label(keys)), maps))
+        .collect()
+}
+
+// 配置の分析を待つ期間(これより古いものは送らずに消す)
+const PATTERN_PENDING_TTL: i64 = 7 * 24 * 60 * 60;
+// 通知を分析の後に編集する期間(これより古いものは編集しない)
+const EVENT_MESSAGE_TTL: i64 = 7 * 24 * 60 * 60;
+
+// ダウンロードした譜面(maniaの難易度)の配置とSSのppを計算してDBに入れ，分析を待っていたsubscriptionに送る
+// ダウンロード前に送った通知にはSS ppを付ける
+// 分析した難易度の数を返す
+pub async fn analyze_patterns(ctx: &Context, id: i64) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let db = DBHandler::new(ctx).await;
+    let maps = match osz::load(&db, id).await? {
+        Some(m) => m,
+        None => return Ok(0),
+    };
+    let mut targets = Vec::new();
+    for (name, map) in maps {
+        if map.keys().is_none() {
+            continue;
+        }
+        let beatmap_id = match map.metadata.beatmap_id {
+            Some(b) => Some(b),
+            None => db.beatmap_id_by_version(id, &map.metadata.version).await?,
+        };
+        match beatmap_id {
+            Some(b) => targets.push((name, b, map)),
+            None => warn!("Skipped {} of {} (unknown beatmap id)", name, id),
+        }
+    }
+    // 譜面が多いと時間がかかるのでasyncの処理を止めないようにする
+    let patterns = tokio::task::spawn_blocking(move || {
+        targets.iter()
+            .filter_map(|(name, b, map)| {
+                let mut p = pattern::analyze(map, id, *b);
+                match &mut p {
+                    Some(p) => p.ss_pp = pp::calculate(map, &Mods::default()).map(|a| a.pp(1.0, &Mods::default())),
+                    None => warn!("Skipped {} of {} (no notes)", name, id),
+                }
+                p
+            })
+            .collect::<Vec<Pattern>>()
+    }).await?;
+    db.replace_patterns(id, &patterns).await?;
+    if let Err(e) = edit_event_messages(ctx, &db, id).await {
+        warn!("Failed to add SS pp to messages of {}: {}", id, e);
+    }
+
+    let (pending, expired) = db.take_pattern_pending(id, utility::unix_now() - PATTERN_PENDING_TTL).await?;
+    if expired > 0 {
+        warn!("Dropped {} notifications that waited for pattern analysis for over {} days", expired, PATTERN_PENDING_TTL / (24 * 60 * 60));
+    }
+    if pending.is_empty() {
+        return Ok(patterns.len());
+    }
+    let map = match db.get_beatmapset(id).await? {
+        Some(m) => m,
+        None => return Ok(patterns.len()),
+    };
+    let by_id = patterns.iter().map(|p| (p.beatmap_id, p.clone())).collect::<HashMap<i64, Pattern>>();
+    let subs = db.all_subscriptions().await?;
+    for p in pending {
+        let sub = match subs.iter().find(|s| s.id == p.subscription_id) {
+            Some(s) => s,
+            None => continue,
+        };
+        if sub.pattern_matches(&map, Some(&by_id)) == Some(true) {
+            let event = status_event(&db, &map, p.old_status.as_deref()).await;
+            let message = EventMessage::new(ctx, &map, &event, true).await;
+            deliver_event(ctx, &db, sub, &map, &event, &message).await;
+        }
+    }
+    Ok(patterns.len())
+}
+
+// ダウンロード前に送った通知(add_event_message)のembedにSS ppとグラフを付ける
+async fn edit_event_messages(ctx: &Context, db: &DBHandler, id: i64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let messages = db.take_event_messages(id, utility::unix_now() - EVENT_MESSAGE_TTL).await?;
+    if messages.is_empty() {
+        return Ok(());
+    }
+    let map = match db.get_beatmapset(id).await? {
+        Some(m) => m,
+        None => return Ok(()),
+    };
+    // maniaの難易度が無ければppも無い
+    let ss = ss_pp_field(&map, &ss_pp(ctx, &map).await);
+    // グラフは1回だけ描く
+    let graph = event_graph(ctx, &map).await;
+    if ss.is_none() && graph.is_none() {
+        return Ok(());
+    }
+    for (channel_id, message_id) in messages {
+        let channel_id = ChannelId(channel_id as u64);
+        let message = match channel_id.message(&ctx.http, MessageId(message_id as u64)).await {
+            Ok(m) => m,
+            // 消されたメッセージなど
+            Err(e) => {
+                warn!("Failed to get message {} in {}: {}", message_id, channel_id, e);
+                continue;
+            }
+        };
+        // 送ったときに既に付いていたものは付けない
+        let has_graph = message.attachments.iter().any(|a| a.filename == GRAPH_FILENAME);
+        let (mut embed, has_ss) = match message.embeds.into_iter().next() {
+            Some(e) => {
+                let has_ss = e.fields.iter().any(|f| f.name == "SS pp");
+                (CreateEmbed::from(e), has_ss)
+            },
+            None => continue,
+        };
+        let ss = ss.as_ref().filter(|_| !has_ss);
+        let graph = graph.as_ref().filter(|_| !has_graph);
+        if ss.is_none() && graph.is_none() {
+            continue;
+        }
+        if let Some(ss) = ss {
+            embed.field("SS pp", ss, false);
+        }
+        if let Some((d, _)) = graph {
+            attach_graph(&mut embed, &map, d);
+        }
+        let res = channel_id.edit_message(&ctx.http, message.id, |m| {
+            m.set_embed(embed);
+            if let Some((_, png)) = graph {
+                m.attachment(AttachmentType::Bytes { data: Cow::from(png.as_slice()), filename: GRAPH_FILENAME.to_string() });
+            }
+            m
+        }).await;
+        if let Err(e) = res {
+            warn!("Failed to edit message {} in {}: {}", message_id, channel_id, e);
         }
     }
+    Ok(())
+}
 
-    // download maps
-    let path = utility::get_env_from_context(ctx, "map_path").await;
-    match api.download_beatmaps(download_maps, &path).await {
-        Ok(_) => info!("{}", format!("Downloaded maps")),
-        Err(e) => error!("{}", format!("Failed to download maps: {}", e)),
+// スケジューラから呼び出される関数(qualified_reminder)
+// ranking予定時刻までQUALIFIED_REMINDER_BEFORE以内になったqualified譜面を，qualifiedを購読しているチャンネルに1回だけ知らせる
+pub async fn remind_qualified(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let before = Config::shared(ctx).await?.qualified_reminder_before.as_secs() as i64;
+    let db = DBHandler::new(ctx).await;
+    let subs = db.all_subscriptions().await?;
+    let now = utility::unix_now();
+
+    for map in db.qualified_queue(None, None).await? {
+        let qualified_at = match map.ranked_date {
+            Some(t) => t,
+            None => continue,
+        };
+        let projected = projected_rank_time(qualified_at);
+        // 予定時刻を過ぎたもの(停止中に過ぎたものも)は送らない
+        if projected <= now || projected - now > before {
+            continue;
+        }
+        if db.reminder_sent(map.id, qualified_at).await? {
+            continue;
+        }
+        info!("{} will be ranked soon ({})", map.id, projected);
+        for sub in subs.iter().filter(|s| s.matches(&map, "qualified")) {
+            let channel_id = ChannelId(sub.channel_id as u64);
+            let embed = ranking_soon_embed(ctx, &map, projected).await;
+            if let Err(e) = channel_id.send_message(&ctx.http, |m| m.set_embed(embed)).await {
+                error!("Failed to send ranking reminder to {}: {}", channel_id, e);
+            }
+        }
+        db.mark_reminder_sent(map.id, qualified_at).await?;
     }
+    Ok(())
+}
 
+// スケジューラから呼び出される関数(daily_digest)
+// 前回の実行(scheduler_jobsのlast_run)からのstatusの変化をまとめてlog channelに送る
+// (停止していた間の分も含める)
+pub async fn daily_digest(ctx: &Context) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Config::shared(ctx).await?;
+    let db = DBHandler::new(ctx).await;
+    // last_runは実行が終わってから更新される．無ければ過去24時間
+    let since = match db.job_last_run(Job::DailyDigest.name()).await? {
+        Some(t) => t,
+        None => utility::unix_now() - 24 * 60 * 60,
+    };
+    let changes = db.status_changes_since(since).await?;
+    let embed = digest_embed("Daily digest", &changes);
+    config.log_channel.send_message(&ctx.http, |m| m.set_embed(embed)).await?;
+    info!("Sent daily digest ({} changes)", changes.len());
     Ok(())
 }
+
+// statusごとにまとめたEmbed(statusごとに最大10件)
+pub fn digest_embed(title: &str, changes: &[StatusChange]) -> CreateEmbed {
+    const MAX_LINES: usize = 10;
+    let mut by_status: BTreeMap<&str, Vec<&StatusChange>> = BTreeMap::new();
+    for c in changes {
+        by_status.entry(c.new_status.as_str()).or_default().push(c);
+    }
+
+    let mut e = CreateEmbed::default();
+    e.title(format!("{} ({} changes)", title, changes.len()))
+        .color(0x00ffff);
+    if changes.is_empty() {
+        e.description("No status changes");
+        return e;
+    }
+    for (status, list) in by_status {
+        let lines = list.iter()
+            .map(|c| format!("[{}] {} - {}", c.beatmapset_id, c.artist, c.title))
+            .collect::<Vec<String>>();
+        e.field(format!("{} ({})", status, list.len()), utility::join_lines(&lines, MAX_LINES, utility::EMBED_FIELD_LIMIT), false);
+    }
+    e
+}
+
+async fn db_update(db: &DBHandler, map: &Beatmap) {
+    match db.insert(map).await {
+        Ok(Some(old)) if old != map.status => info!("{}: {} -> {}", map.id, old, map.status),
+        Ok(_) => {},
+        Err(e) => error!("Failed to insert map: {}", e),
+    }
+}
@@ -1,2 +1,9 @@
 pub mod api;
+pub mod download;
+pub mod error;
+pub mod models;
+pub mod ratelimit;
+pub mod revision;
+pub mod mode;
+pub mod status;
 pub mod handler;
\ No newline at end of file
@@ -0,0 +1,76 @@
+use std::fmt;
+
+// ゲームモード
+// DBにはAPIと同じ名前(osu, taiko, fruits, mania)で入れる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, sqlx::Type)]
+#[sqlx(rename_all = "lowercase")]
+pub enum GameMode {
+    Osu,
+    Taiko,
+    #[sqlx(rename = "fruits")]
+    Catch,
+    Mania,
+}
+
+impl GameMode {
+    pub const ALL: [GameMode; 4] = [GameMode::Osu, GameMode::Taiko, GameMode::Catch, GameMode::Mania];
+
+    // APIのm=に渡す値
+    pub fn id(self) -> u8 {
+        match self {
+            GameMode::Osu => 0,
+            GameMode::Taiko => 1,
+            GameMode::Catch => 2,
+            GameMode::Mania => 3,
+        }
+    }
+
+    // APIのmodeの値
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GameMode::Osu => "osu",
+            GameMode::Taiko => "taiko",
+            GameMode::Catch => "fruits",
+            GameMode::Mania => "mania",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            GameMode::Osu => "osu!",
+            GameMode::Taiko => "osu!taiko",
+            GameMode::Catch => "osu!catch",
+            GameMode::Mania => "osu!mania",
+        }
+    }
+
+    // APIの値やコマンドの入力から(std, ctbなどの略称も可)
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "osu" | "std" | "standard" | "0" => Some(GameMode::Osu),
+            "taiko" | "1" => Some(GameMode::Taiko),
+            "fruits" | "catch" | "ctb" | "2" => Some(GameMode::Catch),
+            "mania" | "3" => Some(GameMode::Mania),
+            _ => None,
+        }
+    }
+
+    // キー数(cs)を持つのはmaniaだけ
+    pub fn has_keys(self) -> bool {
+        self == GameMode::Mania
+    }
+
+    // 難易度をまとめて表示するときの見出し("4k"，mania以外は"osu!taiko"など)
+    pub fn group_label(self, keys: Option<i64>) -> String {
+        match keys {
+            Some(k) => format!("{}k", k),
+            None => self.label().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for GameMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
@@ -0,0 +1,79 @@
+// osu! API v2 のレスポンスをそのまま受け取るための型
+// 欠けていることがあるフィールドは Option にしておき，Beatmap への変換時に埋める
+use serde::Deserialize;
+
+// POST /oauth/token
+#[derive(Debug, Clone, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub expires_in: u64, // 秒
+}
+
+// GET /api/v2/beatmapsets/search
+#[derive(Debug, Clone, Deserialize)]
+pub struct BeatmapsetSearch {
+    #[serde(default)]
+    pub beatmapsets: Vec<ApiBeatmapset>,
+    pub cursor_string: Option<String>, // 最終ページではnull
+}
+
+// GET /api/v2/beatmapsets/{id} (searchの各要素も同じ形)
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiBeatmapset {
+    pub id: i64,
+    pub title: String,
+    pub artist: String,
+    pub creator: String,
+    pub user_id: Option<i64>,
+    pub status: String, // ranked, loved, qualified, pending, wip, graveyard
+    pub preview_url: Option<String>, // "//b.ppy.sh/preview/xxx.mp3" のようにschemeなし
+    #[serde(default)]
+    pub covers: Option<Covers>,
+    #[serde(default)]
+    pub tags: Option<String>,
+    pub ranked_date: Option<String>,
+    #[serde(default)]
+    pub beatmaps: Vec<ApiBeatmap>,
+}
+
+// 各難易度
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiBeatmap {
+    pub id: i64,
+    pub mode: String, // osu, taiko, fruits, mania
+    pub version: String,
+    pub difficulty_rating: f64,
+    pub cs: Option<f64>, // maniaではキー数
+    pub ar: Option<f64>,
+    pub accuracy: Option<f64>, // OD
+    pub drain: Option<f64>, // HP
+    pub bpm: Option<f64>,
+    pub total_length: Option<i64>,
+    pub count_circles: Option<i64>,
+    pub count_sliders: Option<i64>,
+}
+
+// カードの画像だけ使う
+#[derive(Debug, Clone, Deserialize)]
+pub struct Covers {
+    pub card: Option<String>,
+    #[serde(rename = "card@2x")]
+    pub card_2x: Option<String>,
+}
+
+// 4xx/5xx のときに返ってくるbody
+// {"error": "..."} や {"authentication": "basic"} の形がある
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiErrorBody {
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+    pub authentication: Option<String>,
+}
+
+impl ApiErrorBody {
+    pub fn message(&self) -> Option<String> {
+        self.error_description.clone()
+            .or_else(|| self.error.clone())
+            .or_else(|| self.authentication.as_ref().map(|a| format!("authentication: {}", a)))
+    }
+}
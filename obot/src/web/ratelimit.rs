@@ -0,0 +1,106 @@
+use std::time::{Duration, Instant};
+
+use tokio::sync::Mutex;
+
+use super::error::ApiError;
+
+// 一度に連続で投げてよいリクエスト数
+const BURST: f64 = 5.0;
+// 429/5xxのretry回数と待ち時間
+const MAX_RETRIES: u32 = 4;
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+// token bucket
+// 1分あたりrequests_per_minute個のtokenが補充され，1リクエストごとに1つ消費する
+#[derive(Debug)]
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+    capacity: f64,
+    refill_per_sec: f64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_minute: u32) -> Self {
+        let rpm = requests_per_minute.max(1) as f64;
+        let capacity = BURST.min(rpm);
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+            capacity,
+            refill_per_sec: rpm / 60.0,
+        }
+    }
+
+    // tokenが1つ取れるまで待つ
+    pub async fn acquire(&self) {
+        loop {
+            let wait = {
+                let mut bucket = self.bucket.lock().await;
+                let now = Instant::now();
+                let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+                bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+                bucket.last_refill = now;
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    return;
+                }
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.refill_per_sec)
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    // limiterを通してリクエストを送る
+    // 429と5xx(と通信エラー)は指数バックオフでretryし，Retry-Afterがあればそれに従う
+    // buildはretryのたびに呼ばれる
+    pub async fn send<F>(&self, build: F) -> Result<reqwest::Response, ApiError>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut attempt = 0;
+        loop {
+            self.acquire().await;
+            let res = build().send().await;
+            let delay = match &res {
+                Ok(r) if is_retryable(r.status()) => retry_after(r).unwrap_or_else(|| backoff(attempt)),
+                Err(e) if e.is_timeout() || e.is_connect() => backoff(attempt),
+                _ => return Ok(res?),
+            };
+            if attempt >= MAX_RETRIES {
+                return Ok(res?);
+            }
+            match &res {
+                Ok(r) => warn!("{} returned {}, retrying in {:?}", r.url(), r.status(), delay),
+                Err(e) => warn!("request failed ({}), retrying in {:?}", e, delay),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+fn backoff(attempt: u32) -> Duration {
+    (BASE_BACKOFF * 2u32.pow(attempt)).min(MAX_BACKOFF)
+}
+
+// Retry-After: <秒数> のみ対応(HTTP-dateの場合はバックオフに任せる)
+fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+    res.headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(|s| Duration::from_secs(s).min(MAX_BACKOFF))
+}
@@ -0,0 +1,159 @@
+// DBに入っている難易度とAPIから取得した難易度を比べて変更点を出す
+use super::api::Difficulty;
+
+// これ未満の星の差は無視する(表示は小数2桁なので)
+const STAR_EPSILON: f64 = 0.005;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DifficultyChange {
+    // labelは"4k"や"osu!taiko"(Difficulty::label)
+    Added { beatmap_id: i64, version: String, label: String, rating: f64 },
+    Removed { beatmap_id: i64, version: String, label: String, rating: f64 },
+    StarChanged { beatmap_id: i64, version: String, label: String, old: f64, new: f64 },
+}
+
+impl DifficultyChange {
+    // DBのchange列の値
+    pub fn kind(&self) -> &'static str {
+        match self {
+            DifficultyChange::Added { .. } => "added",
+            DifficultyChange::Removed { .. } => "removed",
+            DifficultyChange::StarChanged { .. } => "star_changed",
+        }
+    }
+
+    pub fn beatmap_id(&self) -> i64 {
+        match self {
+            DifficultyChange::Added { beatmap_id, .. }
+            | DifficultyChange::Removed { beatmap_id, .. }
+            | DifficultyChange::StarChanged { beatmap_id, .. } => *beatmap_id,
+        }
+    }
+
+    pub fn version(&self) -> &str {
+        match self {
+            DifficultyChange::Added { version, .. }
+            | DifficultyChange::Removed { version, .. }
+            | DifficultyChange::StarChanged { version, .. } => version,
+        }
+    }
+
+    // (変更前, 変更後)の星
+    pub fn ratings(&self) -> (Option<f64>, Option<f64>) {
+        match self {
+            DifficultyChange::Added { rating, .. } => (None, Some(*rating)),
+            DifficultyChange::Removed { rating, .. } => (Some(*rating), None),
+            DifficultyChange::StarChanged { old, new, .. } => (Some(*old), Some(*new)),
+        }
+    }
+
+    // embedに表示する1行
+    pub fn line(&self) -> String {
+        match self {
+            DifficultyChange::Added { version, label, rating, .. } => {
+                format!("+ [{}] {} ({:.2}★)", label, version, rating)
+            },
+            DifficultyChange::Removed { version, label, rating, .. } => {
+                format!("- [{}] {} ({:.2}★)", label, version, rating)
+            },
+            DifficultyChange::StarChanged { version, label, old, new, .. } => {
+                format!("~ [{}] {}: {:.2}★ → {:.2}★", label, version, old, new)
+            },
+        }
+    }
+}
+
+// storedにmigrationで仮に入れた難易度(負のid)が含まれている場合は比較できないので空を返す
+// storedに1つも無いmodeの難易度は比べない(mania以外に対応する前に保存したsetでは全て"Added"になってしまうので)
+pub fn diff_difficulties(stored: &[Difficulty], fresh: &[Difficulty]) -> Vec<DifficultyChange> {
+    if stored.iter().any(|d| d.id < 0) {
+        return Vec::new();
+    }
+
+    let mut changes = Vec::new();
+    for f in fresh.iter().filter(|f| stored.iter().any(|s| s.mode == f.mode)) {
+        match stored.iter().find(|s| s.id == f.id) {
+            None => changes.push(DifficultyChange::Added {
+                beatmap_id: f.id,
+                version: f.version.clone(),
+                label: f.label(),
+                rating: f.difficulty_rating,
+            }),
+            Some(s) if (s.difficulty_rating - f.difficulty_rating).abs() >= STAR_EPSILON => {
+                changes.push(DifficultyChange::StarChanged {
+                    beatmap_id: f.id,
+                    version: f.version.clone(),
+                    label: f.label(),
+                    old: s.difficulty_rating,
+                    new: f.difficulty_rating,
+                })
+            },
+            Some(_) => {},
+        }
+    }
+    for s in stored {
+        if !fresh.iter().any(|f| f.id == s.id) {
+            changes.push(DifficultyChange::Removed {
+                beatmap_id: s.id,
+                version: s.version.clone(),
+                label: s.label(),
+                rating: s.difficulty_rating,
+            });
+        }
+    }
+    changes
+}
+
+// storedに無いmodeの難易度があるか(DBに入れ直す必要がある)
+pub fn has_new_modes(stored: &[Difficulty], fresh: &[Difficulty]) -> bool {
+    fresh.iter().any(|f| !stored.iter().any(|s| s.mode == f.mode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::mode::GameMode;
+
+    fn difficulty(id: i64, mode: GameMode, rating: f64) -> Difficulty {
+        Difficulty {
+            id,
+            beatmapset_id: 1,
+            mode,
+            version: format!("diff {}", id),
+            difficulty_rating: rating,
+            keys: if mode == GameMode::Mania { Some(4) } else { None },
+            cs: 4.0,
+            ar: 0.0,
+            od: 8.0,
+            hp: 8.0,
+            bpm: 180.0,
+            total_length: 120,
+            count_notes: 1000,
+            count_lns: 0,
+        }
+    }
+
+    #[test]
+    fn ignores_modes_not_stored_yet() {
+        let stored = [difficulty(1, GameMode::Mania, 3.0)];
+        let fresh = [difficulty(1, GameMode::Mania, 3.0), difficulty(2, GameMode::Osu, 4.0)];
+        assert!(diff_difficulties(&stored, &fresh).is_empty());
+        assert!(has_new_modes(&stored, &fresh));
+    }
+
+    #[test]
+    fn diffs_stored_modes() {
+        let stored = [difficulty(1, GameMode::Mania, 3.0), difficulty(2, GameMode::Mania, 4.0), difficulty(3, GameMode::Osu, 5.0)];
+        let fresh = [difficulty(1, GameMode::Mania, 3.5), difficulty(3, GameMode::Osu, 5.0), difficulty(4, GameMode::Osu, 6.0)];
+        let changes = diff_difficulties(&stored, &fresh);
+        assert_eq!(changes.iter().map(|c| (c.kind(), c.beatmap_id())).collect::<Vec<_>>(),
+            vec![("star_changed", 1), ("added", 4), ("removed", 2)]);
+        assert!(!has_new_modes(&stored, &fresh));
+    }
+
+    #[test]
+    fn skips_placeholder_difficulties() {
+        let stored = [difficulty(-1, GameMode::Mania, 3.0)];
+        assert!(diff_difficulties(&stored, &[difficulty(1, GameMode::Mania, 3.0)]).is_empty());
+    }
+}
@@ -0,0 +1,104 @@
+// beatmapsetのstatusの変化(qualified -> ranked など)を通知用に分類する
+use std::borrow::Cow;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatusEvent {
+    // 初めて見つけた譜面
+    New,
+    // qualified -> ranked (qualifiedになった時刻が分かればその時刻)
+    Ranked { qualified_since: Option<i64> },
+    // qualified -> pending, wip, graveyard
+    Disqualified,
+    // ranked, loved など -> pending, wip
+    BackToPending { from: String },
+    // それ以外(graveyard -> loved など)
+    Changed { from: String },
+}
+
+impl StatusEvent {
+    pub fn new(old: Option<&str>, new: &str, qualified_since: Option<i64>) -> Self {
+        let old = match old {
+            Some(o) => o,
+            None => return StatusEvent::New,
+        };
+        match (old, new) {
+            ("qualified", "ranked" | "approved") => StatusEvent::Ranked { qualified_since },
+            ("qualified", "pending" | "wip" | "graveyard") => StatusEvent::Disqualified,
+            (_, "pending" | "wip") => StatusEvent::BackToPending { from: old.to_string() },
+            _ => StatusEvent::Changed { from: old.to_string() },
+        }
+    }
+
+    // embedのタイトルの()内と色
+    pub fn label(&self, status: &str) -> (u32, Cow<'static, str>) {
+        match self {
+            StatusEvent::New | StatusEvent::Changed { .. } => status_label(status),
+            StatusEvent::Ranked { .. } => (0x00ff00, "Ranked".into()),
+            StatusEvent::Disqualified => (0xff4040, "Disqualified".into()),
+            StatusEvent::BackToPending { .. } => (0xff8800, "Back to pending".into()),
+        }
+    }
+
+    // embedのdescriptionに入れる説明
+    pub fn description(&self, status: &str) -> Option<String> {
+        match self {
+            StatusEvent::New => None,
+            StatusEvent::Ranked { qualified_since: Some(t) } => {
+                Some(format!("Ranked (was qualified since <t:{}:f>, <t:{}:R>)", t, t))
+            },
+            StatusEvent::Ranked { qualified_since: None } => Some("Ranked (was qualified)".to_string()),
+            StatusEvent::Disqualified => Some(format!("Disqualified (now {})", status)),
+            StatusEvent::BackToPending { from } => Some(format!("Back to pending (was {})", from)),
+            StatusEvent::Changed { from } => Some(format!("{} → {}", from, status)),
+        }
+    }
+}
+
+// qualifiedになってから(問題がなければ)rankedになるまでの期間
+pub const QUALIFIED_PERIOD: i64 = 7 * 24 * 60 * 60;
+
+// ranking予定時刻(qualifiedになった時刻 + 7日．実際はranking queueの混み具合で遅れることがある)
+pub fn projected_rank_time(qualified_at: i64) -> i64 {
+    qualified_at + QUALIFIED_PERIOD
+}
+
+// statusごとの色と表示名
+// 知らないstatusはそのまま表示する(空ならUnknown)
+pub fn status_label(status: &str) -> (u32, Cow<'static, str>) {
+    let label = match status {
+        "ranked" => return (0x00ff00, "Ranked".into()),
+        "approved" => return (0x00ff00, "Approved".into()),
+        "loved" => return (0xff00ff, "Loved".into()),
+        "qualified" => return (0xffff00, "Qualified".into()),
+        "pending" => "Pending",
+        "wip" => "WIP",
+        "graveyard" => "Graveyard",
+        "" => "Unknown",
+        s => return (0xeeeeee, s.to_string().into()),
+    };
+    (0xeeeeee, label.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_statuses() {
+        assert_eq!(status_label("ranked"), (0x00ff00, "Ranked".into()));
+        assert_eq!(status_label("graveyard"), (0xeeeeee, "Graveyard".into()));
+        assert_eq!(status_label("wip").1, "WIP");
+        // 知らないstatusはGraveyardにしない
+        assert_eq!(status_label("something_new").1, "something_new");
+        assert_eq!(status_label("").1, "Unknown");
+    }
+
+    #[test]
+    fn classifies_events() {
+        assert_eq!(StatusEvent::new(None, "ranked", None), StatusEvent::New);
+        assert_eq!(StatusEvent::new(Some("qualified"), "ranked", Some(1)), StatusEvent::Ranked { qualified_since: Some(1) });
+        assert_eq!(StatusEvent::new(Some("qualified"), "pending", None), StatusEvent::Disqualified);
+        assert_eq!(StatusEvent::new(Some("loved"), "wip", None), StatusEvent::BackToPending { from: "loved".to_string() });
+        assert_eq!(StatusEvent::new(Some("graveyard"), "loved", None).label("loved").1, "Loved");
+    }
+}